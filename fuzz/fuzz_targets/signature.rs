@@ -0,0 +1,41 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wabasen::{Address, Credential, Signature};
+
+// Parsing user-supplied signatures, addresses and credentials must return
+// errors, never panic, and parsed values must survive their canonical encoding.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        if let Ok(signature) = Signature::parse(text) {
+            assert_eq!(Signature::from_bytes(&signature.to_bytes()), Ok(signature));
+        }
+
+        if let Ok(address) = Address::parse(text) {
+            assert_eq!(Address::parse(&address.to_checksum()), Ok(address));
+        }
+
+        let _ = Credential::parse(text);
+    }
+
+    let _ = Signature::from_bytes(data);
+});
@@ -0,0 +1,171 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::keccak256;
+use std::fmt;
+
+pub(crate) const ADDRESS_SIZE: usize = 20;
+
+/// Why an address string could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddressError {
+    /// The address is not 40 hex characters, with or without `0x`.
+    Format,
+    /// The address mixes cases but does not match its EIP-55 checksum.
+    Checksum,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Format => write!(f, "an address is 40 hex characters"),
+            AddressError::Checksum => write!(f, "address checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Ethereum wallet address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address([u8; ADDRESS_SIZE]);
+
+impl Address {
+    /// Parses an address given with or without `0x`. All-lowercase and
+    /// all-uppercase addresses are taken as is, mixed-case ones must match
+    /// their EIP-55 checksum so that typos are caught.
+    pub fn parse(address: &str) -> Result<Address, AddressError> {
+        let digits: &str = address
+            .strip_prefix("0x")
+            .or_else(|| address.strip_prefix("0X"))
+            .unwrap_or(address);
+
+        if digits.len() != 2 * ADDRESS_SIZE || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(AddressError::Format);
+        }
+
+        let mut bytes: [u8; ADDRESS_SIZE] = [0u8; ADDRESS_SIZE];
+
+        if hex::decode_to_slice(digits, &mut bytes).is_err() {
+            return Err(AddressError::Format);
+        }
+
+        let address: Address = Address(bytes);
+
+        let has_lower: bool = digits.bytes().any(|c| c.is_ascii_lowercase());
+        let has_upper: bool = digits.bytes().any(|c| c.is_ascii_uppercase());
+
+        if has_lower && has_upper && address.to_checksum()[2..] != *digits {
+            return Err(AddressError::Checksum);
+        }
+
+        Ok(address)
+    }
+
+    pub fn from_bytes(bytes: [u8; ADDRESS_SIZE]) -> Address {
+        Address(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ADDRESS_SIZE] {
+        &self.0
+    }
+
+    /// Returns the `0x`-prefixed lowercase form.
+    pub fn to_lowercase(&self) -> String {
+        format!("0x{}", hex::encode(self.0))
+    }
+
+    /// Returns the `0x`-prefixed EIP-55 mixed-case form.
+    pub fn to_checksum(&self) -> String {
+        let lowercase: String = hex::encode(self.0);
+        let hash: [u8; 32] = keccak256(lowercase.as_bytes());
+
+        let checksummed: String = lowercase
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let nibble: u8 = (hash[i / 2] >> (4 * (1 - i % 2))) & 0x0f;
+
+                if nibble >= 8 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect();
+
+        format!("0x{}", checksummed)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_checksum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(text in "\\PC*") {
+            let _ = Address::parse(&text);
+        }
+
+        #[test]
+        fn every_form_parses_to_the_same_address(bytes in proptest::array::uniform20(any::<u8>())) {
+            let address: Address = Address::from_bytes(bytes);
+
+            prop_assert_eq!(Address::parse(&address.to_checksum()), Ok(address));
+            prop_assert_eq!(Address::parse(&address.to_lowercase()), Ok(address));
+            prop_assert_eq!(Address::parse(&address.to_lowercase()[2..]), Ok(address));
+            prop_assert_eq!(Address::parse(&address.to_lowercase().to_uppercase()), Ok(address));
+        }
+
+        #[test]
+        fn a_flipped_case_fails_the_checksum(
+            bytes in proptest::array::uniform20(any::<u8>()),
+            index in 2usize..42,
+        ) {
+            let checksum: String = Address::from_bytes(bytes).to_checksum();
+            let c: char = checksum.as_bytes()[index] as char;
+
+            prop_assume!(c.is_ascii_alphabetic());
+
+            let flipped: String = format!(
+                "{}{}{}",
+                &checksum[..index],
+                match c.is_ascii_uppercase() {
+                    true => c.to_ascii_lowercase(),
+                    false => c.to_ascii_uppercase(),
+                },
+                &checksum[index + 1..]
+            );
+
+            // A single-case address has no checksum to fail.
+            prop_assume!(flipped[2..].bytes().any(|c| c.is_ascii_lowercase()));
+            prop_assume!(flipped[2..].bytes().any(|c| c.is_ascii_uppercase()));
+
+            prop_assert_eq!(Address::parse(&flipped), Err(AddressError::Checksum));
+        }
+    }
+}
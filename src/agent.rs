@@ -0,0 +1,1132 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::Address,
+    compare::ct_eq,
+    decrypt, decrypt_batch, decrypt_per_file, encrypt, encrypt_batch, encrypt_per_file,
+    hash_message, parse_address,
+    scheme::{forget_digests, Scheme, TypedData},
+    secret::{lock_memory, lock_secrets_in_memory},
+    signer::Signer,
+    AccessOptions, ArchiveOptions, BatchOptions, Credential, RecipientKey,
+};
+use glob::Pattern;
+use serde_json::{json, Value};
+use std::{
+    cell::RefCell,
+    env,
+    fs::{self, remove_file, DirBuilder, Metadata},
+    io::{BufRead, BufReader, Write},
+    mem::size_of_val,
+    net::Shutdown,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{DirBuilderExt, MetadataExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When the agent forgets its wallet and exits.
+#[derive(Clone, Default)]
+pub struct AgentOptions {
+    /// Time after which the wallet is forgotten, counted from the start.
+    pub ttl: Option<Duration>,
+    /// Time without any request after which the wallet is forgotten.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Returns `$XDG_RUNTIME_DIR/wabasen-agent.sock`, or a socket in a directory
+/// named after the user in the temporary directory.
+pub fn default_agent_socket() -> String {
+    let path: PathBuf = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("wabasen-agent.sock"),
+        None => env::temp_dir()
+            .join(format!("wabasen-{}", current_uid()))
+            .join("agent.sock"),
+    };
+
+    path.to_string_lossy().to_string()
+}
+
+/// Serves encrypt and decrypt requests on the Unix socket at `socket` with the
+/// wallet of `signer` and `password`, until the wallet is forgotten. Requests
+/// only name files, so clients never see the signatures or the keys.
+///
+/// Each connection is served on its own thread, so a long request does not
+/// hold up the others, while this thread alone holds the wallet and signs for
+/// them. It forgets the wallet at the end of its time to live even while
+/// requests run; those still running fail if they need another signature.
+pub fn run_agent(
+    socket: &str,
+    signer: Box<dyn Signer>,
+    password: &str,
+    options: &AgentOptions,
+) -> Result<(), String> {
+    // Clients in other directories are told where the socket is.
+    let socket: PathBuf = match env::current_dir() {
+        Ok(f) => f.join(socket),
+        Err(_) => return Err("Failed to read the current directory".to_string()),
+    };
+
+    let listener: UnixListener = bind(&socket)?;
+
+    // The agent outlives single commands, so the file keys it derives for
+    // them are kept out of swap as well.
+    lock_secrets_in_memory();
+
+    let mut agent: Agent = Agent::new(signer, password);
+    let address: Address = agent.signer.address();
+
+    println!(
+        "WABASEN_AGENT_SOCK={}; export WABASEN_AGENT_SOCK;",
+        socket.display()
+    );
+    eprintln!(
+        "Agent of wallet '{}' listening, stop it with `wabasen agent forget`",
+        address
+    );
+
+    let (events, received): (Sender<Event>, Receiver<Event>) = channel();
+    let accepted: Sender<Event> = events.clone();
+
+    // Blocks in accept for as long as the process runs.
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if accepted.send(Event::Connection(stream)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut connections: Vec<(JoinHandle<()>, Option<UnixStream>)> = Vec::new();
+
+    let result: Result<(), String> = agent.serve(&received, options, |stream| {
+        let events: Sender<Event> = events.clone();
+        let reader: Option<UnixStream> = stream.try_clone().ok();
+
+        connections.retain(|(connection, _)| !connection.is_finished());
+        connections.push((
+            thread::spawn(move || answer(stream, &events, address)),
+            reader,
+        ));
+    });
+
+    let _ = remove_file(&socket);
+
+    // Requests waiting for a signature now fail, the others finish, and the
+    // clients that have yet to send theirs are hung up on.
+    drop(agent);
+    drop(received);
+    forget_digests();
+
+    for (connection, reader) in connections {
+        if let Some(reader) = reader {
+            let _ = reader.shutdown(Shutdown::Read);
+        }
+
+        let _ = connection.join();
+    }
+
+    result
+}
+
+/// Binds the socket with mode 0600 in a private directory, replacing the
+/// socket of an agent that is no longer running.
+fn bind(socket: &Path) -> Result<UnixListener, String> {
+    private_directory(socket)?;
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!(
+                "An agent already listens on the socket ({})",
+                socket.display()
+            ));
+        }
+
+        if remove_file(socket).is_err() {
+            return Err(format!(
+                "Failed to remove the stale agent socket ({})",
+                socket.display()
+            ));
+        }
+    }
+
+    let umask: libc::mode_t = unsafe { libc::umask(0o177) };
+    let listener: Result<UnixListener, std::io::Error> = UnixListener::bind(socket);
+    unsafe { libc::umask(umask) };
+
+    let listener: UnixListener = match listener {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to listen on the agent socket ({})",
+                socket.display()
+            ))
+        }
+    };
+
+    Ok(listener)
+}
+
+/// Creates the directory of `socket` with mode 0700 when missing, and checks
+/// that no other user owns it or may write to it, so that nobody can put
+/// their own socket in place of the agent's.
+fn private_directory(socket: &Path) -> Result<(), String> {
+    let directory: &Path = match socket.parent() {
+        Some(f) if !f.as_os_str().is_empty() => f,
+        _ => Path::new("."),
+    };
+
+    if !directory.exists() && DirBuilder::new().mode(0o700).create(directory).is_err() {
+        return Err(format!(
+            "Failed to create the agent directory ({})",
+            directory.display()
+        ));
+    }
+
+    let metadata: Metadata = match fs::symlink_metadata(directory) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read the agent directory ({})",
+                directory.display()
+            ))
+        }
+    };
+
+    if !metadata.is_dir() || metadata.uid() != current_uid() || metadata.mode() & 0o022 != 0 {
+        return Err(format!(
+            "Invalid agent directory, it must belong to the user and be writable by them only ({})",
+            directory.display()
+        ));
+    }
+
+    Ok(())
+}
+
+fn current_uid() -> libc::uid_t {
+    unsafe { libc::getuid() }
+}
+
+/// Returns the user id of the process at the other end of `stream`.
+fn peer_uid(stream: &UnixStream) -> Option<libc::uid_t> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut credentials: libc::ucred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut length: libc::socklen_t = size_of_val(&credentials) as libc::socklen_t;
+
+        let result: libc::c_int = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut length,
+            )
+        };
+
+        (result == 0).then_some(credentials.uid)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let mut uid: libc::uid_t = 0;
+        let mut gid: libc::gid_t = 0;
+
+        let result: libc::c_int =
+            unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+
+        (result == 0).then_some(uid)
+    }
+}
+
+/// Checks that the process at the other end of `stream` runs as the user.
+fn check_peer(stream: &UnixStream) -> Result<(), String> {
+    check_peer_uid(peer_uid(stream), current_uid())
+}
+
+fn check_peer_uid(peer: Option<libc::uid_t>, user: libc::uid_t) -> Result<(), String> {
+    match peer {
+        Some(uid) if uid == user => Ok(()),
+        Some(_) => Err("Invalid agent peer, it belongs to another user".to_string()),
+        None => Err("Failed to read the agent peer credentials".to_string()),
+    }
+}
+
+/// What the threads serving connections send to the agent thread.
+enum Event {
+    Connection(UnixStream),
+    /// A request read from a client, answered with its outcome.
+    Request(Request, Sender<Result<Reply, String>>),
+    /// A signature needed by an encrypt or decrypt request in progress.
+    Sign(Message, Sender<Result<String, String>>),
+}
+
+/// The fields of a client request the agent thread decides on.
+struct Request {
+    op: String,
+    address: Option<String>,
+    password: Option<Zeroizing<String>>,
+}
+
+enum Reply {
+    Done,
+    /// The encrypt or decrypt request may run, with the password of the wallet.
+    Run(Zeroizing<String>),
+    Forget,
+}
+
+enum Message {
+    Personal(Vec<u8>),
+    TypedData(TypedData),
+}
+
+type Replies<T> = Receiver<Result<T, String>>;
+
+/// Wallet held by the agent. The password and the signatures are locked in
+/// memory and wiped when the agent forgets them.
+struct Agent {
+    signer: CachedSigner,
+    password: String,
+    locked: bool,
+}
+
+impl Agent {
+    fn new(signer: Box<dyn Signer>, password: &str) -> Agent {
+        let agent: Agent = Agent {
+            signer: CachedSigner {
+                signer,
+                signatures: RefCell::new(Vec::new()),
+            },
+            password: password.to_string(),
+            locked: false,
+        };
+
+        if !lock_memory(agent.password.as_ptr(), agent.password.len()) {
+            eprintln!("Warning: Failed to lock the agent memory, secrets may be swapped to disk");
+        }
+
+        agent
+    }
+
+    /// Handles events until the wallet is forgotten, at the latest when the
+    /// time to live or the idle timeout runs out. New connections are passed
+    /// to `connect`.
+    fn serve(
+        &mut self,
+        events: &Receiver<Event>,
+        options: &AgentOptions,
+        mut connect: impl FnMut(UnixStream),
+    ) -> Result<(), String> {
+        let started: Instant = Instant::now();
+        let mut last_request: Instant = started;
+
+        loop {
+            let ttl_deadline: Option<Instant> = options.ttl.map(|ttl| started + ttl);
+            let idle_deadline: Option<Instant> =
+                options.idle_timeout.map(|timeout| last_request + timeout);
+
+            let now: Instant = Instant::now();
+
+            if ttl_deadline.is_some_and(|deadline| deadline <= now) {
+                eprintln!("The agent forgot the wallet at the end of its time to live");
+                return Ok(());
+            }
+
+            if idle_deadline.is_some_and(|deadline| deadline <= now) {
+                eprintln!("The agent forgot the wallet after being idle");
+                return Ok(());
+            }
+
+            let event: Result<Event, RecvTimeoutError> =
+                match ttl_deadline.into_iter().chain(idle_deadline).min() {
+                    Some(deadline) => events.recv_timeout(deadline - now),
+                    None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+
+            match event {
+                Ok(Event::Connection(stream)) => connect(stream),
+                Ok(Event::Request(request, reply)) => {
+                    last_request = Instant::now();
+
+                    let result: Result<Reply, String> = self.handle(&request);
+                    let forget: bool = matches!(result, Ok(Reply::Forget));
+
+                    let _ = reply.send(result);
+
+                    if forget {
+                        eprintln!("The agent forgot the wallet");
+                        return Ok(());
+                    }
+                }
+                Ok(Event::Sign(message, reply)) => {
+                    let _ = reply.send(self.sign(&message));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("Failed to accept an agent connection".to_string())
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Request) -> Result<Reply, String> {
+        match request.op.as_str() {
+            "encrypt" | "decrypt" => {
+                self.check(request)?;
+
+                return Ok(Reply::Run(Zeroizing::new(self.password.clone())));
+            }
+            "lock" => {
+                self.locked = true;
+                forget_digests();
+            }
+            "unlock" => {
+                let password: &str = match &request.password {
+                    Some(f) => f,
+                    None => return Err("Invalid agent request (password)".to_string()),
+                };
+
+                if !ct_eq(password.as_bytes(), self.password.as_bytes()) {
+                    return Err("Wrong password for the agent".to_string());
+                }
+
+                self.locked = false;
+            }
+            "forget" => return Ok(Reply::Forget),
+            op => return Err(format!("Unknown agent operation ({})", op)),
+        };
+
+        Ok(Reply::Done)
+    }
+
+    /// Signs for a request in progress, unless the agent was locked since.
+    fn sign(&self, message: &Message) -> Result<String, String> {
+        if self.locked {
+            return Err("The agent is locked, see `wabasen agent unlock`".to_string());
+        }
+
+        match message {
+            Message::Personal(message) => self.signer.sign_message(message),
+            Message::TypedData(typed_data) => self.signer.sign_typed_data(typed_data),
+        }
+    }
+
+    /// Checks that the agent is unlocked and holds the wallet and password the
+    /// client expects, when it names them.
+    fn check(&self, request: &Request) -> Result<(), String> {
+        if self.locked {
+            return Err("The agent is locked, see `wabasen agent unlock`".to_string());
+        }
+
+        if let Some(address) = &request.address {
+            if !ct_eq(
+                parse_address(address)?.as_bytes(),
+                self.signer.address().as_bytes(),
+            ) {
+                return Err(format!(
+                    "The agent holds wallet '{}', not '{}'",
+                    self.signer.address(),
+                    address
+                ));
+            }
+        }
+
+        if let Some(password) = &request.password {
+            if !ct_eq(password.as_bytes(), self.password.as_bytes()) {
+                return Err("The agent holds another password".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Answers the request read from `stream`, on the thread of its connection.
+fn answer(stream: UnixStream, events: &Sender<Event>, address: Address) {
+    let result: Result<(), String> = check_peer(&stream)
+        .and_then(|_| read_message(&stream, Some(REQUEST_TIMEOUT)))
+        .and_then(|request| run(&request, events, address));
+
+    let response: Value = match &result {
+        Ok(_) => json!({ "ok": true }),
+        Err(e) => json!({ "error": e }),
+    };
+
+    let _ = writeln!(&stream, "{}", response);
+}
+
+/// Has the agent thread decide on `request` and, for encrypt and decrypt
+/// requests, runs them here with a signer that asks the agent thread.
+fn run(request: &Value, events: &Sender<Event>, address: Address) -> Result<(), String> {
+    let op: &str = string_field(request, "op")?;
+
+    let (reply, replied): (Sender<Result<Reply, String>>, Replies<Reply>) = channel();
+
+    let decision: Request = Request {
+        op: op.to_string(),
+        address: request["address"].as_str().map(str::to_string),
+        password: request["password"]
+            .as_str()
+            .map(|password| Zeroizing::new(password.to_string())),
+    };
+
+    if events.send(Event::Request(decision, reply)).is_err() {
+        return Err(FORGOTTEN.to_string());
+    }
+
+    let password: Zeroizing<String> = match replied.recv() {
+        Ok(Ok(Reply::Run(password))) => password,
+        Ok(Ok(_)) => return Ok(()),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(FORGOTTEN.to_string()),
+    };
+
+    let signer: AgentSigner = AgentSigner {
+        address,
+        events: events.clone(),
+    };
+
+    let directory: PathBuf = PathBuf::from(string_field(request, "cwd")?);
+
+    if !directory.is_absolute() {
+        return Err("Invalid agent request (cwd)".to_string());
+    }
+
+    let inputs: Vec<String> = string_list(request, "inputs")?
+        .iter()
+        .map(|input| resolve(&directory, input))
+        .collect();
+    let options: ArchiveOptions = ArchiveOptions {
+        output_directory: Some(directory),
+        ..parse_archive(&request["archive"])?
+    };
+    let batch: BatchOptions = parse_batch(&request["batch"])?;
+
+    if op == "encrypt" {
+        let access: AccessOptions = parse_access(&request["access"])?;
+
+        match single_input(&inputs) {
+            Some(input) if batch.per_file => {
+                encrypt_per_file(input, &signer, &password, &access, &options)
+            }
+            Some(input) => encrypt(input, &signer, &password, &access, &options),
+            None => encrypt_batch(&inputs, &signer, &password, &access, &options, &batch),
+        }
+    } else {
+        let cosigners: Vec<Credential> = parse_credentials(&request["cosigners"])?;
+
+        match single_input(&inputs) {
+            Some(input) if batch.per_file => {
+                decrypt_per_file(input, &signer, &password, &cosigners, &options)
+            }
+            Some(input) => decrypt(input, &signer, &password, &cosigners, &options),
+            None => decrypt_batch(&inputs, &signer, &password, &cosigners, &options, &batch),
+        }
+    }
+}
+
+const FORGOTTEN: &str = "The agent forgot the wallet";
+
+/// Signer of the requests in progress, which has the agent thread sign.
+struct AgentSigner {
+    address: Address,
+    events: Sender<Event>,
+}
+
+impl AgentSigner {
+    fn sign(&self, message: Message) -> Result<String, String> {
+        let (reply, replied): (Sender<Result<String, String>>, Replies<String>) = channel();
+
+        if self.events.send(Event::Sign(message, reply)).is_err() {
+            return Err(FORGOTTEN.to_string());
+        }
+
+        match replied.recv() {
+            Ok(f) => f,
+            Err(_) => Err(FORGOTTEN.to_string()),
+        }
+    }
+}
+
+impl Signer for AgentSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        self.sign(Message::Personal(message.to_vec()))
+    }
+
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        self.sign(Message::TypedData(typed_data.clone()))
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+/// Signer whose signatures are kept by the agent, so that a Ledger or a signer
+/// command is asked once for each message rather than once per request. The
+/// kept signatures are locked in memory and wiped with the agent; the copies
+/// handed out end up in credentials, which wipe them once dropped.
+struct CachedSigner {
+    signer: Box<dyn Signer>,
+    signatures: RefCell<Vec<([u8; 32], String)>>,
+}
+
+impl CachedSigner {
+    fn cached(
+        &self,
+        digest: [u8; 32],
+        sign: impl FnOnce() -> Result<String, String>,
+    ) -> Result<String, String> {
+        if let Some((_, signature)) = self
+            .signatures
+            .borrow()
+            .iter()
+            .find(|(signed, _)| ct_eq(signed, &digest))
+        {
+            return Ok(signature.clone());
+        }
+
+        let signature: String = sign()?;
+
+        lock_memory(signature.as_ptr(), signature.len());
+
+        let copy: String = signature.clone();
+
+        self.signatures.borrow_mut().push((digest, signature));
+
+        Ok(copy)
+    }
+}
+
+impl Signer for CachedSigner {
+    fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        self.cached(hash_message(message), || self.signer.sign_message(message))
+    }
+
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        self.cached(typed_data.hash(), || {
+            self.signer.sign_typed_data(typed_data)
+        })
+    }
+}
+
+impl Drop for CachedSigner {
+    fn drop(&mut self) {
+        for (_, signature) in self.signatures.get_mut().iter_mut() {
+            signature.zeroize();
+        }
+    }
+}
+
+/// Client of a running agent. The address and password, when given, must be
+/// the ones the agent holds.
+pub struct AgentClient {
+    socket: String,
+    address: Option<String>,
+    password: Option<Zeroizing<String>>,
+}
+
+impl AgentClient {
+    pub fn new(socket: &str, address: Option<&str>, password: Option<&str>) -> AgentClient {
+        AgentClient {
+            socket: socket.to_string(),
+            address: address.map(|address| address.to_string()),
+            password: password.map(|password| Zeroizing::new(password.to_string())),
+        }
+    }
+
+    /// Has the agent encrypt `inputs` as `encrypt`, `encrypt_per_file` or
+    /// `encrypt_batch` would, in the current directory.
+    pub fn encrypt(
+        &self,
+        inputs: &[String],
+        access: &AccessOptions,
+        options: &ArchiveOptions,
+        batch: &BatchOptions,
+    ) -> Result<(), String> {
+        self.request(self.encrypt_request(&current_directory()?, inputs, access, options, batch))?;
+
+        println!("The agent encrypted {}", inputs.join(", "));
+
+        Ok(())
+    }
+
+    fn encrypt_request(
+        &self,
+        directory: &str,
+        inputs: &[String],
+        access: &AccessOptions,
+        options: &ArchiveOptions,
+        batch: &BatchOptions,
+    ) -> Value {
+        json!({
+            "op": "encrypt",
+            "address": self.address,
+            "password": self.password.as_deref().map(String::as_str),
+            "cwd": directory,
+            "inputs": inputs,
+            "access": {
+                "recipients": access.recipients.iter().map(recipient_json).collect::<Vec<Value>>(),
+                "threshold": access.threshold,
+                "recovery_code": access.recovery_code,
+                "escrow_key": access.escrow_key,
+                "scheme": access.scheme.name(),
+                "file_id": access.scheme.file_id(),
+            },
+            "archive": archive_json(options),
+            "batch": batch_json(batch),
+        })
+    }
+
+    /// Has the agent decrypt `inputs` as `decrypt`, `decrypt_per_file` or
+    /// `decrypt_batch` would, in the current directory.
+    pub fn decrypt(
+        &self,
+        inputs: &[String],
+        cosigners: &[Credential],
+        options: &ArchiveOptions,
+        batch: &BatchOptions,
+    ) -> Result<(), String> {
+        self.request(json!({
+            "op": "decrypt",
+            "address": self.address,
+            "password": self.password.as_deref().map(String::as_str),
+            "cwd": current_directory()?,
+            "inputs": inputs,
+            "cosigners": cosigners.iter().map(credential_json).collect::<Vec<Value>>(),
+            "archive": archive_json(options),
+            "batch": batch_json(batch),
+        }))?;
+
+        println!("The agent decrypted {}", inputs.join(", "));
+
+        Ok(())
+    }
+
+    /// Has the agent refuse every request until it is unlocked.
+    pub fn lock(&self) -> Result<(), String> {
+        self.request(json!({ "op": "lock" }))
+    }
+
+    /// Unlocks the agent with the password it holds.
+    pub fn unlock(&self, password: &str) -> Result<(), String> {
+        self.request(json!({ "op": "unlock", "password": password }))
+    }
+
+    /// Has the agent wipe its wallet and exit.
+    pub fn forget(&self) -> Result<(), String> {
+        self.request(json!({ "op": "forget" }))
+    }
+
+    fn request(&self, request: Value) -> Result<(), String> {
+        let stream: UnixStream = match UnixStream::connect(&self.socket) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to connect to the agent ({})", self.socket)),
+        };
+
+        // Requests may carry the password, so they only go to an agent run by
+        // the same user.
+        check_peer(&stream)?;
+
+        if writeln!(&stream, "{}", request).is_err() {
+            return Err(format!(
+                "Failed to send the request to the agent ({})",
+                self.socket
+            ));
+        }
+
+        // Operations on large inputs take as long as they need.
+        let response: Value = read_message(&stream, None)?;
+
+        match response["error"].as_str() {
+            Some(e) => Err(e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads one line of JSON, waiting at most `timeout` for it.
+fn read_message(stream: &UnixStream, timeout: Option<Duration>) -> Result<Value, String> {
+    let mut line: String = String::new();
+
+    if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(timeout).is_err() {
+        return Err("Failed to read the agent message".to_string());
+    }
+
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(0) | Err(_) => return Err("Failed to read the agent message".to_string()),
+        Ok(_) => {}
+    };
+
+    let message: Result<Value, serde_json::Error> = serde_json::from_str(&line);
+
+    line.zeroize();
+
+    match message {
+        Ok(f) => Ok(f),
+        Err(_) => Err("Invalid agent message".to_string()),
+    }
+}
+
+fn current_directory() -> Result<String, String> {
+    match env::current_dir() {
+        Ok(f) => Ok(f.to_string_lossy().to_string()),
+        Err(_) => Err("Failed to read the current directory".to_string()),
+    }
+}
+
+/// Resolves `input` against the directory of the client. The directory is
+/// escaped in glob patterns, so that only the pattern's own wildcards match.
+fn resolve(directory: &Path, input: &str) -> String {
+    if Path::new(input).is_absolute() {
+        return input.to_string();
+    }
+
+    match input.contains(['*', '?', '[']) {
+        true => format!(
+            "{}/{}",
+            Pattern::escape(&directory.to_string_lossy()),
+            input
+        ),
+        false => directory.join(input).to_string_lossy().to_string(),
+    }
+}
+
+/// Returns the input when exactly one existing path is given, as the command
+/// line does.
+fn single_input(inputs: &[String]) -> Option<&str> {
+    match inputs {
+        [input] if Path::new(input).exists() => Some(input),
+        _ => None,
+    }
+}
+
+fn credential_json(credential: &Credential) -> Value {
+    json!({
+        "address": credential.address,
+        "password": credential.password,
+        "signature": credential.signature,
+    })
+}
+
+fn parse_credentials(value: &Value) -> Result<Vec<Credential>, String> {
+    let mut credentials: Vec<Credential> = Vec::new();
+
+    for credential in value.as_array().map(Vec::as_slice).unwrap_or_default() {
+        credentials.push(Credential::new(
+            string_field(credential, "address")?,
+            string_field(credential, "password")?,
+            string_field(credential, "signature")?,
+        ));
+    }
+
+    Ok(credentials)
+}
+
+fn recipient_json(recipient: &RecipientKey) -> Value {
+    json!({
+        "address": recipient.address,
+        "public_key": recipient.public_key,
+        "scheme": recipient.scheme.name(),
+        "file_id": recipient.scheme.file_id(),
+    })
+}
+
+fn parse_recipients(value: &Value) -> Result<Vec<RecipientKey>, String> {
+    let mut recipients: Vec<RecipientKey> = Vec::new();
+
+    for recipient in value.as_array().map(Vec::as_slice).unwrap_or_default() {
+        recipients.push(RecipientKey::new(
+            string_field(recipient, "address")?,
+            string_field(recipient, "public_key")?,
+            Scheme::parse(
+                string_field(recipient, "scheme")?,
+                string_field(recipient, "file_id")?,
+            )?,
+        )?);
+    }
+
+    Ok(recipients)
+}
+
+fn parse_access(value: &Value) -> Result<AccessOptions, String> {
+    Ok(AccessOptions {
+        recipients: parse_recipients(&value["recipients"])?,
+        threshold: value["threshold"]
+            .as_u64()
+            .map(|threshold| threshold as usize),
+        recovery_code: value["recovery_code"].as_str().map(str::to_string),
+        escrow_key: value["escrow_key"].as_str().map(str::to_string),
+        scheme: Scheme::parse(
+            string_field(value, "scheme")?,
+            string_field(value, "file_id")?,
+        )?,
+    })
+}
+
+fn archive_json(options: &ArchiveOptions) -> Value {
+    json!({
+        "preserve_owner": options.preserve_owner,
+        "xattrs": options.xattrs,
+        "acls": options.acls,
+        "follow_symlinks": options.follow_symlinks,
+        "reproducible": options.reproducible,
+    })
+}
+
+fn parse_archive(value: &Value) -> Result<ArchiveOptions, String> {
+    Ok(ArchiveOptions {
+        preserve_owner: bool_field(value, "preserve_owner")?,
+        xattrs: bool_field(value, "xattrs")?,
+        acls: bool_field(value, "acls")?,
+        follow_symlinks: bool_field(value, "follow_symlinks")?,
+        reproducible: value["reproducible"].as_u64(),
+        output_directory: None,
+    })
+}
+
+fn batch_json(batch: &BatchOptions) -> Value {
+    json!({
+        "per_file": batch.per_file,
+        "keep_going": batch.keep_going,
+    })
+}
+
+fn parse_batch(value: &Value) -> Result<BatchOptions, String> {
+    Ok(BatchOptions {
+        per_file: bool_field(value, "per_file")?,
+        keep_going: bool_field(value, "keep_going")?,
+    })
+}
+
+fn string_field<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    match value[name].as_str() {
+        Some(f) => Ok(f),
+        None => Err(format!("Invalid agent request ({})", name)),
+    }
+}
+
+fn string_list(value: &Value, name: &str) -> Result<Vec<String>, String> {
+    match value[name].as_array() {
+        Some(items) => items
+            .iter()
+            .map(|item| match item.as_str() {
+                Some(f) => Ok(f.to_string()),
+                None => Err(format!("Invalid agent request ({})", name)),
+            })
+            .collect(),
+        None => Err(format!("Invalid agent request ({})", name)),
+    }
+}
+
+fn bool_field(value: &Value, name: &str) -> Result<bool, String> {
+    match value[name].as_bool() {
+        Some(f) => Ok(f),
+        None => Err(format!("Invalid agent request ({})", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::LocalWallet;
+    use std::{cell::Cell, fs::set_permissions, os::unix::fs::PermissionsExt, rc::Rc};
+    use tempfile::TempDir;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    fn wallet() -> Box<dyn Signer> {
+        Box::new(LocalWallet::from_private_key(&hex::decode(FIRST_KEY).unwrap()).unwrap())
+    }
+
+    /// Starts an agent on a socket in `folder`, and waits until it listens.
+    fn start(folder: &TempDir, options: AgentOptions) -> (String, JoinHandle<Result<(), String>>) {
+        let socket: String = folder
+            .path()
+            .join("agent")
+            .join("agent.sock")
+            .to_string_lossy()
+            .to_string();
+        let path: String = socket.clone();
+        let agent: JoinHandle<Result<(), String>> =
+            thread::spawn(move || run_agent(&path, wallet(), "password", &options));
+
+        while UnixStream::connect(&socket).is_err() {
+            assert!(!agent.is_finished());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        (socket, agent)
+    }
+
+    #[test]
+    fn peers_of_the_same_user_are_accepted() {
+        let (client, _server): (UnixStream, UnixStream) = UnixStream::pair().unwrap();
+
+        assert_eq!(peer_uid(&client), Some(current_uid()));
+        assert!(check_peer(&client).is_ok());
+    }
+
+    #[test]
+    fn peers_of_other_users_are_rejected() {
+        assert!(check_peer_uid(Some(1000), 1000).is_ok());
+        assert!(check_peer_uid(Some(1001), 1000)
+            .unwrap_err()
+            .contains("another user"));
+        assert!(check_peer_uid(None, 1000).is_err());
+    }
+
+    #[test]
+    fn socket_directories_must_be_private() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let created: PathBuf = folder.path().join("created");
+
+        private_directory(&created.join("agent.sock")).unwrap();
+
+        let mode: u32 = fs::metadata(&created).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        set_permissions(&created, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(private_directory(&created.join("agent.sock")).is_err());
+
+        let file: PathBuf = folder.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(private_directory(&file.join("agent.sock")).is_err());
+    }
+
+    #[test]
+    fn signatures_are_asked_once_per_message() {
+        struct Counting {
+            wallet: Box<dyn Signer>,
+            count: Rc<Cell<usize>>,
+        }
+
+        impl Signer for Counting {
+            fn address(&self) -> Address {
+                self.wallet.address()
+            }
+
+            fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+                self.count.set(self.count.get() + 1);
+                self.wallet.sign_message(message)
+            }
+
+            fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+                self.count.set(self.count.get() + 1);
+                self.wallet.sign_typed_data(typed_data)
+            }
+        }
+
+        let count: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+        let signer: CachedSigner = CachedSigner {
+            signer: Box::new(Counting {
+                wallet: wallet(),
+                count: count.clone(),
+            }),
+            signatures: RefCell::new(Vec::new()),
+        };
+
+        let first: String = signer.sign_message(b"first").unwrap();
+        assert_eq!(signer.sign_message(b"first").unwrap(), first);
+        assert_eq!(count.get(), 1);
+
+        assert_ne!(signer.sign_message(b"second").unwrap(), first);
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn the_wallet_is_forgotten_at_the_end_of_its_time_to_live() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let started: Instant = Instant::now();
+        let (socket, agent): (String, JoinHandle<Result<(), String>>) = start(
+            &folder,
+            AgentOptions {
+                ttl: Some(Duration::from_secs(1)),
+                idle_timeout: None,
+            },
+        );
+
+        // A client that never sends its request holds up neither the agent
+        // nor its expiry.
+        let _silent: UnixStream = UnixStream::connect(&socket).unwrap();
+
+        agent.join().unwrap().unwrap();
+
+        assert!(started.elapsed() < REQUEST_TIMEOUT);
+        assert!(!Path::new(&socket).exists());
+    }
+
+    #[test]
+    fn requests_run_in_the_directory_of_the_client() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let (socket, agent): (String, JoinHandle<Result<(), String>>) =
+            start(&folder, AgentOptions::default());
+        let work: TempDir = TempDir::new().unwrap();
+        let directory: PathBuf = env::current_dir().unwrap();
+        let input: String = "wabasen-agent-test.txt".to_string();
+
+        fs::write(work.path().join(&input), b"content").unwrap();
+
+        // Another client waiting on its request does not hold this one up.
+        let _silent: UnixStream = UnixStream::connect(&socket).unwrap();
+
+        let client: AgentClient = AgentClient::new(&socket, None, Some("password"));
+        client
+            .request(client.encrypt_request(
+                &work.path().to_string_lossy(),
+                &[input],
+                &AccessOptions::default(),
+                &ArchiveOptions::default(),
+                &BatchOptions::default(),
+            ))
+            .unwrap();
+
+        assert!(work.path().join("wabasen-agent-test.waba").exists());
+        assert!(!Path::new("wabasen-agent-test.waba").exists());
+        assert_eq!(env::current_dir().unwrap(), directory);
+
+        client.forget().unwrap();
+        agent.join().unwrap().unwrap();
+    }
+}
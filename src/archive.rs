@@ -0,0 +1,945 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use filetime::{set_file_mtime, set_symlink_file_times, FileTime};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression, GzBuilder};
+use std::{
+    fs::{
+        create_dir_all, metadata, read_dir, read_link, symlink_metadata, DirEntry, File, Metadata,
+    },
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+
+const SAMPLE_SIZE: u64 = 64 * 1024;
+const SAMPLE_LIMIT: u64 = 4 * 1024 * 1024;
+/// Below this, the gzip framing outweighs what the sample says about the data.
+const MIN_SAMPLE_SIZE: u64 = 4 * 1024;
+const INCOMPRESSIBLE_RATIO: f64 = 0.95;
+
+const PAX_MTIME: &str = "mtime";
+const PAX_XATTR: &str = "SCHILY.xattr.";
+const ACL_XATTR: &str = "system.posix_acl_";
+
+/// Selects which file metadata is recorded in the archive and restored from it.
+///
+/// Modes, owner ids and whole-second mtimes are always recorded. Sub-second
+/// mtimes, extended attributes and POSIX ACLs travel as PAX records, and sparse
+/// files are stored with GNU sparse headers so their holes survive the round trip.
+/// PAX sparse records (`GNU.sparse.*`) are not used because the `tar` crate
+/// cannot read them back; GNU tar and bsdtar read both forms.
+#[derive(Clone, Debug)]
+pub struct ArchiveOptions {
+    /// Restore the owner and group recorded in the archive (usually requires root).
+    pub preserve_owner: bool,
+    /// Record and restore extended attributes.
+    pub xattrs: bool,
+    /// Record and restore POSIX ACLs, which Linux stores as `system.posix_acl_*` attributes.
+    pub acls: bool,
+    /// Archive the targets of symbolic links instead of the links themselves.
+    pub follow_symlinks: bool,
+    /// Build a byte-identical archive for identical input: entries are sorted by
+    /// name, owners are cleared and every mtime is set to the given timestamp
+    /// (`SOURCE_DATE_EPOCH` on the command line). Sparse files are stored dense.
+    pub reproducible: Option<u64>,
+    /// Directory that single inputs are encrypted or decrypted into, instead of
+    /// the working directory.
+    pub output_directory: Option<PathBuf>,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        ArchiveOptions {
+            preserve_owner: false,
+            xattrs: false,
+            acls: false,
+            follow_symlinks: true,
+            reproducible: None,
+            output_directory: None,
+        }
+    }
+}
+
+type PaxRecords = Vec<(String, Vec<u8>)>;
+
+pub(crate) fn compress(
+    from_path: &str,
+    to_path: &str,
+    compression: Compression,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let archive_file: File = match File::create(to_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
+    };
+
+    let encoder: GzEncoder<File> = match options.reproducible {
+        Some(_) => GzBuilder::new()
+            .mtime(0)
+            .operating_system(255)
+            .write(archive_file, compression),
+        None => GzEncoder::new(archive_file, compression),
+    };
+
+    let mut archive: Builder<GzEncoder<File>> = Builder::new(encoder);
+
+    archive.follow_symlinks(options.follow_symlinks);
+    archive.sparse(true);
+
+    let from_path_data: &Path = Path::new(from_path);
+
+    if from_path_data.is_file() {
+        let name: &Path = match from_path_data.file_name() {
+            Some(name) => Path::new(name),
+            None => return Err(format!("Invalid input path ({})", from_path)),
+        };
+
+        append_entry(&mut archive, from_path_data, name, options)?;
+    } else if from_path_data.is_dir() {
+        append_dir_entries(&mut archive, from_path_data, Path::new(""), options)?;
+    } else {
+        return Err(format!("Invalid input path ({})", from_path));
+    }
+
+    let encoder: GzEncoder<File> = match archive.into_inner() {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to write archive ({})", to_path)),
+    };
+
+    match encoder.finish() {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to write archive ({})", to_path)),
+    };
+
+    Ok(())
+}
+
+fn append_dir_entries(
+    archive: &mut Builder<GzEncoder<File>>,
+    dir_path: &Path,
+    dir_name: &Path,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let entries = match read_dir(dir_path) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read input folder ({})",
+                dir_path.display()
+            ))
+        }
+    };
+
+    let mut entries: Vec<DirEntry> = match entries.collect() {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read input folder ({})",
+                dir_path.display()
+            ))
+        }
+    };
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path: PathBuf = entry.path();
+        let name: PathBuf = dir_name.join(entry.file_name());
+
+        let meta: Metadata = append_entry(archive, &path, &name, options)?;
+
+        if meta.is_dir() {
+            append_dir_entries(archive, &path, &name, options)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn append_entry(
+    archive: &mut Builder<GzEncoder<File>>,
+    path: &Path,
+    name: &Path,
+    options: &ArchiveOptions,
+) -> Result<Metadata, String> {
+    let meta: Metadata = match options.follow_symlinks {
+        true => metadata(path),
+        false => symlink_metadata(path),
+    }
+    .map_err(|_| format!("Failed to read input metadata ({})", path.display()))?;
+
+    let records: PaxRecords = read_pax_records(path, &meta, options)?;
+
+    if !records.is_empty() {
+        match archive.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), &v[..]))) {
+            Ok(f) => f,
+            Err(_) => {
+                return Err(format!(
+                    "Failed to archive input metadata ({})",
+                    path.display()
+                ))
+            }
+        };
+    }
+
+    let result = match options.reproducible {
+        Some(mtime) => append_normalized_entry(archive, path, name, &meta, mtime),
+        None => archive.append_path_with_name(path, name),
+    };
+
+    match result {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to archive input ({})", path.display())),
+    };
+
+    Ok(meta)
+}
+
+fn append_normalized_entry(
+    archive: &mut Builder<GzEncoder<File>>,
+    path: &Path,
+    name: &Path,
+    meta: &Metadata,
+    mtime: u64,
+) -> std::io::Result<()> {
+    let mut header: Header = Header::new_gnu();
+
+    header.set_metadata_in_mode(meta, HeaderMode::Complete);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+
+    if meta.file_type().is_symlink() {
+        archive.append_link(&mut header, name, read_link(path)?)
+    } else if meta.is_file() {
+        archive.append_data(&mut header, name, File::open(path)?)
+    } else {
+        archive.append_data(&mut header, name, std::io::empty())
+    }
+}
+
+fn read_pax_records(
+    path: &Path,
+    meta: &Metadata,
+    options: &ArchiveOptions,
+) -> Result<PaxRecords, String> {
+    let mut records: PaxRecords = Vec::new();
+
+    if let Some(since_epoch) = meta
+        .modified()
+        .ok()
+        .filter(|_| options.reproducible.is_none())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    {
+        if since_epoch.subsec_nanos() != 0 {
+            records.push((
+                PAX_MTIME.to_string(),
+                format!(
+                    "{}.{:09}",
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_nanos()
+                )
+                .into_bytes(),
+            ));
+        }
+    }
+
+    if !meta.file_type().is_symlink() {
+        records.extend(read_xattrs(path, options)?);
+    }
+
+    records.sort();
+
+    Ok(records)
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path, options: &ArchiveOptions) -> Result<PaxRecords, String> {
+    let mut records: PaxRecords = Vec::new();
+
+    if !options.xattrs && !options.acls {
+        return Ok(records);
+    }
+
+    let names = match xattr::list_deref(path) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read extended attributes ({})",
+                path.display()
+            ))
+        }
+    };
+
+    for name in names {
+        let name: &str = match name.to_str() {
+            Some(name) if is_selected_xattr(name, options) => name,
+            _ => continue,
+        };
+
+        let value: Vec<u8> = match xattr::get_deref(path, name) {
+            Ok(Some(f)) => f,
+            Ok(None) => continue,
+            Err(_) => {
+                return Err(format!(
+                    "Failed to read extended attribute {} ({})",
+                    name,
+                    path.display()
+                ))
+            }
+        };
+
+        records.push((format!("{}{}", PAX_XATTR, name), value));
+    }
+
+    Ok(records)
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path, _options: &ArchiveOptions) -> Result<PaxRecords, String> {
+    Ok(Vec::new())
+}
+
+fn is_selected_xattr(name: &str, options: &ArchiveOptions) -> bool {
+    match name.starts_with(ACL_XATTR) {
+        true => options.acls,
+        false => options.xattrs,
+    }
+}
+
+/// Samples the beginning of every file under `from_path` and reports whether gzip
+/// would gain almost nothing on it (JPEG, MP4, ZIP, ...), in which case the
+/// archive is stored without compression. Inputs too small to judge are
+/// compressed. Symbolic links are only followed when the archive follows them.
+pub(crate) fn is_incompressible(from_path: &str, options: &ArchiveOptions) -> Result<bool, String> {
+    let mut pending: Vec<PathBuf> = vec![Path::new(from_path).to_path_buf()];
+    let mut encoder: GzEncoder<Vec<u8>> = GzEncoder::new(Vec::new(), Compression::fast());
+    let mut sampled: u64 = 0;
+
+    while let Some(path) = pending.pop() {
+        if sampled >= SAMPLE_LIMIT {
+            break;
+        }
+
+        let meta: Metadata = match options.follow_symlinks {
+            true => metadata(&path),
+            false => symlink_metadata(&path),
+        }
+        .map_err(|_| format!("Failed to read input metadata ({})", path.display()))?;
+
+        if meta.is_dir() {
+            let entries = match read_dir(&path) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to read input folder ({})", path.display())),
+            };
+
+            let mut children: Vec<PathBuf> = Vec::new();
+
+            for entry in entries {
+                match entry {
+                    Ok(f) => children.push(f.path()),
+                    Err(_) => {
+                        return Err(format!("Failed to read input folder ({})", path.display()))
+                    }
+                };
+            }
+
+            // Files are sampled in archive order, whatever order the filesystem
+            // lists them in, so the same tree always gets the same compression.
+            children.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
+            pending.extend(children);
+        } else if meta.is_file() {
+            let file: File = match File::open(&path) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to open input file ({})", path.display())),
+            };
+
+            let mut sample: Vec<u8> = Vec::new();
+
+            match file.take(SAMPLE_SIZE).read_to_end(&mut sample) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to read input file ({})", path.display())),
+            };
+
+            match encoder.write_all(&sample) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to sample input file ({})", path.display())),
+            };
+
+            sampled += sample.len() as u64;
+        }
+    }
+
+    if sampled < MIN_SAMPLE_SIZE {
+        return Ok(false);
+    }
+
+    let compressed: Vec<u8> = match encoder.finish() {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to sample input ({})", from_path)),
+    };
+
+    Ok(compressed.len() as f64 / sampled as f64 >= INCOMPRESSIBLE_RATIO)
+}
+
+pub(crate) fn decompress(
+    from_path: &str,
+    to_path: &str,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let archive_file: File = match File::open(from_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to open input ({})", from_path)),
+    };
+
+    let decoder: GzDecoder<File> = GzDecoder::new(archive_file);
+
+    let mut archive: Archive<GzDecoder<File>> = Archive::new(decoder);
+
+    archive.set_preserve_ownerships(options.preserve_owner);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(false);
+
+    match create_dir_all(to_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to create output folder ({})", to_path)),
+    };
+
+    let entries = match archive.entries() {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to archive input ({})", from_path)),
+    };
+
+    // Directories are restored last, deepest first, so that extracting their
+    // content does not bump their mtime or trip over read-only permissions.
+    let mut directories: Vec<(Entry<GzDecoder<File>>, PaxRecords)> = Vec::new();
+
+    for entry in entries {
+        let mut entry: Entry<GzDecoder<File>> = match entry {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to archive input ({})", from_path)),
+        };
+
+        let records: PaxRecords = read_entry_records(&mut entry, from_path)?;
+
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push((entry, records));
+        } else {
+            unpack_entry(&mut entry, &records, to_path, options)?;
+        }
+    }
+
+    for (mut entry, records) in directories.into_iter().rev() {
+        unpack_entry(&mut entry, &records, to_path, options)?;
+    }
+
+    Ok(())
+}
+
+fn read_entry_records(
+    entry: &mut Entry<GzDecoder<File>>,
+    from_path: &str,
+) -> Result<PaxRecords, String> {
+    let mut records: PaxRecords = Vec::new();
+
+    let extensions = match entry.pax_extensions() {
+        Ok(Some(f)) => f,
+        Ok(None) => return Ok(records),
+        Err(_) => return Err(format!("Failed to read archive metadata ({})", from_path)),
+    };
+
+    for extension in extensions {
+        let extension = match extension {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to read archive metadata ({})", from_path)),
+        };
+
+        if let Ok(key) = extension.key() {
+            records.push((key.to_string(), extension.value_bytes().to_vec()));
+        }
+    }
+
+    Ok(records)
+}
+
+fn unpack_entry(
+    entry: &mut Entry<GzDecoder<File>>,
+    records: &PaxRecords,
+    to_path: &str,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let path: PathBuf = match entry.path() {
+        Ok(f) => f.into_owned(),
+        Err(_) => return Err(format!("Invalid archive entry path ({})", to_path)),
+    };
+
+    match entry.unpack_in(to_path) {
+        Ok(true) => (),
+        Ok(false) => return Ok(()),
+        Err(_) => return Err(format!("Failed to unpack ({})", path.display())),
+    };
+
+    let dst_path: PathBuf = match unpacked_path(to_path, &path) {
+        Some(f) => f,
+        None => return Ok(()),
+    };
+    let is_symlink: bool = entry.header().entry_type().is_symlink();
+
+    if !is_symlink {
+        write_xattrs(&dst_path, records, options)?;
+    }
+
+    // tar leaves directory mtimes alone and drops sub-second precision, so the
+    // recorded mtime is applied here for every entry.
+    let mtime: Option<FileTime> = parse_mtime(records).or_else(|| {
+        entry
+            .header()
+            .mtime()
+            .ok()
+            .map(|mtime| FileTime::from_unix_time(mtime as i64, 0))
+    });
+
+    if let Some(mtime) = mtime {
+        let result = match is_symlink {
+            true => set_symlink_file_times(&dst_path, mtime, mtime),
+            false => set_file_mtime(&dst_path, mtime),
+        };
+
+        match result {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to restore mtime ({})", dst_path.display())),
+        };
+    }
+
+    Ok(())
+}
+
+/// Returns where `unpack_in` wrote the entry at `path`: root and prefix
+/// components are dropped, so absolute entries stay inside `to_path`. Entries
+/// that climb out of it, or name `to_path` itself, give `None`.
+fn unpacked_path(to_path: &str, path: &Path) -> Option<PathBuf> {
+    let mut dst_path: PathBuf = Path::new(to_path).to_path_buf();
+    let mut depth: usize = 0;
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                dst_path.push(part);
+                depth += 1;
+            }
+            Component::ParentDir => return None,
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => (),
+        }
+    }
+
+    match depth {
+        0 => None,
+        _ => Some(dst_path),
+    }
+}
+
+#[cfg(unix)]
+fn write_xattrs(path: &Path, records: &PaxRecords, options: &ArchiveOptions) -> Result<(), String> {
+    for (key, value) in records {
+        let name: &str = match key.strip_prefix(PAX_XATTR) {
+            Some(name) if is_selected_xattr(name, options) => name,
+            _ => continue,
+        };
+
+        match xattr::set(path, name, value) {
+            Ok(f) => f,
+            Err(_) => {
+                return Err(format!(
+                    "Failed to restore extended attribute {} ({})",
+                    name,
+                    path.display()
+                ))
+            }
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_xattrs(
+    _path: &Path,
+    _records: &PaxRecords,
+    _options: &ArchiveOptions,
+) -> Result<(), String> {
+    Ok(())
+}
+
+fn parse_mtime(records: &PaxRecords) -> Option<FileTime> {
+    let value: &str = records
+        .iter()
+        .find(|(key, _)| key == PAX_MTIME)
+        .and_then(|(_, value)| std::str::from_utf8(value).ok())?;
+
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    let seconds: i64 = seconds.parse().ok()?;
+    let nanos: u32 = format!("{:0<9.9}", fraction).parse().ok()?;
+
+    Some(FileTime::from_unix_time(seconds, nanos))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    use std::{
+        fs::{read, set_permissions, symlink_metadata, write, Permissions},
+        os::unix::fs::{chown, symlink, MetadataExt, PermissionsExt},
+    };
+    use tempfile::TempDir;
+
+    const ACL_ACCESS: &str = "system.posix_acl_access";
+
+    /// Archives the content of `source` and unpacks it into a new folder.
+    fn round_trip(source: &Path, options: &ArchiveOptions) -> (TempDir, PathBuf) {
+        let work: TempDir = TempDir::new().unwrap();
+        let archive_path: PathBuf = work.path().join("archive");
+        let output_path: PathBuf = work.path().join("output");
+
+        compress(
+            source.to_str().unwrap(),
+            archive_path.to_str().unwrap(),
+            Compression::default(),
+            options,
+        )
+        .unwrap();
+        decompress(
+            archive_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+            options,
+        )
+        .unwrap();
+
+        (work, output_path)
+    }
+
+    fn set_mtime(path: &Path, seconds: i64, nanos: u32) {
+        set_file_mtime(path, FileTime::from_unix_time(seconds, nanos)).unwrap();
+    }
+
+    /// The `stat` fields an archive is expected to restore.
+    fn stat(path: &Path) -> (u32, i64, i64, u64) {
+        let meta: Metadata = symlink_metadata(path).unwrap();
+        (meta.mode(), meta.mtime(), meta.mtime_nsec(), meta.size())
+    }
+
+    /// An access ACL granting uid 1000 read access, in the kernel's xattr layout.
+    fn acl_value() -> Vec<u8> {
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 6, u32::MAX),
+            (0x02, 4, 1000),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ];
+
+        let mut value: Vec<u8> = 2u32.to_le_bytes().to_vec();
+
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+
+        value
+    }
+
+    #[test]
+    fn round_trip_keeps_modes_and_subsecond_mtimes() {
+        let source: TempDir = TempDir::new().unwrap();
+        let folder: PathBuf = source.path().join("folder");
+        let script: PathBuf = folder.join("script.sh");
+        let secret: PathBuf = folder.join("secret.txt");
+
+        create_dir_all(&folder).unwrap();
+        write(&script, b"#!/bin/sh\n").unwrap();
+        write(&secret, b"secret").unwrap();
+        set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+        set_permissions(&secret, Permissions::from_mode(0o640)).unwrap();
+        set_permissions(&folder, Permissions::from_mode(0o750)).unwrap();
+        set_mtime(&script, 1_600_000_000, 123_456_789);
+        set_mtime(&secret, 1_600_000_001, 1);
+        set_mtime(&folder, 1_600_000_002, 999_999_999);
+
+        let (_work, output) = round_trip(source.path(), &ArchiveOptions::default());
+
+        for name in ["folder", "folder/script.sh", "folder/secret.txt"] {
+            assert_eq!(
+                stat(&source.path().join(name)),
+                stat(&output.join(name)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_xattrs_and_acls() {
+        let source: TempDir = TempDir::new().unwrap();
+        let file: PathBuf = source.path().join("file.txt");
+
+        write(&file, b"attributes").unwrap();
+
+        // Not every filesystem takes user attributes or ACLs.
+        if xattr::set(&file, "user.wabasen", b"value").is_err()
+            || xattr::set(&file, ACL_ACCESS, &acl_value()).is_err()
+        {
+            return;
+        }
+
+        let options: ArchiveOptions = ArchiveOptions {
+            xattrs: true,
+            acls: true,
+            ..ArchiveOptions::default()
+        };
+        let (_work, output) = round_trip(source.path(), &options);
+        let restored: PathBuf = output.join("file.txt");
+
+        assert_eq!(
+            xattr::get(&restored, "user.wabasen").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            xattr::get(&restored, ACL_ACCESS).unwrap(),
+            xattr::get(&file, ACL_ACCESS).unwrap()
+        );
+        assert_eq!(stat(&file), stat(&restored));
+
+        let (_work, output) = round_trip(source.path(), &ArchiveOptions::default());
+        let restored: PathBuf = output.join("file.txt");
+
+        assert_eq!(xattr::get(&restored, "user.wabasen").unwrap(), None);
+        assert_eq!(xattr::get(&restored, ACL_ACCESS).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip_keeps_or_follows_symlinks() {
+        let source: TempDir = TempDir::new().unwrap();
+        let link: PathBuf = source.path().join("link");
+
+        write(source.path().join("target.txt"), b"target").unwrap();
+        symlink("target.txt", &link).unwrap();
+        set_symlink_file_times(
+            &link,
+            FileTime::from_unix_time(1_600_000_000, 5),
+            FileTime::from_unix_time(1_600_000_000, 5),
+        )
+        .unwrap();
+
+        let options: ArchiveOptions = ArchiveOptions {
+            follow_symlinks: false,
+            ..ArchiveOptions::default()
+        };
+        let (_work, output) = round_trip(source.path(), &options);
+
+        assert_eq!(
+            read_link(output.join("link")).unwrap(),
+            Path::new("target.txt")
+        );
+        assert_eq!(stat(&link), stat(&output.join("link")));
+
+        let (_work, output) = round_trip(source.path(), &ArchiveOptions::default());
+        let followed: Metadata = symlink_metadata(output.join("link")).unwrap();
+
+        assert!(followed.is_file());
+        assert_eq!(read(output.join("link")).unwrap(), b"target");
+    }
+
+    #[test]
+    fn round_trip_keeps_sparse_holes() {
+        const LENGTH: u64 = 16 * 1024 * 1024;
+
+        let source: TempDir = TempDir::new().unwrap();
+        let sparse: PathBuf = source.path().join("sparse.img");
+
+        let mut file: File = File::create(&sparse).unwrap();
+        file.write_all(b"head").unwrap();
+        file.set_len(LENGTH).unwrap();
+        drop(file);
+
+        // The source must have holes for the test to say anything.
+        if metadata(&sparse).unwrap().blocks() * 512 >= LENGTH {
+            return;
+        }
+
+        let (_work, output) = round_trip(source.path(), &ArchiveOptions::default());
+        let restored: PathBuf = output.join("sparse.img");
+        let meta: Metadata = metadata(&restored).unwrap();
+
+        assert_eq!(meta.len(), LENGTH);
+        assert!(meta.blocks() * 512 < LENGTH / 2);
+        assert_eq!(read(&restored).unwrap(), read(&sparse).unwrap());
+    }
+
+    #[test]
+    fn reproducible_archives_are_byte_identical() {
+        let source: TempDir = TempDir::new().unwrap();
+        let work: TempDir = TempDir::new().unwrap();
+        let folder: PathBuf = source.path().join("folder");
+        let file: PathBuf = folder.join("file.txt");
+        let other: PathBuf = source.path().join("other.txt");
+
+        create_dir_all(&folder).unwrap();
+        write(&file, b"file").unwrap();
+        write(&other, b"other").unwrap();
+
+        let options: ArchiveOptions = ArchiveOptions {
+            reproducible: Some(1_700_000_000),
+            ..ArchiveOptions::default()
+        };
+
+        let archive = |name: &str| -> Vec<u8> {
+            let archive_path: PathBuf = work.path().join(name);
+            compress(
+                source.path().to_str().unwrap(),
+                archive_path.to_str().unwrap(),
+                Compression::default(),
+                &options,
+            )
+            .unwrap();
+            read(archive_path).unwrap()
+        };
+
+        for path in [&folder, &file, &other] {
+            set_mtime(path, 1_600_000_000, 1);
+        }
+        let first: Vec<u8> = archive("first");
+
+        for path in [&folder, &file, &other] {
+            set_mtime(path, 1_650_000_000, 999);
+            // Changing owners takes root, the mtimes still differ without it.
+            let _ = chown(path, Some(1234), Some(5678));
+        }
+        let second: Vec<u8> = archive("second");
+
+        assert_eq!(first, second);
+    }
+
+    fn random_bytes(length: usize) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0u8; length];
+        OsRng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn text_bytes(length: usize) -> Vec<u8> {
+        b"Lorem ipsum dolor sit amet, consectetur adipiscing elit.\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(length)
+            .collect()
+    }
+
+    #[test]
+    fn random_data_is_incompressible_and_text_is_not() {
+        let source: TempDir = TempDir::new().unwrap();
+        let random: PathBuf = source.path().join("random.bin");
+        let text: PathBuf = source.path().join("text.txt");
+
+        write(&random, random_bytes(256 * 1024)).unwrap();
+        write(&text, text_bytes(256 * 1024)).unwrap();
+
+        let options: ArchiveOptions = ArchiveOptions::default();
+
+        assert!(is_incompressible(random.to_str().unwrap(), &options).unwrap());
+        assert!(!is_incompressible(text.to_str().unwrap(), &options).unwrap());
+    }
+
+    #[test]
+    fn incompressible_sampling_keeps_to_unfollowed_links() {
+        let outside: TempDir = TempDir::new().unwrap();
+        let source: TempDir = TempDir::new().unwrap();
+
+        // Each file only gives its first 64 KiB to the sample.
+        for index in 0..16 {
+            write(
+                outside.path().join(format!("random{}.bin", index)),
+                random_bytes(64 * 1024),
+            )
+            .unwrap();
+        }
+        write(source.path().join("text.txt"), text_bytes(8 * 1024)).unwrap();
+        symlink(outside.path(), source.path().join("link")).unwrap();
+
+        let source_path: &str = source.path().to_str().unwrap();
+
+        assert!(is_incompressible(source_path, &ArchiveOptions::default()).unwrap());
+        assert!(!is_incompressible(
+            source_path,
+            &ArchiveOptions {
+                follow_symlinks: false,
+                ..ArchiveOptions::default()
+            }
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn absolute_entries_stay_inside_the_destination() {
+        let work: TempDir = TempDir::new().unwrap();
+        let outside: PathBuf = work.path().join("outside.txt");
+        let archive_path: PathBuf = work.path().join("archive");
+        let output_path: PathBuf = work.path().join("output");
+
+        write(&outside, b"outside").unwrap();
+        set_mtime(&outside, 1_600_000_000, 0);
+
+        let mut archive: Builder<GzEncoder<File>> = Builder::new(GzEncoder::new(
+            File::create(&archive_path).unwrap(),
+            Compression::default(),
+        ));
+
+        archive
+            .append_pax_extensions([(PAX_MTIME, &b"1000.5"[..])])
+            .unwrap();
+
+        // `set_path` refuses absolute paths, so the name is written raw.
+        let name: &[u8] = outside.as_os_str().as_encoded_bytes();
+        let mut header: Header = Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_entry_type(EntryType::Regular);
+        header.set_cksum();
+        archive.append(&header, &b"inside"[..]).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        decompress(
+            archive_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+            &ArchiveOptions::default(),
+        )
+        .unwrap();
+
+        let inside: PathBuf = output_path.join(outside.strip_prefix("/").unwrap());
+
+        assert_eq!(read(&outside).unwrap(), b"outside");
+        assert_eq!(metadata(&outside).unwrap().mtime(), 1_600_000_000);
+        assert_eq!(read(&inside).unwrap(), b"inside");
+        assert_eq!(metadata(&inside).unwrap().mtime(), 1000);
+    }
+}
@@ -0,0 +1,332 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    check_output,
+    credential::{Unlock, Wallets},
+    decrypt_input, decrypted_path, encrypt_input, encrypted_path,
+    per_file::{decrypt_tree, encrypt_tree, tree_schemes},
+    scheme::Scheme,
+    signer::Signer,
+    signing_scheme, verify_access, AccessOptions, ArchiveOptions, Credential, EncryptedOutput,
+};
+use glob::glob;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Settings shared by every input of a batch run.
+#[derive(Clone, Debug, Default)]
+pub struct BatchOptions {
+    /// Encrypt or decrypt each file of the input folders separately.
+    pub per_file: bool,
+    /// Carry on with the remaining inputs after one of them failed.
+    pub keep_going: bool,
+}
+
+enum Status {
+    Pending,
+    Done(String),
+    Failed(String),
+    Skipped,
+}
+
+struct Item {
+    input: String,
+    status: Status,
+}
+
+/// Encrypts several inputs (paths or glob patterns) with a single signature
+/// verification and prints a summary table of every item.
+pub fn encrypt_batch(
+    inputs: &[String],
+    signer: &dyn Signer,
+    password: &str,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
+    batch: &BatchOptions,
+) -> Result<(), String> {
+    let mut items: Vec<Item> = expand_inputs(inputs)?;
+
+    if !batch.per_file {
+        check_outputs(&mut items, |input| encrypted_path(input, options));
+    }
+
+    println!("\nEncrypt {} inputs\n", items.len());
+
+    let start_time: Instant = Instant::now();
+
+    let access: AccessOptions = verify_access(signer, password, access)?;
+
+    run_items(&mut items, batch, |input| match batch.per_file {
+        true => {
+            encrypt_tree(input, &access, options).map(|count| format!("{} files encrypted", count))
+        }
+        false => encrypt_input(input, &access, options).map(|output: EncryptedOutput| match output
+            .compressed
+        {
+            true => output.path,
+            false => format!("{} (stored uncompressed)", output.path),
+        }),
+    });
+
+    summarize(&items, "encrypted", start_time)
+}
+
+/// Decrypts several `.waba` inputs (paths or glob patterns), asking the wallet
+/// to sign once for each scheme of the inputs, and prints a summary table of
+/// every item.
+pub fn decrypt_batch(
+    inputs: &[String],
+    signer: &dyn Signer,
+    password: &str,
+    cosigners: &[Credential],
+    options: &ArchiveOptions,
+    batch: &BatchOptions,
+) -> Result<(), String> {
+    let mut items: Vec<Item> = expand_inputs(inputs)?;
+
+    if !batch.per_file {
+        check_outputs(&mut items, |input| decrypted_path(input, options));
+    }
+
+    println!("\nDecrypt {} inputs\n", items.len());
+
+    let start_time: Instant = Instant::now();
+
+    let schemes: Vec<Scheme> = items
+        .iter()
+        .filter(|item| matches!(item.status, Status::Pending))
+        .flat_map(|item| match batch.per_file {
+            true => tree_schemes(&item.input, options),
+            false => signing_scheme(&item.input).into_iter().collect(),
+        })
+        .collect();
+
+    let wallets: Wallets = Wallets::sign(signer, password, cosigners, &schemes);
+
+    run_items(&mut items, batch, |input| match batch.per_file {
+        true => {
+            decrypt_tree(input, &wallets, options).map(|count| format!("{} files decrypted", count))
+        }
+        false => decrypt_input(input, &Unlock::Wallets(&wallets), options),
+    });
+
+    summarize(&items, "decrypted", start_time)
+}
+
+fn expand_inputs(inputs: &[String]) -> Result<Vec<Item>, String> {
+    let mut items: Vec<Item> = Vec::new();
+
+    for input in inputs {
+        if !input.contains(['*', '?', '[']) {
+            push_unique(&mut items, input.to_string(), Status::Pending);
+            continue;
+        }
+
+        let paths = match glob(input) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Invalid glob pattern ({})", input)),
+        };
+
+        let mut matched: bool = false;
+
+        for path in paths {
+            let path: String = match path.map(|path| path.to_str().map(str::to_string)) {
+                Ok(Some(f)) => f,
+                Ok(None) => return Err(format!("The file name is not valid UTF-8 ({})", input)),
+                Err(_) => return Err(format!("Failed to read input path ({})", input)),
+            };
+
+            push_unique(&mut items, path, Status::Pending);
+            matched = true;
+        }
+
+        if !matched {
+            let status: Status = Status::Failed("No input matches this pattern".to_string());
+            push_unique(&mut items, input.to_string(), status);
+        }
+    }
+
+    Ok(items)
+}
+
+fn push_unique(items: &mut Vec<Item>, input: String, status: Status) {
+    if !items.iter().any(|item| item.input == input) {
+        items.push(Item { input, status });
+    }
+}
+
+/// Fails the pending items that would write to the same output as another
+/// item, or to a path that already exists, before any item runs.
+fn check_outputs(items: &mut [Item], output_path: impl Fn(&str) -> Result<String, String>) {
+    let outputs: Vec<Option<String>> = items
+        .iter()
+        .map(|item| match item.status {
+            Status::Pending => output_path(&item.input).ok(),
+            _ => None,
+        })
+        .collect();
+
+    for (item, output) in items.iter_mut().zip(&outputs) {
+        let output: &String = match output {
+            Some(f) => f,
+            None => continue,
+        };
+
+        if outputs
+            .iter()
+            .flatten()
+            .filter(|other| *other == output)
+            .count()
+            > 1
+        {
+            item.status = Status::Failed(format!("Another input has the same output ({})", output));
+        } else if let Err(e) = check_output(Path::new(output)) {
+            item.status = Status::Failed(e);
+        }
+    }
+}
+
+fn run_items<F>(items: &mut [Item], batch: &BatchOptions, job: F)
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    let mut stopped: bool = false;
+
+    for item in items.iter_mut() {
+        if let Status::Pending = item.status {
+            item.status = match stopped {
+                true => Status::Skipped,
+                false => match job(&item.input) {
+                    Ok(detail) => Status::Done(detail),
+                    Err(e) => Status::Failed(e),
+                },
+            };
+        }
+
+        if let Status::Failed(_) = item.status {
+            stopped = !batch.keep_going;
+        }
+    }
+}
+
+fn summarize(items: &[Item], action: &str, start_time: Instant) -> Result<(), String> {
+    let elapsed_time: Duration = start_time.elapsed();
+
+    let width: usize = items
+        .iter()
+        .map(|item| item.input.chars().count())
+        .chain(Some("INPUT".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("\n\n{:<width$}  {:<7}  DETAIL", "INPUT", "STATUS");
+
+    let mut done: usize = 0;
+    let mut failed: usize = 0;
+
+    for item in items {
+        let (status, detail): (&str, &str) = match &item.status {
+            Status::Done(detail) => {
+                done += 1;
+                ("ok", detail)
+            }
+            Status::Failed(e) => {
+                failed += 1;
+                ("failed", e)
+            }
+            Status::Pending | Status::Skipped => ("skipped", ""),
+        };
+
+        println!("{:<width$}  {:<7}  {}", item.input, status, detail);
+    }
+
+    println!(
+        "\n{} of {} inputs are {} in {:?}",
+        done,
+        items.len(),
+        action,
+        elapsed_time
+    );
+
+    match done == items.len() {
+        true => Ok(()),
+        false => Err(format!(
+            "{} of {} inputs failed, {} skipped",
+            failed,
+            items.len(),
+            items.len() - done - failed
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    fn items(inputs: &[&str]) -> Vec<Item> {
+        inputs
+            .iter()
+            .map(|input| Item {
+                input: input.to_string(),
+                status: Status::Pending,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inputs_with_the_same_output_fail_before_running() {
+        let mut items: Vec<Item> = items(&[
+            "first/wabasen-batch-test.txt",
+            "second/wabasen-batch-test.md",
+            "wabasen-batch-other.txt",
+        ]);
+
+        check_outputs(&mut items, |input| {
+            encrypted_path(input, &ArchiveOptions::default())
+        });
+
+        assert!(matches!(&items[0].status, Status::Failed(e) if e.contains("same output")));
+        assert!(matches!(&items[1].status, Status::Failed(e) if e.contains("same output")));
+        assert!(matches!(items[2].status, Status::Pending));
+    }
+
+    #[test]
+    fn existing_outputs_fail_before_running() {
+        let directory: TempDir = tempdir().unwrap();
+        let existing: NamedTempFile = NamedTempFile::new_in(&directory).unwrap();
+        let stem: &str = existing.path().file_name().unwrap().to_str().unwrap();
+
+        let mut items: Vec<Item> =
+            items(&[&format!("{}.waba", stem), "wabasen-batch-missing.waba"]);
+
+        let options: ArchiveOptions = ArchiveOptions {
+            output_directory: Some(directory.path().to_path_buf()),
+            ..ArchiveOptions::default()
+        };
+
+        check_outputs(&mut items, |input| decrypted_path(input, &options));
+
+        assert!(matches!(&items[0].status, Status::Failed(e) if e.contains("already exists")));
+        assert!(matches!(items[1].status, Status::Pending));
+    }
+}
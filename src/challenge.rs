@@ -0,0 +1,92 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{parse_address, scheme::Scheme};
+use serde_json::{json, Value};
+
+/// Prints exactly what the wallet at `address` must sign for `password` under
+/// `scheme`, so that front-ends and scripts produce the signature `decrypt`
+/// verifies. With `as_json` the output is a single JSON object instead.
+pub fn challenge(
+    address: Option<&str>,
+    password: &str,
+    scheme: &Scheme,
+    as_json: bool,
+) -> Result<(), String> {
+    let address: String = match (address, scheme) {
+        (Some(address), _) => parse_address(address)?.to_lowercase(),
+        (None, Scheme::PersonalSign) => String::new(),
+        (None, _) => {
+            return Err(format!(
+                "The {} scheme needs the wallet address",
+                scheme.name()
+            ))
+        }
+    };
+
+    let output: Value = challenge_request(&address, password, scheme);
+
+    if as_json {
+        println!("{}", output);
+        return Ok(());
+    }
+
+    match scheme.message(&address, password) {
+        Some(message) => {
+            println!("Sign this message with personal_sign (EIP-191):\n");
+            println!("{}", message);
+        }
+        None => {
+            println!("Sign this typed data with eth_signTypedData_v4 (EIP-712):\n");
+
+            match serde_json::to_string_pretty(&output["typedData"]) {
+                Ok(f) => println!("{}", f),
+                Err(_) => return Err("Failed to format the typed data".to_string()),
+            };
+        }
+    };
+
+    println!("\nHash: {}", output["hash"].as_str().unwrap_or_default());
+
+    Ok(())
+}
+
+/// Describes what the wallet at `address`, in lowercase, must sign: the signing
+/// method, the message or typed data, and the hash the signature is made over.
+fn challenge_request(address: &str, password: &str, scheme: &Scheme) -> Value {
+    let hash: String = format!("0x{}", hex::encode(scheme.message_hash(address, password)));
+
+    match (
+        scheme.message(address, password),
+        scheme.typed_data(address, password),
+    ) {
+        (Some(message), _) => json!({
+            "scheme": scheme.name(),
+            "method": "personal_sign",
+            "message": message,
+            "hash": hash,
+        }),
+        (None, typed_data) => json!({
+            "scheme": scheme.name(),
+            "method": "eth_signTypedData_v4",
+            "typedData": typed_data.map(|typed_data| typed_data.json().clone()),
+            "hash": hash,
+        }),
+    }
+}
@@ -17,7 +17,8 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
 */
-use clap::{Arg, ArgMatches, Command};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use wabasen::DEFAULT_DERIVATION_PATH;
 
 pub fn parse_args() -> ArgMatches {
     Command::new("Wabasen")
@@ -27,6 +28,13 @@ pub fn parse_args() -> ArgMatches {
         .propagate_version(true)
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("lock-memory")
+                .long("lock-memory")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Lock keys and other secrets in memory so they are never swapped to disk"),
+        )
         .subcommand(
             Command::new("encrypt")
                 .about("Encrypt files or folders using wallet-based 2FA")
@@ -36,14 +44,28 @@ pub fn parse_args() -> ArgMatches {
                         .long("input")
                         .value_name("INPUT")
                         .required(true)
-                        .help("Input path of file or folder"),
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .help("Input paths or glob patterns of files or folders"),
+                )
+                .arg(
+                    Arg::new("keep-going")
+                        .long("keep-going")
+                        .action(ArgAction::SetTrue)
+                        .help("Continue with the remaining inputs when one fails"),
+                )
+                .arg(
+                    Arg::new("per-file")
+                        .long("per-file")
+                        .action(ArgAction::SetTrue)
+                        .help("Encrypt each file of the input folder separately"),
                 )
                 .arg(
                     Arg::new("address")
                         .short('a')
                         .long("address")
                         .value_name("ADDRESS")
-                        .required(true)
+                        .required_unless_present_any(["signer", "agent"])
                         .help("Address wallet linked to the signature"),
                 )
                 .arg(
@@ -51,17 +73,81 @@ pub fn parse_args() -> ArgMatches {
                         .short('p')
                         .long("password")
                         .value_name("PASSWORD")
-                        .required(true)
-                        .default_value("password")
-                        .help("Password signed by the wallet"),
+                        .required_unless_present("agent")
+                        .help("Password signed by the wallet, optional with an agent"),
                 )
                 .arg(
                     Arg::new("signature")
                         .short('s')
                         .long("signature")
                         .value_name("SIGNATURE")
-                        .required(true)
+                        .required_unless_present_any(["signer", "agent"])
+                        .conflicts_with("signer")
                         .help("Signature of the password performed by the wallet"),
+                )
+                .args(signer_args())
+                .group(signer_group())
+                .arg(agent_arg())
+                .arg(
+                    Arg::new("recipient")
+                        .short('r')
+                        .long("recipient")
+                        .value_name("RECIPIENT_KEY")
+                        .action(ArgAction::Append)
+                        .help("Additional wallet that can decrypt the file, with its key (repeatable)"),
+                )
+                .arg(
+                    Arg::new("threshold")
+                        .short('t')
+                        .long("threshold")
+                        .value_name("COUNT")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Number of wallets that must sign together to decrypt"),
+                )
+                .arg(scheme_arg())
+                .arg(file_id_arg())
+                .arg(
+                    Arg::new("recovery-code")
+                        .long("recovery-code")
+                        .action(ArgAction::SetTrue)
+                        .help("Ask for a recovery code that can also decrypt the file, see `recovery code`"),
+                )
+                .arg(
+                    Arg::new("escrow-key")
+                        .long("escrow-key")
+                        .value_name("PUBLIC_KEY")
+                        .help("Escrow public key that can also decrypt the file, see `recovery escrow`"),
+                )
+                .arg(
+                    Arg::new("xattrs")
+                        .long("xattrs")
+                        .action(ArgAction::SetTrue)
+                        .help("Record extended attributes"),
+                )
+                .arg(
+                    Arg::new("acls")
+                        .long("acls")
+                        .action(ArgAction::SetTrue)
+                        .help("Record POSIX ACLs"),
+                )
+                .arg(
+                    Arg::new("follow-symlinks")
+                        .long("follow-symlinks")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("no-follow-symlinks")
+                        .help("Archive the targets of symbolic links (default)"),
+                )
+                .arg(
+                    Arg::new("no-follow-symlinks")
+                        .long("no-follow-symlinks")
+                        .action(ArgAction::SetTrue)
+                        .help("Archive symbolic links as links"),
+                )
+                .arg(
+                    Arg::new("reproducible")
+                        .long("reproducible")
+                        .action(ArgAction::SetTrue)
+                        .help("Build a deterministic archive (sorted entries, mtimes from SOURCE_DATE_EPOCH)"),
                 ),
         )
         .subcommand(
@@ -73,32 +159,405 @@ pub fn parse_args() -> ArgMatches {
                         .long("input")
                         .value_name("INPUT")
                         .required(true)
-                        .help("Input path of encrypted file"),
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .help("Input paths or glob patterns of encrypted files"),
+                )
+                .arg(
+                    Arg::new("keep-going")
+                        .long("keep-going")
+                        .action(ArgAction::SetTrue)
+                        .help("Continue with the remaining inputs when one fails"),
+                )
+                .arg(
+                    Arg::new("per-file")
+                        .long("per-file")
+                        .action(ArgAction::SetTrue)
+                        .help("Decrypt each .waba file of the input folder in place"),
                 )
                 .arg(
                     Arg::new("address")
                         .short('a')
                         .long("address")
                         .value_name("ADDRESS")
-                        .required(true)
-                        .help("Address wallet linked to the signature"),
+                        .required_unless_present_any([
+                            "recovery-code",
+                            "escrow-secret",
+                            "signer",
+                            "agent",
+                        ])
+                        .action(ArgAction::Append)
+                        .help("Address wallet linked to the signature (repeat for threshold files)"),
                 )
                 .arg(
                     Arg::new("password")
                         .short('p')
                         .long("password")
                         .value_name("PASSWORD")
-                        .required(true)
-                        .default_value("password")
-                        .help("Password signed by the wallet"),
+                        .required_unless_present_any(["recovery-code", "escrow-secret", "agent"])
+                        .action(ArgAction::Append)
+                        .help("Password signed by the wallet (once for all wallets or once per wallet), optional with an agent"),
                 )
                 .arg(
                     Arg::new("signature")
                         .short('s')
                         .long("signature")
                         .value_name("SIGNATURE")
+                        .required_unless_present_any([
+                            "recovery-code",
+                            "escrow-secret",
+                            "signer",
+                            "agent",
+                        ])
+                        .action(ArgAction::Append)
+                        .help("Signature of the password performed by the wallet (once per wallet)"),
+                )
+                .args(signer_args())
+                .group(signer_group().conflicts_with_all(["recovery-code", "escrow-secret"]))
+                .arg(agent_arg())
+                .arg(
+                    Arg::new("recovery-code")
+                        .long("recovery-code")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["address", "signature", "escrow-secret"])
+                        .help("Ask for the recovery code and decrypt with it instead of the wallets"),
+                )
+                .arg(
+                    Arg::new("escrow-secret")
+                        .long("escrow-secret")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["address", "signature"])
+                        .help("Ask for the escrow secret key and decrypt with it instead of the wallets"),
+                )
+                .arg(
+                    Arg::new("preserve-owner")
+                        .long("preserve-owner")
+                        .action(ArgAction::SetTrue)
+                        .help("Restore the recorded owner and group (usually requires root)"),
+                )
+                .arg(
+                    Arg::new("xattrs")
+                        .long("xattrs")
+                        .action(ArgAction::SetTrue)
+                        .help("Restore extended attributes"),
+                )
+                .arg(
+                    Arg::new("acls")
+                        .long("acls")
+                        .action(ArgAction::SetTrue)
+                        .help("Restore POSIX ACLs"),
+                ),
+        )
+        .subcommand(
+            Command::new("recipients")
+                .about("List, add or remove the wallets that can decrypt a file")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List the wallets that can decrypt a file")
+                        .arg(input_arg()),
+                )
+                .subcommand(
+                    Command::new("key")
+                        .about("Print the key others encrypt files for this wallet with")
+                        .arg(address_arg())
+                        .arg(password_arg())
+                        .arg(signature_arg())
+                        .arg(scheme_arg())
+                        .arg(file_id_arg()),
+                )
+                .subcommand(
+                    Command::new("add")
+                        .about("Allow more wallets to decrypt a file without re-encrypting it")
+                        .arg(input_arg())
+                        .arg(address_arg())
+                        .arg(password_arg())
+                        .arg(signature_arg())
+                        .arg(
+                            Arg::new("recipient")
+                                .short('r')
+                                .long("recipient")
+                                .value_name("RECIPIENT_KEY")
+                                .required(true)
+                                .action(ArgAction::Append)
+                                .help("Wallet to add, with its key (repeatable)"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Stop wallets from decrypting a file without re-encrypting it")
+                        .arg(input_arg())
+                        .arg(address_arg())
+                        .arg(password_arg())
+                        .arg(signature_arg())
+                        .arg(
+                            Arg::new("recipient")
+                                .short('r')
+                                .long("recipient")
+                                .value_name("ADDRESS")
+                                .required(true)
+                                .action(ArgAction::Append)
+                                .help("Wallet to remove (repeatable)"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("challenge")
+                .about("Print the exact message the wallet must sign for a password")
+                .arg(
+                    Arg::new("address")
+                        .short('a')
+                        .long("address")
+                        .value_name("ADDRESS")
+                        .help("Address of the signing wallet, which salts the password digest"),
+                )
+                .arg(password_arg())
+                .arg(scheme_arg())
+                .arg(file_id_arg())
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the message and its hash as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("recovery")
+                .about("Generate keys that can decrypt files when their wallets are lost")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("code")
+                        .about("Generate a recovery code, printed once and never stored"),
+                )
+                .subcommand(
+                    Command::new("escrow")
+                        .about("Generate an escrow key pair, printed once and never stored"),
+                ),
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Move an encrypted file to a new wallet or password without writing the plaintext")
+                .arg(input_arg())
+                .arg(address_arg().required(false).required_unless_present("signer"))
+                .arg(password_arg())
+                .arg(
+                    signature_arg()
+                        .required(false)
+                        .required_unless_present("signer")
+                        .conflicts_with("signer"),
+                )
+                .args(signer_args())
+                .group(signer_group())
+                .arg(
+                    Arg::new("new-address")
+                        .long("new-address")
+                        .value_name("ADDRESS")
+                        .required(true)
+                        .help("Address wallet linked to the new signature"),
+                )
+                .arg(
+                    Arg::new("new-password")
+                        .long("new-password")
+                        .value_name("PASSWORD")
+                        .required(true)
+                        .help("New password signed by the wallet"),
+                )
+                .arg(
+                    Arg::new("new-signature")
+                        .long("new-signature")
+                        .value_name("SIGNATURE")
                         .required(true)
-                        .help("Signature of the password performed by the wallet"),
+                        .help("Signature of the new password performed by the wallet"),
+                )
+                .arg(
+                    Arg::new("new-scheme")
+                        .long("new-scheme")
+                        .value_name("SCHEME")
+                        .value_parser(["statement", "personal-sign", "eip712"])
+                        .help("Message signed for the new signature (default: the scheme of the file)"),
+                )
+                .arg(file_id_arg()),
+        )
+        .subcommand(
+            Command::new("agent")
+                .about("Keep a wallet unlocked for encrypt and decrypt, like ssh-agent")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("start")
+                        .about("Hold the wallet and serve requests until it is forgotten")
+                        .arg(socket_arg())
+                        .arg(address_arg().required(false).required_unless_present("signer"))
+                        .arg(password_arg())
+                        .arg(
+                            signature_arg()
+                                .required(false)
+                                .required_unless_present("signer")
+                                .conflicts_with("signer"),
+                        )
+                        .args(signer_args())
+                        .group(signer_group())
+                        .arg(
+                            Arg::new("ttl")
+                                .long("ttl")
+                                .value_name("SECONDS")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Forget the wallet this many seconds after the start"),
+                        )
+                        .arg(
+                            Arg::new("idle-timeout")
+                                .long("idle-timeout")
+                                .value_name("SECONDS")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Forget the wallet after this many seconds without requests"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("lock")
+                        .about("Refuse requests until the agent is unlocked")
+                        .arg(socket_arg()),
+                )
+                .subcommand(
+                    Command::new("unlock")
+                        .about("Serve requests again after `agent lock`")
+                        .arg(socket_arg())
+                        .arg(password_arg()),
+                )
+                .subcommand(
+                    Command::new("forget")
+                        .about("Wipe the wallet and stop the agent")
+                        .arg(socket_arg()),
                 ),
-        ).get_matches()
+        )
+        .get_matches()
+}
+
+/// Socket of a running `agent`, which signs for the first wallet when its
+/// signature is not given.
+fn agent_arg() -> Arg {
+    Arg::new("agent")
+        .long("agent")
+        .value_name("SOCKET")
+        .env("WABASEN_AGENT_SOCK")
+        .help("Agent that signs for the first wallet when no signature or signer is given")
+}
+
+fn socket_arg() -> Arg {
+    Arg::new("socket")
+        .long("socket")
+        .value_name("PATH")
+        .env("WABASEN_AGENT_SOCK")
+        .help("Socket of the agent [default: $XDG_RUNTIME_DIR/wabasen-agent.sock]")
+}
+
+fn input_arg() -> Arg {
+    Arg::new("input")
+        .short('i')
+        .long("input")
+        .value_name("INPUT")
+        .required(true)
+        .help("Input path of encrypted file")
+}
+
+fn address_arg() -> Arg {
+    Arg::new("address")
+        .short('a')
+        .long("address")
+        .value_name("ADDRESS")
+        .required(true)
+        .help("Address wallet linked to the signature")
+}
+
+fn password_arg() -> Arg {
+    Arg::new("password")
+        .short('p')
+        .long("password")
+        .value_name("PASSWORD")
+        .required(true)
+        .default_value("password")
+        .help("Password signed by the wallet")
+}
+
+fn signature_arg() -> Arg {
+    Arg::new("signature")
+        .short('s')
+        .long("signature")
+        .value_name("SIGNATURE")
+        .required(true)
+        .help("Signature of the password performed by the wallet")
+}
+
+/// Wallets that sign the password in place of `--signature`, the first wallet
+/// when several are given.
+fn signer_args() -> [Arg; 8] {
+    [
+        Arg::new("keystore")
+            .long("keystore")
+            .value_name("PATH")
+            .help("Ethereum V3 keystore that signs the password in place of --signature"),
+        Arg::new("mnemonic-file")
+            .long("mnemonic-file")
+            .value_name("PATH")
+            .help(
+                "File holding the BIP-39 mnemonic that signs the password in place of --signature",
+            ),
+        Arg::new("mnemonic-passphrase")
+            .long("mnemonic-passphrase")
+            .action(ArgAction::SetTrue)
+            .requires("mnemonic-file")
+            .help("Ask for the BIP-39 passphrase of the mnemonic"),
+        Arg::new("derivation-path")
+            .long("derivation-path")
+            .value_name("PATH")
+            .default_value(DEFAULT_DERIVATION_PATH)
+            .help("BIP-32 derivation path of the wallet in the mnemonic or on the Ledger"),
+        Arg::new("signer-command")
+            .long("signer-command")
+            .value_name("COMMAND")
+            .requires("address")
+            .help("Program that reads the challenge as JSON on stdin and prints the signature"),
+        Arg::new("rpc-url")
+            .long("rpc-url")
+            .value_name("URL")
+            .requires("address")
+            .help("Ethereum node that signs with the unlocked account of --address"),
+        Arg::new("ledger")
+            .long("ledger")
+            .action(ArgAction::SetTrue)
+            .help("Sign on a Ledger plugged in over USB, in its Ethereum app"),
+        Arg::new("speculos")
+            .long("speculos")
+            .value_name("HOST:PORT")
+            .help("Sign on the Speculos Ledger emulator at its APDU port"),
+    ]
+}
+
+fn signer_group() -> ArgGroup {
+    ArgGroup::new("signer").args([
+        "keystore",
+        "mnemonic-file",
+        "signer-command",
+        "rpc-url",
+        "ledger",
+        "speculos",
+    ])
+}
+
+fn scheme_arg() -> Arg {
+    Arg::new("scheme")
+        .long("scheme")
+        .value_name("SCHEME")
+        .value_parser(["statement", "personal-sign", "eip712"])
+        .default_value("statement")
+        .help("Message signed by the wallets, recorded in the file")
+}
+
+fn file_id_arg() -> Arg {
+    Arg::new("file-id")
+        .long("file-id")
+        .value_name("ID")
+        .default_value("")
+        .help("File id bound into the signed message")
 }
@@ -0,0 +1,256 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::Address, hash_message, parse_address, scheme::TypedData, signature::Signature,
+    signer::Signer,
+};
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    process::{Child, Command, Output, Stdio},
+};
+
+/// External program that signs for a wallet, like git's `gpg.program`. It
+/// receives a JSON object on stdin with the `address` of the wallet, the
+/// signing `method`, and the `message` or `typedData` to sign, and prints the
+/// signature.
+pub struct CommandSigner {
+    command: String,
+    address: Address,
+}
+
+impl CommandSigner {
+    pub fn new(command: &str, address: &str) -> Result<CommandSigner, String> {
+        Ok(CommandSigner {
+            command: command.to_string(),
+            address: parse_address(address)?,
+        })
+    }
+}
+
+impl Signer for CommandSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Passes the message as text, or as `0x`-prefixed hex when it is not UTF-8.
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        let text: String = match std::str::from_utf8(message) {
+            Ok(f) => f.to_string(),
+            Err(_) => format!("0x{}", hex::encode(message)),
+        };
+
+        self.run(json!({
+            "address": self.address.to_lowercase(),
+            "method": "personal_sign",
+            "message": text,
+            "hash": format!("0x{}", hex::encode(hash_message(message))),
+        }))
+    }
+
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        self.run(json!({
+            "address": self.address.to_lowercase(),
+            "method": "eth_signTypedData_v4",
+            "typedData": typed_data.json(),
+            "hash": format!("0x{}", hex::encode(typed_data.hash())),
+        }))
+    }
+}
+
+impl CommandSigner {
+    /// Runs the command with `request` on its stdin and returns the signature
+    /// it printed, in its canonical hex form.
+    fn run(&self, request: Value) -> Result<String, String> {
+        let mut child: Child = match shell(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+        {
+            Ok(f) => f,
+            Err(_) => {
+                return Err(format!(
+                    "Failed to run the signer command ({})",
+                    self.command
+                ))
+            }
+        };
+
+        // A command that exits without reading the request fails below on its
+        // exit status or its output, which explains more than a broken pipe.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = writeln!(stdin, "{}", request);
+        }
+
+        let output: Output = match child.wait_with_output() {
+            Ok(f) => f,
+            Err(_) => {
+                return Err(format!(
+                    "Failed to run the signer command ({})",
+                    self.command
+                ))
+            }
+        };
+
+        if !output.status.success() {
+            return Err(format!(
+                "The signer command failed with {} ({})",
+                output.status, self.command
+            ));
+        }
+
+        match String::from_utf8(output.stdout)
+            .ok()
+            .and_then(|text| Signature::parse(&text).ok())
+        {
+            Some(f) => Ok(f.to_hex()),
+            None => Err(format!(
+                "The signer command printed an invalid signature ({})",
+                self.command
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell: Command = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell: Command = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{scheme::Scheme, signer::sign_password, wallet::LocalWallet, Credential};
+    use std::fs::read_to_string;
+    use tempfile::TempDir;
+
+    const PRIVATE_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(PRIVATE_KEY).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn the_request_goes_to_stdin_and_the_signature_comes_back() {
+        let work: TempDir = TempDir::new().unwrap();
+        let request_path: String = work.path().join("request.json").display().to_string();
+        let signature: String = wallet().sign_message(b"password").unwrap();
+
+        // Prints the signature in another encoding, to check it comes back canonical.
+        let command: String = format!(
+            "cat > '{}'; echo '  {}'",
+            request_path,
+            signature.trim_start_matches("0x").to_uppercase()
+        );
+        let signer: CommandSigner =
+            CommandSigner::new(&command, &wallet().address().to_checksum()).unwrap();
+
+        let credential: Credential =
+            sign_password(&signer, "password", &Scheme::PersonalSign).unwrap();
+        assert_eq!(credential.signature, signature);
+
+        let request: Value = serde_json::from_str(&read_to_string(&request_path).unwrap()).unwrap();
+        assert_eq!(request["address"], wallet().address().to_lowercase());
+        assert_eq!(request["method"], "personal_sign");
+        assert_eq!(request["message"], "password");
+        assert_eq!(
+            request["hash"],
+            format!("0x{}", hex::encode(hash_message(b"password")))
+        );
+    }
+
+    #[test]
+    fn typed_data_requests_carry_the_payload() {
+        let work: TempDir = TempDir::new().unwrap();
+        let request_path: String = work.path().join("request.json").display().to_string();
+        let scheme: Scheme = Scheme::parse("eip712", "report").unwrap();
+        let typed_data: TypedData = scheme
+            .typed_data(&wallet().address().to_lowercase(), "password")
+            .unwrap();
+        let signature: String = wallet().sign_typed_data(&typed_data).unwrap();
+
+        let command: String = format!("cat > '{}'; echo {}", request_path, signature);
+        let signer: CommandSigner =
+            CommandSigner::new(&command, &wallet().address().to_checksum()).unwrap();
+
+        assert_eq!(signer.sign_typed_data(&typed_data).unwrap(), signature);
+
+        let request: Value = serde_json::from_str(&read_to_string(&request_path).unwrap()).unwrap();
+        assert_eq!(request["method"], "eth_signTypedData_v4");
+        assert_eq!(&request["typedData"], typed_data.json());
+        assert_eq!(
+            request["hash"],
+            format!("0x{}", hex::encode(typed_data.hash()))
+        );
+    }
+
+    #[test]
+    fn a_failing_command_is_an_error() {
+        let signer: CommandSigner =
+            CommandSigner::new("cat > /dev/null; exit 3", &wallet().address().to_checksum())
+                .unwrap();
+
+        let error: String = signer.sign_message(b"password").unwrap_err();
+        assert!(
+            error.starts_with("The signer command failed with"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn garbage_output_is_an_error() {
+        let signer: CommandSigner = CommandSigner::new(
+            "cat > /dev/null; echo 'not a signature'",
+            &wallet().address().to_checksum(),
+        )
+        .unwrap();
+
+        let error: String = signer.sign_message(b"password").unwrap_err();
+        assert!(
+            error.starts_with("The signer command printed an invalid signature"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn a_signature_of_another_wallet_is_rejected() {
+        let other: LocalWallet = LocalWallet::from_private_key(&[7u8; 32]).unwrap();
+        let command: String = format!(
+            "cat > /dev/null; echo {}",
+            other.sign_message(b"password").unwrap()
+        );
+        let signer: CommandSigner =
+            CommandSigner::new(&command, &wallet().address().to_checksum()).unwrap();
+
+        assert!(sign_password(&signer, "password", &Scheme::PersonalSign).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+//! Constant-time comparisons for everything derived from a secret: recovered
+//! addresses, key slot ids, fingerprints, MACs and passwords. Their timing
+//! only depends on the lengths compared, never on where the bytes differ.
+
+use subtle::ConstantTimeEq;
+
+#[cfg(test)]
+thread_local! {
+    static CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Tells whether `a` and `b` hold the same bytes.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    #[cfg(test)]
+    CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    a.ct_eq(b).into()
+}
+
+/// Number of constant-time comparisons made so far by the current thread, so
+/// that tests can check which paths go through them.
+#[cfg(test)]
+pub(crate) fn ct_eq_calls() -> usize {
+    CALLS.with(|calls| calls.get())
+}
+
+/// Tells whether `bytes` starts with `prefix`.
+pub(crate) fn ct_starts_with(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && ct_eq(&bytes[..prefix.len()], prefix)
+}
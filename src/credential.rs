@@ -0,0 +1,366 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    header::{wallet_secret, KEY_SIZE},
+    parse_address,
+    recovery::RecoveryKey,
+    scheme::Scheme,
+    signer::{sign_password, Signer},
+    verify_password_from_signature,
+};
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+/// A wallet address together with a password and the wallet's signature of it.
+#[derive(Clone)]
+pub struct Credential {
+    pub address: String,
+    pub password: String,
+    pub signature: String,
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        self.password.zeroize();
+        self.signature.zeroize();
+    }
+}
+
+/// Public key a wallet publishes once so that others can encrypt files for it,
+/// as printed by `recipient_key`. It is an X25519 key derived from the wallet's
+/// signature of its password, so only that wallet and password open the file.
+/// The signature depends on the scheme and file id, so the key only serves
+/// files written with the ones it records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientKey {
+    pub address: String,
+    /// Hex X25519 public key.
+    pub public_key: String,
+    /// Scheme, with its file id, the wallet signed under to derive the key.
+    pub scheme: Scheme,
+}
+
+/// Wallets that can open the files written by `encrypt`, on top of the one
+/// encrypting them.
+#[derive(Clone, Default)]
+pub struct AccessOptions {
+    /// Additional wallets allowed to decrypt.
+    pub recipients: Vec<RecipientKey>,
+    /// Number of wallets, among the encrypting one and `recipients`, that must
+    /// provide their signature together to decrypt. `None` lets each of them
+    /// decrypt on its own.
+    pub threshold: Option<usize>,
+    /// Recovery code that can also decrypt on its own.
+    pub recovery_code: Option<String>,
+    /// Hex X25519 public key of an escrow that can also decrypt on its own.
+    pub escrow_key: Option<String>,
+    /// What the wallets sign, recorded in the file header.
+    pub scheme: Scheme,
+}
+
+/// What opens a file being decrypted: wallet credentials, or the recovery key
+/// of a recovery slot.
+pub(crate) enum Unlock<'a> {
+    Wallets(&'a Wallets),
+    Recovery(&'a RecoveryKey),
+}
+
+/// Wallets decrypting files: the first one has signed for each scheme of the
+/// files, the cosigners come with their own signatures. A failed signature
+/// only fails the files signed under its scheme.
+pub(crate) struct Wallets {
+    signed: Vec<(Scheme, Result<Credential, String>)>,
+    cosigners: Vec<Credential>,
+}
+
+impl Wallets {
+    /// Has `signer` sign once for each of `schemes` up front, so that files can
+    /// then be decrypted in parallel without asking the wallet again.
+    pub(crate) fn sign(
+        signer: &dyn Signer,
+        password: &str,
+        cosigners: &[Credential],
+        schemes: &[Scheme],
+    ) -> Wallets {
+        let mut signed: Vec<(Scheme, Result<Credential, String>)> = Vec::new();
+
+        for scheme in schemes {
+            if !signed
+                .iter()
+                .any(|(signed_scheme, _)| signed_scheme == scheme)
+            {
+                signed.push((scheme.clone(), sign_password(signer, password, scheme)));
+            }
+        }
+
+        Wallets {
+            signed,
+            cosigners: cosigners.to_vec(),
+        }
+    }
+
+    /// Returns the credentials for a file signed under `scheme`, the first
+    /// wallet first.
+    pub(crate) fn credentials(&self, scheme: &Scheme) -> Result<Vec<Credential>, String> {
+        let first: &Credential = match self
+            .signed
+            .iter()
+            .find(|(signed_scheme, _)| signed_scheme == scheme)
+        {
+            Some((_, Ok(f))) => f,
+            Some((_, Err(e))) => return Err(e.clone()),
+            None => {
+                return Err(format!(
+                    "The wallet has not signed for the {} scheme",
+                    scheme.name()
+                ))
+            }
+        };
+
+        let mut credentials: Vec<Credential> = vec![first.clone()];
+        credentials.extend_from_slice(&self.cosigners);
+
+        Ok(credentials)
+    }
+}
+
+impl Credential {
+    pub fn new(address: &str, password: &str, signature: &str) -> Credential {
+        Credential {
+            address: address.to_string(),
+            password: password.to_string(),
+            signature: signature.to_string(),
+        }
+    }
+
+    /// Parses the `ADDRESS:PASSWORD:SIGNATURE` form taken by `--recipient`.
+    /// The password may itself contain colons.
+    pub fn parse(value: &str) -> Result<Credential, String> {
+        let (address, rest) = match value.split_once(':') {
+            Some(f) => f,
+            None => return Err(format!("Invalid recipient format ({})", value)),
+        };
+
+        let (password, signature) = match rest.rsplit_once(':') {
+            Some(f) => f,
+            None => return Err(format!("Invalid recipient format ({})", value)),
+        };
+
+        Ok(Credential::new(address, password, signature))
+    }
+
+    /// Checks that the signature is the wallet's signature of the password
+    /// under `scheme`.
+    pub(crate) fn verify(&self, scheme: &Scheme) -> Result<(), String> {
+        verify_password_from_signature(&self.address, &self.password, &self.signature, scheme)
+    }
+}
+
+impl RecipientKey {
+    /// Checks the address and the public key, and returns them normalized.
+    pub fn new(address: &str, public_key: &str, scheme: Scheme) -> Result<RecipientKey, String> {
+        let address: String = parse_address(address)?.to_string();
+
+        let public_key: String = match hex::decode(public_key.trim_start_matches("0x")) {
+            Ok(bytes) if bytes.len() == KEY_SIZE => hex::encode(bytes),
+            _ => return Err(format!("Invalid recipient key format ({})", public_key)),
+        };
+
+        Ok(RecipientKey {
+            address,
+            public_key,
+            scheme,
+        })
+    }
+
+    /// Parses the `ADDRESS:PUBLIC_KEY:SCHEME[:FILE_ID]` form taken by
+    /// `--recipient`. The file id may itself contain colons.
+    pub fn parse(value: &str) -> Result<RecipientKey, String> {
+        let parts: Vec<&str> = value.splitn(4, ':').collect();
+
+        match parts[..] {
+            [address, public_key, scheme] => {
+                RecipientKey::new(address, public_key, Scheme::parse(scheme, "")?)
+            }
+            [address, public_key, scheme, file_id] => {
+                RecipientKey::new(address, public_key, Scheme::parse(scheme, file_id)?)
+            }
+            _ => Err(format!("Invalid recipient format ({})", value)),
+        }
+    }
+
+    /// Checks that the key was derived for files written under `scheme`, which
+    /// the wallet must sign again to decrypt them.
+    pub(crate) fn check_scheme(&self, scheme: &Scheme) -> Result<(), String> {
+        match self.scheme == *scheme {
+            true => Ok(()),
+            false => Err(format!(
+                "Invalid recipient key, it serves the {} scheme with file id '{}' and the file uses the {} scheme with file id '{}' ({})",
+                self.scheme.name(),
+                self.scheme.file_id(),
+                scheme.name(),
+                scheme.file_id(),
+                self.address
+            )),
+        }
+    }
+
+    /// Derives the key of the wallet of `credential`, once its signature is
+    /// checked under `scheme`.
+    pub(crate) fn derive(credential: &Credential, scheme: &Scheme) -> Result<RecipientKey, String> {
+        credential.verify(scheme)?;
+
+        let secret: StaticSecret = wallet_secret(credential)?;
+
+        RecipientKey::new(
+            &credential.address,
+            &hex::encode(PublicKey::from(&secret).as_bytes()),
+            scheme.clone(),
+        )
+    }
+
+    pub(crate) fn public_key_bytes(&self) -> Result<[u8; KEY_SIZE], String> {
+        match hex::decode(self.public_key.trim_start_matches("0x")) {
+            Ok(bytes) => match bytes.try_into() {
+                Ok(f) => Ok(f),
+                Err(_) => Err(format!(
+                    "Invalid recipient key format ({})",
+                    self.public_key
+                )),
+            },
+            Err(_) => Err(format!(
+                "Invalid recipient key format ({})",
+                self.public_key
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RecipientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.address,
+            self.public_key,
+            self.scheme.name()
+        )?;
+
+        match self.scheme.file_id() {
+            "" => Ok(()),
+            file_id => write!(f, ":{}", file_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_access, wallet::LocalWallet, AccessOptions};
+    use proptest::prelude::*;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const SECOND_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn wallet(private_key: &str) -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(private_key).unwrap()).unwrap()
+    }
+
+    fn access(recipient: &RecipientKey, scheme: &Scheme) -> AccessOptions {
+        AccessOptions {
+            recipients: vec![recipient.clone()],
+            scheme: scheme.clone(),
+            ..AccessOptions::default()
+        }
+    }
+
+    #[test]
+    fn recipient_keys_only_serve_their_scheme_and_file_id() {
+        let document: Scheme = Scheme::parse("statement", "doc1").unwrap();
+        let signer: LocalWallet = wallet(FIRST_KEY);
+        let recipient: LocalWallet = wallet(SECOND_KEY);
+
+        let default_key: RecipientKey =
+            crate::recipient_key(&recipient, "password", &Scheme::default()).unwrap();
+        let document_key: RecipientKey =
+            crate::recipient_key(&recipient, "password", &document).unwrap();
+
+        assert_ne!(default_key.public_key, document_key.public_key);
+        assert_eq!(
+            RecipientKey::parse(&document_key.to_string()),
+            Ok(document_key.clone())
+        );
+
+        let error: String =
+            match verify_access(&signer, "password", &access(&default_key, &document)) {
+                Ok(_) => panic!("a key made for another file id was accepted"),
+                Err(e) => e,
+            };
+        assert!(error.contains("file id 'doc1'"), "{}", error);
+
+        assert!(verify_access(&signer, "password", &access(&document_key, &document)).is_ok());
+        assert!(verify_access(
+            &signer,
+            "password",
+            &access(&document_key, &Scheme::PersonalSign)
+        )
+        .is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(text in "\\PC*") {
+            let _ = Credential::parse(&text);
+        }
+
+        #[test]
+        fn parse_round_trips(
+            address in "[^:]*",
+            password in "\\PC*",
+            signature in "[^:]*",
+        ) {
+            let credential: Credential =
+                Credential::parse(&format!("{}:{}:{}", address, password, signature)).unwrap();
+
+            prop_assert_eq!(&credential.address, &address);
+            prop_assert_eq!(&credential.password, &password);
+            prop_assert_eq!(&credential.signature, &signature);
+        }
+
+        #[test]
+        fn recipient_key_parse_round_trips(
+            address in proptest::array::uniform20(any::<u8>()),
+            public_key in proptest::array::uniform32(any::<u8>()),
+            scheme in prop_oneof!["statement", "personal-sign", "eip712"],
+            file_id in "\\PC*",
+        ) {
+            let recipient: RecipientKey = RecipientKey::new(
+                &crate::Address::from_bytes(address).to_checksum(),
+                &hex::encode(public_key),
+                Scheme::parse(&scheme, &file_id).unwrap(),
+            )
+            .unwrap();
+
+            prop_assert_eq!(RecipientKey::parse(&recipient.to_string()), Ok(recipient.clone()));
+            prop_assert_eq!(recipient.public_key_bytes(), Ok(public_key));
+        }
+    }
+}
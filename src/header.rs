@@ -0,0 +1,910 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::{Address, ADDRESS_SIZE},
+    compare::{ct_eq, ct_starts_with},
+    credential::{AccessOptions, Credential, RecipientKey},
+    parse_address, parse_signature,
+    recovery::{escrow_unwrap_key, escrow_wrap_key, recovery_code_key, RecoveryKey},
+    scheme::Scheme,
+    secret::{secret_keccak256, SecretBytes},
+    shamir,
+};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305,
+};
+use std::{
+    fs::{rename, File},
+    io::{copy, Read, Seek, SeekFrom, Write},
+};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 4] = b"WABA";
+const VERSION: u8 = 1;
+/// Version of the key slot format, bound into every slot.
+const SLOT_VERSION: u8 = 1;
+const WALLET_KEY_DOMAIN: &[u8] = b"Wabasen wallet key v1";
+const WALLET_SLOT_DOMAIN: &[u8] = b"Wabasen wallet slot v1";
+
+pub(crate) const KEY_SIZE: usize = 32;
+pub(crate) const NONCE_SIZE: usize = 19;
+const SLOT_NONCE_SIZE: usize = 24;
+
+/// Slot holding the file key wrapped for the X25519 key of one wallet. Its id
+/// is the address followed by the ephemeral public key of the exchange.
+pub(crate) const SLOT_WALLET: u8 = 1;
+/// Slot holding one Shamir share of the file key, wrapped for the X25519 key of
+/// one wallet. Its id is the address, the threshold, the share index and the
+/// ephemeral public key of the exchange.
+pub(crate) const SLOT_WALLET_SHARE: u8 = 2;
+/// Slot holding the file key wrapped with a recovery code. Its id is a short
+/// fingerprint of the code.
+pub(crate) const SLOT_RECOVERY: u8 = 3;
+/// Slot holding the file key wrapped for an X25519 escrow key. Its id is the
+/// escrow public key followed by the ephemeral public key of the exchange.
+pub(crate) const SLOT_ESCROW: u8 = 4;
+
+/// Header written in front of the encrypted payload.
+///
+/// The payload is encrypted with a random file key, and every key slot wraps
+/// that key for one way of opening the file. Files written before headers
+/// existed start directly with ciphertext and are still read as such.
+///
+/// Layout: `WABA`, version byte, signing scheme (u8), file id length (u8),
+/// file id, payload nonce (19 bytes), slot count (u16 BE), then for each slot:
+/// kind, id length (u8), id, nonce (24 bytes), wrapped key length (u16 BE),
+/// wrapped key.
+pub(crate) struct Header {
+    pub(crate) scheme: Scheme,
+    pub(crate) nonce: [u8; NONCE_SIZE],
+    pub(crate) slots: Vec<Slot>,
+}
+
+pub(crate) struct Slot {
+    pub(crate) kind: u8,
+    pub(crate) id: Vec<u8>,
+    nonce: [u8; SLOT_NONCE_SIZE],
+    wrapped_key: Vec<u8>,
+}
+
+impl Header {
+    /// Creates a header for a new file key, readable by every recipient of
+    /// `access` or, with a threshold, by enough of them together.
+    pub(crate) fn generate(
+        access: &AccessOptions,
+    ) -> Result<(Header, SecretBytes<KEY_SIZE>), String> {
+        let mut nonce: [u8; NONCE_SIZE] = [0u8; NONCE_SIZE];
+        let mut file_key: SecretBytes<KEY_SIZE> = SecretBytes::zeroed();
+
+        OsRng.fill_bytes(&mut nonce);
+        OsRng.fill_bytes(&mut *file_key);
+
+        if access.scheme.file_id().len() > u8::MAX as usize {
+            return Err("The file id is longer than 255 bytes".to_string());
+        }
+
+        let mut header: Header = Header {
+            scheme: access.scheme.clone(),
+            nonce,
+            slots: Vec::new(),
+        };
+
+        match access.threshold {
+            Some(0) => return Err("Invalid threshold, at least 1 wallet must sign".to_string()),
+            Some(threshold) if threshold > 1 => {
+                header.add_shares(&file_key, &access.recipients, threshold)?
+            }
+            _ => {
+                for recipient in &access.recipients {
+                    header.add_recipient(&file_key, recipient)?;
+                }
+            }
+        };
+
+        if let Some(code) = &access.recovery_code {
+            let (kek, id): (SecretBytes<KEY_SIZE>, Vec<u8>) = recovery_code_key(code)?;
+            header
+                .slots
+                .push(Slot::wrap(SLOT_RECOVERY, id, &kek, &*file_key)?);
+        }
+
+        if let Some(public_key) = &access.escrow_key {
+            let (kek, id): (SecretBytes<KEY_SIZE>, Vec<u8>) = escrow_wrap_key(public_key)?;
+            header
+                .slots
+                .push(Slot::wrap(SLOT_ESCROW, id, &kek, &*file_key)?);
+        }
+
+        Ok((header, file_key))
+    }
+
+    /// Wraps `file_key` for `recipient`, replacing any slot of the same address.
+    pub(crate) fn add_recipient(
+        &mut self,
+        file_key: &[u8; KEY_SIZE],
+        recipient: &RecipientKey,
+    ) -> Result<(), String> {
+        let address: Vec<u8> = parse_address(&recipient.address)?.as_bytes().to_vec();
+        let (kek, id): (SecretBytes<KEY_SIZE>, Vec<u8>) =
+            wallet_wrap_key(recipient, address.clone())?;
+
+        self.remove_slot(&address);
+        self.slots
+            .push(Slot::wrap(SLOT_WALLET, id, &kek, file_key)?);
+
+        Ok(())
+    }
+
+    fn add_shares(
+        &mut self,
+        file_key: &[u8; KEY_SIZE],
+        recipients: &[RecipientKey],
+        threshold: usize,
+    ) -> Result<(), String> {
+        if threshold > recipients.len() || recipients.len() > u8::MAX as usize {
+            return Err(format!(
+                "Invalid threshold, {} of {} wallets",
+                threshold,
+                recipients.len()
+            ));
+        }
+
+        let shares: Vec<(u8, Zeroizing<Vec<u8>>)> =
+            shamir::split(file_key, threshold as u8, recipients.len() as u8);
+
+        for (recipient, (x, share)) in recipients.iter().zip(shares) {
+            let mut id: Vec<u8> = parse_address(&recipient.address)?.as_bytes().to_vec();
+
+            if self.slots.iter().any(|slot| ct_starts_with(&slot.id, &id)) {
+                return Err(format!("Duplicate wallet ({})", recipient.address));
+            }
+
+            id.push(threshold as u8);
+            id.push(x);
+
+            let (kek, id): (SecretBytes<KEY_SIZE>, Vec<u8>) = wallet_wrap_key(recipient, id)?;
+
+            self.slots
+                .push(Slot::wrap(SLOT_WALLET_SHARE, id, &kek, &share)?);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the slot holding the whole file key for the wallet `address` and
+    /// reports whether one existed.
+    pub(crate) fn remove_slot(&mut self, address: &[u8]) -> bool {
+        let count: usize = self.slots.len();
+
+        self.slots
+            .retain(|slot| !(slot.kind == SLOT_WALLET && ct_starts_with(&slot.id, address)));
+
+        self.slots.len() != count
+    }
+
+    /// Recovers the file key, either from the wallet slot of one of the
+    /// credentials or from the shares they unlock together. A share given twice
+    /// only counts once towards the threshold.
+    pub(crate) fn unwrap_key(
+        &self,
+        credentials: &[Credential],
+    ) -> Result<SecretBytes<KEY_SIZE>, String> {
+        let mut shares: Vec<(u8, Zeroizing<Vec<u8>>)> = Vec::new();
+        let threshold: usize = self.share_threshold()?.unwrap_or_default();
+
+        for credential in credentials {
+            let address: Vec<u8> = parse_address(&credential.address)?.as_bytes().to_vec();
+
+            let slot: &Slot = match self.find_slot(&address) {
+                Some(index) => &self.slots[index],
+                None => {
+                    return Err(format!(
+                        "The file is not encrypted for wallet ({})",
+                        credential.address
+                    ))
+                }
+            };
+
+            credential.verify(&self.scheme)?;
+
+            let kek: SecretBytes<KEY_SIZE> = wallet_unwrap_key(credential, &slot.id)?;
+
+            let key: Zeroizing<Vec<u8>> = match slot.unwrap(&kek) {
+                Some(f) => f,
+                None => {
+                    return Err(format!(
+                        "Failed to unwrap the file key for wallet ({})",
+                        credential.address
+                    ))
+                }
+            };
+
+            if slot.kind == SLOT_WALLET {
+                return to_key(&key);
+            }
+
+            let x: u8 = slot.id[ADDRESS_SIZE + 1];
+
+            if shares.iter().any(|(share_x, _)| *share_x == x) {
+                continue;
+            }
+
+            shares.push((x, key));
+        }
+
+        if shares.is_empty() {
+            return Err("No wallet was given to decrypt the file".to_string());
+        }
+
+        if shares.len() < threshold {
+            return Err(format!(
+                "{} of the {} required wallet signatures were given, {} more needed",
+                shares.len(),
+                threshold,
+                threshold - shares.len()
+            ));
+        }
+
+        to_key(&shamir::combine(&shares[..threshold]))
+    }
+
+    /// Recovers the file key from its recovery or escrow slot.
+    pub(crate) fn unwrap_recovery_key(
+        &self,
+        recovery: &RecoveryKey,
+    ) -> Result<SecretBytes<KEY_SIZE>, String> {
+        for slot in &self.slots {
+            let kek: SecretBytes<KEY_SIZE> = match (recovery, slot.kind) {
+                (RecoveryKey::Code(code), SLOT_RECOVERY) => match recovery_code_key(code)? {
+                    (kek, id) if ct_eq(&id, &slot.id) => kek,
+                    _ => continue,
+                },
+                (RecoveryKey::EscrowSecret(secret_key), SLOT_ESCROW) => {
+                    match escrow_unwrap_key(secret_key, &slot.id)? {
+                        Some(f) => f,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            return match slot.unwrap(&kek) {
+                Some(key) => to_key(&key),
+                None => Err("Failed to unwrap the file key with the recovery key".to_string()),
+            };
+        }
+
+        Err("The file has no recovery slot for this key".to_string())
+    }
+
+    /// Moves the slot of `old` to `new`: the key or share it holds is unwrapped
+    /// with the old credential and wrapped again for the new one.
+    /// With `scheme`, the file moves to that signing scheme, which is only
+    /// possible when no other wallet can open it.
+    pub(crate) fn rekey_slot(
+        &mut self,
+        old: &Credential,
+        new: &Credential,
+        scheme: Option<&Scheme>,
+    ) -> Result<(), String> {
+        let scheme: Scheme = scheme.unwrap_or(&self.scheme).clone();
+
+        old.verify(&self.scheme)?;
+
+        let recipient: RecipientKey = RecipientKey::derive(new, &scheme)?;
+
+        let wallet_slots: usize = self
+            .slots
+            .iter()
+            .filter(|slot| slot.kind != SLOT_RECOVERY && slot.kind != SLOT_ESCROW)
+            .count();
+
+        if scheme != self.scheme && wallet_slots > 1 {
+            return Err(
+                "The other wallets of the file sign with its current scheme, remove them first"
+                    .to_string(),
+            );
+        }
+
+        let old_address: Vec<u8> = parse_address(&old.address)?.as_bytes().to_vec();
+        let new_address: Vec<u8> = parse_address(&new.address)?.as_bytes().to_vec();
+
+        let index: usize = match self.find_slot(&old_address) {
+            Some(f) => f,
+            None => {
+                return Err(format!(
+                    "The file is not encrypted for wallet ({})",
+                    old.address
+                ))
+            }
+        };
+
+        if !ct_eq(&new_address, &old_address) && self.find_slot(&new_address).is_some() {
+            return Err(format!(
+                "The file is already encrypted for wallet ({})",
+                new.address
+            ));
+        }
+
+        let old_kek: SecretBytes<KEY_SIZE> = wallet_unwrap_key(old, &self.slots[index].id)?;
+
+        let key: Zeroizing<Vec<u8>> = match self.slots[index].unwrap(&old_kek) {
+            Some(f) => f,
+            None => {
+                return Err(format!(
+                    "Failed to unwrap the file key for wallet ({})",
+                    old.address
+                ))
+            }
+        };
+
+        let mut id: Vec<u8> = new_address;
+
+        let kind: u8 = self.slots[index].kind;
+
+        if kind == SLOT_WALLET_SHARE {
+            id.extend_from_slice(&self.slots[index].id[ADDRESS_SIZE..ADDRESS_SIZE + 2]);
+        }
+
+        let (kek, id): (SecretBytes<KEY_SIZE>, Vec<u8>) = wallet_wrap_key(&recipient, id)?;
+
+        self.slots[index] = Slot::wrap(kind, id, &kek, &key)?;
+        self.scheme = scheme;
+
+        Ok(())
+    }
+
+    /// Returns the threshold recorded by the share slots, or `None` when there
+    /// are none. Every share slot must record the same one, so that no single
+    /// slot decides how many wallets are needed.
+    fn share_threshold(&self) -> Result<Option<usize>, String> {
+        let mut threshold: Option<usize> = None;
+
+        for slot in &self.slots {
+            if slot.kind != SLOT_WALLET_SHARE {
+                continue;
+            }
+
+            let slot_threshold: usize = slot.id[ADDRESS_SIZE] as usize;
+
+            if slot_threshold == 0 || threshold.is_some_and(|f| f != slot_threshold) {
+                return Err(
+                    "Invalid file header, its share slots disagree on the threshold".to_string(),
+                );
+            }
+
+            threshold = Some(slot_threshold);
+        }
+
+        Ok(threshold)
+    }
+
+    fn find_slot(&self, address: &[u8]) -> Option<usize> {
+        self.slots.iter().position(|slot| {
+            matches!(slot.kind, SLOT_WALLET | SLOT_WALLET_SHARE)
+                && ct_starts_with(&slot.id, address)
+        })
+    }
+
+    /// Returns the addresses of every wallet slot, `0x`-prefixed, with the
+    /// policy of share slots appended, followed by the recovery slots.
+    pub(crate) fn recipients(&self) -> Vec<String> {
+        self.slots
+            .iter()
+            .filter_map(|slot| match slot.kind {
+                SLOT_WALLET => Some(slot.address().to_string()),
+                SLOT_WALLET_SHARE => Some(format!(
+                    "{} (share, {} required)",
+                    slot.address(),
+                    slot.id[ADDRESS_SIZE]
+                )),
+                SLOT_RECOVERY => Some(format!("recovery code ({})", hex::encode(&slot.id))),
+                SLOT_ESCROW => Some(format!("escrow key {}", hex::encode(&slot.id[..KEY_SIZE]))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reads the header of the `.waba` file at `path`, failing on files without one.
+    pub(crate) fn open(path: &str) -> Result<Header, String> {
+        let mut file: File = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to open input file ({})", path)),
+        };
+
+        match Header::read(&mut file)? {
+            Some(f) => Ok(f),
+            None => Err(format!(
+                "The file has no key slots, encrypt it again to use them ({})",
+                path
+            )),
+        }
+    }
+
+    /// Replaces the header of the `.waba` file at `path` with this one, copying
+    /// the encrypted payload as is, and swaps the result in atomically.
+    pub(crate) fn rewrite(&self, path: &str) -> Result<(), String> {
+        let temp_path: String = format!("{}_temp", path);
+
+        let result: Result<(), String> = self.write_with_payload_of(path, &temp_path);
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+            return result;
+        }
+
+        match rename(&temp_path, path) {
+            Ok(f) => Ok(f),
+            Err(_) => {
+                let _ = std::fs::remove_file(&temp_path);
+                Err(format!("Failed to replace the encrypted file ({})", path))
+            }
+        }
+    }
+
+    fn write_with_payload_of(&self, from_path: &str, to_path: &str) -> Result<(), String> {
+        let mut source_file: File = match File::open(from_path) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
+        };
+
+        if Header::read(&mut source_file)?.is_none() {
+            return Err(format!("The file has no key slots ({})", from_path));
+        }
+
+        let mut dist_file: File = match File::create(to_path) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
+        };
+
+        self.write(&mut dist_file)?;
+
+        match copy(&mut source_file, &mut dist_file) {
+            Ok(_) => (),
+            Err(_) => return Err(format!("Failed to write file ({})", to_path)),
+        };
+
+        match dist_file.sync_all() {
+            Ok(f) => Ok(f),
+            Err(_) => Err(format!("Failed to write file ({})", to_path)),
+        }
+    }
+
+    /// Reads a header, or rewinds and returns `None` for files without one.
+    pub(crate) fn read<R: Read + Seek>(reader: &mut R) -> Result<Option<Header>, String> {
+        let mut magic: [u8; 4] = [0u8; 4];
+
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            match reader.seek(SeekFrom::Start(0)) {
+                Ok(_) => return Ok(None),
+                Err(_) => return Err("Failed to read file header".to_string()),
+            };
+        }
+
+        let version: u8 = read_array::<_, 1>(reader)?[0];
+
+        if version != VERSION {
+            return Err(format!("Unsupported file format version ({})", version));
+        }
+
+        let scheme_id: u8 = read_array::<_, 1>(reader)?[0];
+        let file_id_length: u8 = read_array::<_, 1>(reader)?[0];
+
+        let file_id: String = match String::from_utf8(read_vec(reader, file_id_length as usize)?) {
+            Ok(f) => f,
+            Err(_) => return Err("Invalid file header".to_string()),
+        };
+
+        let scheme: Scheme = Scheme::from_header(scheme_id, file_id)?;
+
+        let nonce: [u8; NONCE_SIZE] = read_array(reader)?;
+        let count: u16 = u16::from_be_bytes(read_array(reader)?);
+
+        let mut slots: Vec<Slot> = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let kind: u8 = read_array::<_, 1>(reader)?[0];
+            let id_length: u8 = read_array::<_, 1>(reader)?[0];
+            let id: Vec<u8> = read_vec(reader, id_length as usize)?;
+            let nonce: [u8; SLOT_NONCE_SIZE] = read_array(reader)?;
+            let key_length: u16 = u16::from_be_bytes(read_array(reader)?);
+            let wrapped_key: Vec<u8> = read_vec(reader, key_length as usize)?;
+
+            if (kind == SLOT_ESCROW && id.len() != 2 * KEY_SIZE)
+                || (kind == SLOT_WALLET && id.len() != ADDRESS_SIZE + KEY_SIZE)
+                || (kind == SLOT_WALLET_SHARE
+                    && (id.len() != ADDRESS_SIZE + 2 + KEY_SIZE || id[ADDRESS_SIZE + 1] == 0))
+            {
+                return Err("Invalid file header".to_string());
+            }
+
+            slots.push(Slot {
+                kind,
+                id,
+                nonce,
+                wrapped_key,
+            });
+        }
+
+        let header: Header = Header {
+            scheme,
+            nonce,
+            slots,
+        };
+
+        header.share_threshold()?;
+
+        Ok(Some(header))
+    }
+
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.scheme.id());
+        bytes.push(self.scheme.file_id().len() as u8);
+        bytes.extend_from_slice(self.scheme.file_id().as_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&(self.slots.len() as u16).to_be_bytes());
+
+        for slot in &self.slots {
+            bytes.push(slot.kind);
+            bytes.push(slot.id.len() as u8);
+            bytes.extend_from_slice(&slot.id);
+            bytes.extend_from_slice(&slot.nonce);
+            bytes.extend_from_slice(&(slot.wrapped_key.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&slot.wrapped_key);
+        }
+
+        match writer.write_all(&bytes) {
+            Ok(f) => Ok(f),
+            Err(_) => Err("Failed to write file header".to_string()),
+        }
+    }
+}
+
+impl Slot {
+    /// Returns the wallet address of a wallet or share slot.
+    fn address(&self) -> Address {
+        let mut bytes: [u8; ADDRESS_SIZE] = [0u8; ADDRESS_SIZE];
+        bytes.copy_from_slice(&self.id[..ADDRESS_SIZE]);
+        Address::from_bytes(bytes)
+    }
+
+    pub(crate) fn wrap(
+        kind: u8,
+        id: Vec<u8>,
+        kek: &[u8; KEY_SIZE],
+        key: &[u8],
+    ) -> Result<Slot, String> {
+        let mut nonce: [u8; SLOT_NONCE_SIZE] = [0u8; SLOT_NONCE_SIZE];
+
+        OsRng.fill_bytes(&mut nonce);
+
+        let aad: Vec<u8> = slot_aad(kind, &id);
+        let aead = XChaCha20Poly1305::new(kek.into());
+
+        let wrapped_key: Vec<u8> = match aead.encrypt(
+            nonce.as_ref().into(),
+            Payload {
+                msg: key,
+                aad: &aad,
+            },
+        ) {
+            Ok(f) => f,
+            Err(_) => return Err("Failed to wrap the file key".to_string()),
+        };
+
+        Ok(Slot {
+            kind,
+            id,
+            nonce,
+            wrapped_key,
+        })
+    }
+
+    pub(crate) fn unwrap(&self, kek: &[u8; KEY_SIZE]) -> Option<Zeroizing<Vec<u8>>> {
+        let aad: Vec<u8> = slot_aad(self.kind, &self.id);
+        let aead = XChaCha20Poly1305::new(kek.into());
+
+        aead.decrypt(
+            self.nonce.as_ref().into(),
+            Payload {
+                msg: &self.wrapped_key,
+                aad: &aad,
+            },
+        )
+        .ok()
+        .map(Zeroizing::new)
+    }
+}
+
+/// Derives the X25519 secret key of a wallet from its signature, in its
+/// canonical form, and the password. Its public half is the wallet's
+/// `RecipientKey`.
+pub(crate) fn wallet_secret(credential: &Credential) -> Result<StaticSecret, String> {
+    let signature: Zeroizing<[u8; 65]> =
+        Zeroizing::new(parse_signature(&credential.signature)?.to_bytes());
+
+    let seed: SecretBytes<KEY_SIZE> = secret_keccak256(&[
+        WALLET_KEY_DOMAIN,
+        &*signature,
+        credential.password.as_bytes(),
+    ]);
+
+    Ok(StaticSecret::from(*seed))
+}
+
+/// Derives a fresh key wrapping a wallet slot for `recipient`. The slot id
+/// returned is `id` followed by the ephemeral public key of the exchange.
+fn wallet_wrap_key(
+    recipient: &RecipientKey,
+    mut id: Vec<u8>,
+) -> Result<(SecretBytes<KEY_SIZE>, Vec<u8>), String> {
+    let public: PublicKey = PublicKey::from(recipient.public_key_bytes()?);
+    let ephemeral: EphemeralSecret = EphemeralSecret::random_from_rng(OsRng);
+
+    id.extend_from_slice(PublicKey::from(&ephemeral).as_bytes());
+
+    let shared: SharedSecret = ephemeral.diffie_hellman(&public);
+
+    Ok((wallet_kek(shared.as_bytes(), &public, &id), id))
+}
+
+/// Derives the key of the wallet slot identified by `id` with the wallet key
+/// of `credential`.
+fn wallet_unwrap_key(credential: &Credential, id: &[u8]) -> Result<SecretBytes<KEY_SIZE>, String> {
+    let secret: StaticSecret = wallet_secret(credential)?;
+
+    let mut ephemeral_public: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
+    ephemeral_public.copy_from_slice(&id[id.len() - KEY_SIZE..]);
+
+    let shared: SharedSecret = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+
+    Ok(wallet_kek(shared.as_bytes(), &PublicKey::from(&secret), id))
+}
+
+fn wallet_kek(shared: &[u8; 32], public: &PublicKey, id: &[u8]) -> SecretBytes<KEY_SIZE> {
+    secret_keccak256(&[WALLET_SLOT_DOMAIN, shared, public.as_bytes(), id])
+}
+
+fn to_key(bytes: &[u8]) -> Result<SecretBytes<KEY_SIZE>, String> {
+    match bytes.try_into() {
+        Ok(f) => Ok(SecretBytes::copy_from(f)),
+        Err(_) => Err("Invalid file key size".to_string()),
+    }
+}
+
+fn slot_aad(kind: u8, id: &[u8]) -> Vec<u8> {
+    let mut aad: Vec<u8> = vec![SLOT_VERSION, kind];
+    aad.extend_from_slice(id);
+    aad
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], String> {
+    let mut bytes: [u8; N] = [0u8; N];
+
+    match reader.read_exact(&mut bytes) {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err("Invalid file header".to_string()),
+    }
+}
+
+fn read_vec<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = vec![0u8; length];
+
+    match reader.read_exact(&mut bytes) {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err("Invalid file header".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compare::ct_eq_calls,
+        recovery::{generate_escrow_keys, generate_recovery_code},
+        signer::sign_password,
+        wallet::LocalWallet,
+    };
+    use std::io::Cursor;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const SECOND_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+    const THIRD_KEY: &str = "5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a";
+
+    fn credential(private_key: &str) -> Credential {
+        let wallet: LocalWallet =
+            LocalWallet::from_private_key(&hex::decode(private_key).unwrap()).unwrap();
+
+        sign_password(&wallet, "password", &Scheme::default()).unwrap()
+    }
+
+    fn access(credentials: &[&Credential], threshold: Option<usize>) -> AccessOptions {
+        AccessOptions {
+            recipients: credentials
+                .iter()
+                .map(|credential| RecipientKey::derive(credential, &Scheme::default()).unwrap())
+                .collect(),
+            threshold,
+            ..AccessOptions::default()
+        }
+    }
+
+    #[test]
+    fn duplicate_shares_count_once() {
+        let first: Credential = credential(FIRST_KEY);
+        let second: Credential = credential(SECOND_KEY);
+        let third: Credential = credential(THIRD_KEY);
+
+        let (header, file_key): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&access(&[&first, &second, &third], Some(2))).unwrap();
+
+        let error: String = match header.unwrap_key(&[first.clone(), first.clone()]) {
+            Ok(_) => panic!("a share given twice reached the threshold"),
+            Err(e) => e,
+        };
+        assert!(error.contains("1 more needed"), "{}", error);
+
+        let key: SecretBytes<KEY_SIZE> = header.unwrap_key(&[first.clone(), first, third]).unwrap();
+        assert_eq!(*key, *file_key);
+    }
+
+    #[test]
+    fn share_slots_must_agree_on_the_threshold() {
+        let first: Credential = credential(FIRST_KEY);
+        let second: Credential = credential(SECOND_KEY);
+        let third: Credential = credential(THIRD_KEY);
+
+        let (mut header, _): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&access(&[&first, &second, &third], Some(2))).unwrap();
+
+        header.slots[2].id[ADDRESS_SIZE] = 1;
+
+        assert!(header.unwrap_key(&[third]).is_err());
+
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write(&mut bytes).unwrap();
+
+        assert!(Header::read(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn only_the_current_version_is_read() {
+        let first: Credential = credential(FIRST_KEY);
+
+        let (header, _): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&access(&[&first], None)).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(bytes[MAGIC.len()], VERSION);
+        assert!(Header::read(&mut Cursor::new(bytes.clone()))
+            .unwrap()
+            .is_some());
+
+        for version in [0, 2, 3] {
+            bytes[MAGIC.len()] = version;
+            assert!(Header::read(&mut Cursor::new(bytes.clone())).is_err());
+        }
+    }
+
+    #[test]
+    fn zero_threshold_is_rejected() {
+        let first: Credential = credential(FIRST_KEY);
+
+        assert!(Header::generate(&access(&[&first], Some(0))).is_err());
+    }
+
+    fn address(credential: &Credential) -> Vec<u8> {
+        parse_address(&credential.address)
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    /// Returns `address` with its last byte changed, as close as an address
+    /// gets to another without matching it.
+    fn near_miss(address: &[u8]) -> Vec<u8> {
+        let mut address: Vec<u8> = address.to_vec();
+        address[ADDRESS_SIZE - 1] ^= 1;
+        address
+    }
+
+    #[test]
+    fn slot_lookup_compares_in_constant_time() {
+        let first: Credential = credential(FIRST_KEY);
+        let second: Credential = credential(SECOND_KEY);
+
+        let (header, _): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&access(&[&first, &second], None)).unwrap();
+
+        let calls: usize = ct_eq_calls();
+        assert_eq!(header.find_slot(&address(&second)), Some(1));
+        assert!(ct_eq_calls() > calls);
+
+        let calls: usize = ct_eq_calls();
+        assert_eq!(header.find_slot(&near_miss(&address(&second))), None);
+        assert!(ct_eq_calls() >= calls + header.slots.len());
+    }
+
+    #[test]
+    fn slot_removal_compares_in_constant_time() {
+        let first: Credential = credential(FIRST_KEY);
+        let second: Credential = credential(SECOND_KEY);
+
+        let (mut header, _): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&access(&[&first, &second], None)).unwrap();
+
+        let calls: usize = ct_eq_calls();
+        assert!(!header.remove_slot(&near_miss(&address(&first))));
+        assert!(ct_eq_calls() >= calls + 2);
+        assert_eq!(header.slots.len(), 2);
+
+        let calls: usize = ct_eq_calls();
+        assert!(header.remove_slot(&address(&first)));
+        assert!(ct_eq_calls() > calls);
+        assert_eq!(header.find_slot(&address(&first)), None);
+        assert_eq!(header.find_slot(&address(&second)), Some(0));
+    }
+
+    #[test]
+    fn recovery_lookup_compares_in_constant_time() {
+        let first: Credential = credential(FIRST_KEY);
+        let code: String = generate_recovery_code().unwrap();
+        let (secret_key, public_key): (String, String) = generate_escrow_keys();
+
+        let (header, file_key): (Header, SecretBytes<KEY_SIZE>) =
+            Header::generate(&AccessOptions {
+                recovery_code: Some(code.clone()),
+                escrow_key: Some(public_key),
+                ..access(&[&first], None)
+            })
+            .unwrap();
+
+        let calls: usize = ct_eq_calls();
+        let key: SecretBytes<KEY_SIZE> = header
+            .unwrap_recovery_key(&RecoveryKey::Code(code))
+            .unwrap();
+        assert_eq!(*key, *file_key);
+        assert!(ct_eq_calls() > calls);
+
+        let calls: usize = ct_eq_calls();
+        let key: SecretBytes<KEY_SIZE> = header
+            .unwrap_recovery_key(&RecoveryKey::EscrowSecret(secret_key))
+            .unwrap();
+        assert_eq!(*key, *file_key);
+        assert!(ct_eq_calls() > calls);
+
+        let other_code: String = generate_recovery_code().unwrap();
+        let (other_secret_key, _): (String, String) = generate_escrow_keys();
+
+        assert!(header
+            .unwrap_recovery_key(&RecoveryKey::Code(other_code))
+            .is_err());
+        assert!(header
+            .unwrap_recovery_key(&RecoveryKey::EscrowSecret(other_secret_key))
+            .is_err());
+    }
+}
@@ -0,0 +1,427 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{compare::ct_eq, keccak256, wallet::LocalWallet};
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes128,
+};
+use pbkdf2::pbkdf2_hmac;
+use scrypt::{scrypt, Params};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fs::read_to_string;
+use zeroize::Zeroizing;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// Limits on the key derivation costs a keystore may ask for, well above what
+/// geth, Foundry and MetaMask write, so that a crafted file cannot make
+/// decryption allocate or compute without bound.
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+const MAX_SCRYPT_WORK: u64 = 1 << 24;
+const MAX_PBKDF2_ROUNDS: u64 = 1 << 24;
+
+impl LocalWallet {
+    /// Decrypts an Ethereum V3 keystore file, as written by geth, Foundry or
+    /// MetaMask exports, with either its scrypt or pbkdf2 key derivation.
+    /// The private key only lives in memory.
+    pub fn from_keystore(path: &str, passphrase: &str) -> Result<LocalWallet, String> {
+        let content: String = match read_to_string(path) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to read keystore file ({})", path)),
+        };
+
+        let keystore: Value = match serde_json::from_str(&content) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Invalid keystore file ({})", path)),
+        };
+
+        if keystore["version"].as_u64() != Some(3) {
+            return Err(format!("Unsupported keystore version ({})", path));
+        }
+
+        // Some writers capitalise the section name.
+        let crypto: &Value = match keystore.get("crypto").or(keystore.get("Crypto")) {
+            Some(f) => f,
+            None => return Err(format!("Invalid keystore file ({})", path)),
+        };
+
+        if crypto["cipher"].as_str() != Some("aes-128-ctr") {
+            return Err(format!("Unsupported keystore cipher ({})", path));
+        }
+
+        let ciphertext: Vec<u8> = hex_field(&crypto["ciphertext"], path)?;
+        let iv: Vec<u8> = hex_field(&crypto["cipherparams"]["iv"], path)?;
+        let mac: Vec<u8> = hex_field(&crypto["mac"], path)?;
+
+        let derived_key: Zeroizing<Vec<u8>> = derive_key(crypto, passphrase, path)?;
+
+        if derived_key.len() < 32 || iv.len() != 16 {
+            return Err(format!("Invalid keystore file ({})", path));
+        }
+
+        let mut mac_input: Zeroizing<Vec<u8>> = Zeroizing::new(derived_key[16..32].to_vec());
+        mac_input.extend_from_slice(&ciphertext);
+
+        if !ct_eq(&keccak256(&mac_input), &mac) {
+            return Err(format!("Wrong keystore passphrase ({})", path));
+        }
+
+        let mut private_key: Zeroizing<Vec<u8>> = Zeroizing::new(ciphertext);
+        Aes128Ctr::new(derived_key[..16].into(), iv[..].into()).apply_keystream(&mut private_key);
+
+        LocalWallet::from_private_key(&private_key)
+    }
+}
+
+fn derive_key(crypto: &Value, passphrase: &str, path: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let params: &Value = &crypto["kdfparams"];
+    let salt: Vec<u8> = hex_field(&params["salt"], path)?;
+    let length: usize = number_field(&params["dklen"], path)? as usize;
+
+    if !(32..=64).contains(&length) {
+        return Err(format!("Invalid keystore file ({})", path));
+    }
+
+    let mut derived_key: Zeroizing<Vec<u8>> = Zeroizing::new(vec![0u8; length]);
+
+    match crypto["kdf"].as_str() {
+        Some("scrypt") => {
+            let n: u64 = number_field(&params["n"], path)?;
+            let r: u64 = number_field(&params["r"], path)?;
+            let p: u64 = number_field(&params["p"], path)?;
+
+            if !n.is_power_of_two() || n < 2 || r == 0 || p == 0 {
+                return Err(format!("Invalid keystore file ({})", path));
+            }
+
+            if n.saturating_mul(r).saturating_mul(128) > MAX_SCRYPT_MEMORY
+                || n.saturating_mul(r).saturating_mul(p) > MAX_SCRYPT_WORK
+            {
+                return Err(format!(
+                    "Unsupported keystore key derivation cost, n {} r {} p {} ({})",
+                    n, r, p, path
+                ));
+            }
+
+            // The bounds above keep r and p well within u32. The scrypt crate
+            // also requires n < 2^(16 r), which geth does not, so keystores
+            // past it, like the Web3 Secret Storage test vector, are derived
+            // by `keystore_scrypt`.
+            match Params::new(n.trailing_zeros() as u8, r as u32, p as u32, length) {
+                Ok(params) => match scrypt(passphrase.as_bytes(), &salt, &params, &mut derived_key)
+                {
+                    Ok(f) => f,
+                    Err(_) => return Err(format!("Failed to derive keystore key ({})", path)),
+                },
+                Err(_) => keystore_scrypt(
+                    passphrase.as_bytes(),
+                    &salt,
+                    n as usize,
+                    r as usize,
+                    p as usize,
+                    &mut derived_key,
+                ),
+            };
+        }
+        Some("pbkdf2") => {
+            if params["prf"].as_str() != Some("hmac-sha256") {
+                return Err(format!("Unsupported keystore key derivation ({})", path));
+            }
+
+            let rounds: u64 = number_field(&params["c"], path)?;
+
+            if rounds == 0 || rounds > MAX_PBKDF2_ROUNDS {
+                return Err(format!(
+                    "Unsupported keystore key derivation cost, c {} ({})",
+                    rounds, path
+                ));
+            }
+
+            // Bounded above, so the rounds fit in u32.
+            let rounds: u32 = rounds as u32;
+
+            pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, rounds, &mut derived_key);
+        }
+        _ => return Err(format!("Unsupported keystore key derivation ({})", path)),
+    };
+
+    Ok(derived_key)
+}
+
+/// scrypt without the bound of `n` by `r`, as go-ethereum computes it.
+/// `n` is a power of two and `n * r * 128` bytes fit in memory.
+fn keystore_scrypt(
+    passphrase: &[u8],
+    salt: &[u8],
+    n: usize,
+    r: usize,
+    p: usize,
+    output: &mut [u8],
+) {
+    let mut blocks: Zeroizing<Vec<u8>> = Zeroizing::new(vec![0u8; p * 128 * r]);
+    pbkdf2_hmac::<Sha256>(passphrase, salt, 1, &mut blocks);
+
+    let mut scratch: Zeroizing<Vec<u32>> = Zeroizing::new(vec![0u32; n * 32 * r]);
+
+    for block in blocks.chunks_mut(128 * r) {
+        let mut words: Zeroizing<Vec<u32>> = Zeroizing::new(
+            block
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+        );
+
+        ro_mix(&mut words, &mut scratch, n);
+
+        for (bytes, word) in block.chunks_exact_mut(4).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    pbkdf2_hmac::<Sha256>(passphrase, &blocks, 1, output);
+}
+
+fn ro_mix(words: &mut [u32], scratch: &mut [u32], n: usize) {
+    let length: usize = words.len();
+    let mut mixed: Zeroizing<Vec<u32>> = Zeroizing::new(vec![0u32; length]);
+
+    for index in 0..n {
+        scratch[index * length..(index + 1) * length].copy_from_slice(words);
+        block_mix(words, &mut mixed);
+        words.copy_from_slice(&mixed);
+    }
+
+    for _ in 0..n {
+        let index: usize = words[length - 16] as usize & (n - 1);
+
+        for (word, previous) in words
+            .iter_mut()
+            .zip(&scratch[index * length..(index + 1) * length])
+        {
+            *word ^= previous;
+        }
+
+        block_mix(words, &mut mixed);
+        words.copy_from_slice(&mixed);
+    }
+}
+
+fn block_mix(input: &[u32], output: &mut [u32]) {
+    let r: usize = input.len() / 32;
+    let mut state: Zeroizing<[u32; 16]> = Zeroizing::new([0u32; 16]);
+    state.copy_from_slice(&input[input.len() - 16..]);
+
+    for (index, chunk) in input.chunks_exact(16).enumerate() {
+        for (word, other) in state.iter_mut().zip(chunk) {
+            *word ^= other;
+        }
+
+        salsa20_8(&mut state);
+
+        let position: usize = (index / 2 + (index % 2) * r) * 16;
+        output[position..position + 16].copy_from_slice(&*state);
+    }
+}
+
+fn salsa20_8(block: &mut [u32; 16]) {
+    fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+
+    let mut x: Zeroizing<[u32; 16]> = Zeroizing::new(*block);
+
+    for _ in 0..4 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+
+    for (word, mixed) in block.iter_mut().zip(x.iter()) {
+        *word = word.wrapping_add(*mixed);
+    }
+}
+
+fn hex_field(value: &Value, path: &str) -> Result<Vec<u8>, String> {
+    match value
+        .as_str()
+        .map(|text| hex::decode(text.trim_start_matches("0x")))
+    {
+        Some(Ok(f)) => Ok(f),
+        _ => Err(format!("Invalid keystore file ({})", path)),
+    }
+}
+
+fn number_field(value: &Value, path: &str) -> Result<u64, String> {
+    match value.as_u64() {
+        Some(f) => Ok(f),
+        None => Err(format!("Invalid keystore file ({})", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::Signer;
+    use serde_json::json;
+    use std::fs::write;
+    use tempfile::NamedTempFile;
+
+    /// Private key of the Web3 Secret Storage test vectors.
+    const PRIVATE_KEY: &str = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+
+    fn pbkdf2_vector() -> Value {
+        json!({
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+                "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+                "kdf": "pbkdf2",
+                "kdfparams": {
+                    "c": 262144,
+                    "dklen": 32,
+                    "prf": "hmac-sha256",
+                    "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd",
+                },
+                "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2",
+            },
+            "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+            "version": 3,
+        })
+    }
+
+    fn scrypt_vector() -> Value {
+        json!({
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": "83dbcc02d8ccb40e466191a123791e0e" },
+                "ciphertext": "d172bf743a674da9cdad04534d56926ef8358534d458fffccd4e6ad2fbde479c",
+                "kdf": "scrypt",
+                "kdfparams": {
+                    "dklen": 32,
+                    "n": 262144,
+                    "p": 8,
+                    "r": 1,
+                    "salt": "ab0c7876052600dd703518d6fc3fe8984592145b591fc8fb5c6d43190334ba19",
+                },
+                "mac": "2103ac29920d71da29f15d75b4a16dbe95cfd7ff8faea1056c33131d846e3097",
+            },
+            "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+            "version": 3,
+        })
+    }
+
+    fn open(keystore: &Value, passphrase: &str) -> Result<LocalWallet, String> {
+        let file: NamedTempFile = NamedTempFile::new().unwrap();
+        write(file.path(), keystore.to_string()).unwrap();
+
+        LocalWallet::from_keystore(file.path().to_str().unwrap(), passphrase)
+    }
+
+    fn expected_address() -> String {
+        LocalWallet::from_private_key(&hex::decode(PRIVATE_KEY).unwrap())
+            .unwrap()
+            .address()
+            .to_string()
+    }
+
+    #[test]
+    fn pbkdf2_test_vector_opens() {
+        let wallet: LocalWallet = open(&pbkdf2_vector(), "testpassword").unwrap();
+
+        assert_eq!(wallet.address().to_string(), expected_address());
+    }
+
+    #[test]
+    fn scrypt_test_vector_opens() {
+        let wallet: LocalWallet = open(&scrypt_vector(), "testpassword").unwrap();
+
+        assert_eq!(wallet.address().to_string(), expected_address());
+    }
+
+    #[test]
+    fn unbounded_scrypt_matches_the_reference() {
+        // RFC 7914, section 12.
+        let mut output: [u8; 64] = [0u8; 64];
+        keystore_scrypt(b"password", b"NaCl", 1024, 8, 16, &mut output);
+        assert_eq!(
+            hex::encode(output),
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+        );
+
+        let mut expected: [u8; 64] = [0u8; 64];
+        let params: Params = Params::new(4, 1, 1, 64).unwrap();
+        scrypt(b"password", b"salt", &params, &mut expected).unwrap();
+        keystore_scrypt(b"password", b"salt", 16, 1, 1, &mut output);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn wrong_passphrases_fail_the_mac() {
+        let mut keystore: Value = pbkdf2_vector();
+        keystore["crypto"]["kdfparams"]["c"] = json!(1);
+
+        let error: String = open(&keystore, "testpassword").err().unwrap();
+        assert!(error.starts_with("Wrong keystore passphrase"), "{}", error);
+
+        let error: String = open(&pbkdf2_vector(), "wrongpassword").err().unwrap();
+        assert!(error.starts_with("Wrong keystore passphrase"), "{}", error);
+    }
+
+    #[test]
+    fn costly_key_derivations_are_rejected() {
+        for (n, r, p) in [
+            (1u64 << 40, 1u64, 1u64),
+            (1 << 20, 1 << 20, 1),
+            (1 << 18, 1, 1 << 40),
+        ] {
+            let mut keystore: Value = scrypt_vector();
+            keystore["crypto"]["kdfparams"]["n"] = json!(n);
+            keystore["crypto"]["kdfparams"]["r"] = json!(r);
+            keystore["crypto"]["kdfparams"]["p"] = json!(p);
+
+            let error: String = open(&keystore, "testpassword").err().unwrap();
+            assert!(
+                error.starts_with("Unsupported keystore key derivation cost"),
+                "{}",
+                error
+            );
+        }
+
+        let mut keystore: Value = pbkdf2_vector();
+        keystore["crypto"]["kdfparams"]["c"] = json!(u64::MAX);
+
+        let error: String = open(&keystore, "testpassword").err().unwrap();
+        assert!(
+            error.starts_with("Unsupported keystore key derivation cost"),
+            "{}",
+            error
+        );
+    }
+}
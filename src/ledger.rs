@@ -0,0 +1,534 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::Address, mnemonic::parse_derivation_path, parse_address, scheme::TypedData,
+    signature::Signature, signer::Signer,
+};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+const CLA: u8 = 0xe0;
+const INS_GET_ADDRESS: u8 = 0x02;
+const INS_SIGN_PERSONAL_MESSAGE: u8 = 0x08;
+const INS_SIGN_EIP712_HASHED: u8 = 0x0c;
+const P1_FIRST_CHUNK: u8 = 0x00;
+const P1_MORE_CHUNKS: u8 = 0x80;
+
+const SW_OK: u16 = 0x9000;
+const SW_DENIED: u16 = 0x6985;
+const SW_LOCKED: u16 = 0x5515;
+const SW_APP_NOT_OPEN: [u16; 3] = [0x6d00, 0x6e00, 0x6511];
+
+const MAX_CHUNK_SIZE: usize = 255;
+
+/// Channel to a Ledger device: a USB HID connection, the TCP APDU port of the
+/// Speculos emulator, or an in-process mock.
+pub trait Transport {
+    /// Sends one APDU command and returns the response data followed by the
+    /// two-byte status word.
+    fn exchange(&self, apdu: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Ethereum app of a Ledger device, which signs on the device after the user
+/// checks the message on its screen.
+pub struct LedgerSigner {
+    transport: Box<dyn Transport>,
+    path: Vec<u32>,
+    address: Address,
+}
+
+impl LedgerSigner {
+    /// Opens the wallet at `derivation_path` in the Ethereum app.
+    pub fn new(
+        transport: Box<dyn Transport>,
+        derivation_path: &str,
+    ) -> Result<LedgerSigner, String> {
+        let path: Vec<u32> = parse_derivation_path(derivation_path)?;
+
+        let response: Vec<u8> = send(
+            transport.as_ref(),
+            INS_GET_ADDRESS,
+            P1_FIRST_CHUNK,
+            &encode_path(&path),
+        )?;
+
+        // The public key comes first, then the address as ASCII hex.
+        let address: Option<&[u8]> = response
+            .first()
+            .map(|length| 1 + *length as usize)
+            .and_then(|offset| Some((offset, *response.get(offset)? as usize)))
+            .and_then(|(offset, length)| response.get(offset + 1..offset + 1 + length));
+
+        let address: &str = match address.map(std::str::from_utf8) {
+            Some(Ok(f)) => f,
+            _ => return Err("Invalid address from the Ledger".to_string()),
+        };
+
+        Ok(LedgerSigner {
+            transport,
+            path,
+            address: parse_address(&format!("0x{}", address.trim_start_matches("0x")))?,
+        })
+    }
+}
+
+impl Signer for LedgerSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Streams the message with `SIGN_PERSONAL_MESSAGE` in chunks, the first one
+    /// starting with the path and the message length.
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        let mut data: Vec<u8> = encode_path(&self.path);
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(message);
+
+        let mut response: Vec<u8> = Vec::new();
+
+        for (index, chunk) in data.chunks(MAX_CHUNK_SIZE).enumerate() {
+            let p1: u8 = match index {
+                0 => P1_FIRST_CHUNK,
+                _ => P1_MORE_CHUNKS,
+            };
+
+            response = send(
+                self.transport.as_ref(),
+                INS_SIGN_PERSONAL_MESSAGE,
+                p1,
+                chunk,
+            )?;
+        }
+
+        decode_signature(&response)
+    }
+
+    /// Signs with the hashed EIP-712 command, which shows the two hashes.
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        let mut data: Vec<u8> = encode_path(&self.path);
+        data.extend_from_slice(&typed_data.domain_separator());
+        data.extend_from_slice(&typed_data.struct_hash());
+
+        let response: Vec<u8> = send(
+            self.transport.as_ref(),
+            INS_SIGN_EIP712_HASHED,
+            P1_FIRST_CHUNK,
+            &data,
+        )?;
+
+        decode_signature(&response)
+    }
+}
+
+/// Reorders the `v ‖ r ‖ s` answer of the app into a signature.
+fn decode_signature(response: &[u8]) -> Result<String, String> {
+    if response.len() != 65 {
+        return Err("Invalid signature from the Ledger".to_string());
+    }
+
+    let mut bytes: Vec<u8> = response[1..].to_vec();
+    bytes.push(response[0]);
+
+    match Signature::from_bytes(&bytes) {
+        Ok(f) => Ok(f.to_hex()),
+        Err(e) => Err(format!("Invalid signature from the Ledger, {}", e)),
+    }
+}
+
+/// Speculos emulator, reached on its APDU port (9999 by default). Each message
+/// is prefixed with its length as a 32-bit big-endian integer, and responses
+/// carry the status word after the data.
+pub struct SpeculosTransport {
+    stream: TcpStream,
+}
+
+impl SpeculosTransport {
+    pub fn connect(address: &str) -> Result<SpeculosTransport, String> {
+        match TcpStream::connect(address) {
+            Ok(stream) => Ok(SpeculosTransport { stream }),
+            Err(_) => Err(format!("Failed to connect to Speculos ({})", address)),
+        }
+    }
+}
+
+impl Transport for SpeculosTransport {
+    fn exchange(&self, apdu: &[u8]) -> Result<Vec<u8>, String> {
+        let mut stream: &TcpStream = &self.stream;
+
+        let mut request: Vec<u8> = (apdu.len() as u32).to_be_bytes().to_vec();
+        request.extend_from_slice(apdu);
+
+        match stream.write_all(&request) {
+            Ok(s) => s,
+            Err(_) => return Err("Failed to write to Speculos".to_string()),
+        };
+
+        let mut length: [u8; 4] = [0u8; 4];
+
+        match stream.read_exact(&mut length) {
+            Ok(s) => s,
+            Err(_) => return Err("Failed to read from Speculos".to_string()),
+        };
+
+        let mut response: Vec<u8> = vec![0u8; u32::from_be_bytes(length) as usize + 2];
+
+        match stream.read_exact(&mut response) {
+            Ok(s) => s,
+            Err(_) => return Err("Failed to read from Speculos".to_string()),
+        };
+
+        Ok(response)
+    }
+}
+
+/// Ledger device plugged in over USB.
+#[cfg(feature = "ledger-hid")]
+pub struct HidTransport {
+    device: hidapi::HidDevice,
+}
+
+#[cfg(feature = "ledger-hid")]
+const LEDGER_VENDOR_ID: u16 = 0x2c97;
+#[cfg(feature = "ledger-hid")]
+const LEDGER_USAGE_PAGE: u16 = 0xffa0;
+#[cfg(feature = "ledger-hid")]
+const HID_PACKET_SIZE: usize = 64;
+#[cfg(feature = "ledger-hid")]
+const HID_CHANNEL: [u8; 2] = [0x01, 0x01];
+#[cfg(feature = "ledger-hid")]
+const HID_TAG_APDU: u8 = 0x05;
+
+#[cfg(feature = "ledger-hid")]
+impl HidTransport {
+    /// Opens the first Ledger device found.
+    pub fn open() -> Result<HidTransport, String> {
+        let api: hidapi::HidApi = match hidapi::HidApi::new() {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Failed to list USB devices, {}", e)),
+        };
+
+        let device_info: &hidapi::DeviceInfo = match api.device_list().find(|device| {
+            device.vendor_id() == LEDGER_VENDOR_ID
+                && (device.usage_page() == LEDGER_USAGE_PAGE || device.interface_number() == 0)
+        }) {
+            Some(f) => f,
+            None => return Err("No Ledger device found".to_string()),
+        };
+
+        match device_info.open_device(&api) {
+            Ok(device) => Ok(HidTransport { device }),
+            Err(e) => Err(format!("Failed to open the Ledger, {}", e)),
+        }
+    }
+}
+
+#[cfg(feature = "ledger-hid")]
+impl Transport for HidTransport {
+    /// Frames the APDU in 64-byte reports on channel 0x0101, the first report
+    /// carrying the APDU length.
+    fn exchange(&self, apdu: &[u8]) -> Result<Vec<u8>, String> {
+        let mut payload: Vec<u8> = (apdu.len() as u16).to_be_bytes().to_vec();
+        payload.extend_from_slice(apdu);
+
+        for (sequence, chunk) in payload.chunks(HID_PACKET_SIZE - 5).enumerate() {
+            // The leading zero is the HID report id.
+            let mut report: Vec<u8> = vec![0x00, HID_CHANNEL[0], HID_CHANNEL[1], HID_TAG_APDU];
+            report.extend_from_slice(&(sequence as u16).to_be_bytes());
+            report.extend_from_slice(chunk);
+            report.resize(HID_PACKET_SIZE + 1, 0);
+
+            match self.device.write(&report) {
+                Ok(_) => {}
+                Err(e) => return Err(format!("Failed to write to the Ledger, {}", e)),
+            };
+        }
+
+        let mut response: Vec<u8> = Vec::new();
+        let mut length: Option<usize> = None;
+        let mut sequence: u16 = 0;
+
+        while length.is_none_or(|length| response.len() < length) {
+            let mut report: [u8; HID_PACKET_SIZE] = [0u8; HID_PACKET_SIZE];
+
+            match self.device.read(&mut report) {
+                Ok(HID_PACKET_SIZE) => {}
+                Ok(_) | Err(_) => return Err("Failed to read from the Ledger".to_string()),
+            };
+
+            if report[..3] != [HID_CHANNEL[0], HID_CHANNEL[1], HID_TAG_APDU]
+                || report[3..5] != sequence.to_be_bytes()
+            {
+                return Err("Invalid response from the Ledger".to_string());
+            }
+
+            match length {
+                None => {
+                    length = Some(u16::from_be_bytes([report[5], report[6]]) as usize);
+                    response.extend_from_slice(&report[7..]);
+                }
+                Some(_) => response.extend_from_slice(&report[5..]),
+            };
+
+            sequence += 1;
+        }
+
+        response.truncate(length.unwrap_or_default());
+
+        Ok(response)
+    }
+}
+
+/// Sends one command and checks its status word.
+fn send(
+    transport: &dyn Transport,
+    instruction: u8,
+    p1: u8,
+    data: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut apdu: Vec<u8> = vec![CLA, instruction, p1, 0x00, data.len() as u8];
+    apdu.extend_from_slice(data);
+
+    let mut response: Vec<u8> = transport.exchange(&apdu)?;
+
+    if response.len() < 2 {
+        return Err("Invalid response from the Ledger".to_string());
+    }
+
+    let status: Vec<u8> = response.split_off(response.len() - 2);
+
+    match u16::from_be_bytes([status[0], status[1]]) {
+        SW_OK => Ok(response),
+        SW_DENIED => Err("The signature was refused on the Ledger".to_string()),
+        SW_LOCKED => Err("Unlock the Ledger first".to_string()),
+        status if SW_APP_NOT_OPEN.contains(&status) => {
+            Err("Open the Ethereum app on the Ledger first".to_string())
+        }
+        status => Err(format!("The Ledger answered with status 0x{:04x}", status)),
+    }
+}
+
+/// Encodes a BIP-32 path as its length followed by each index in big-endian.
+fn encode_path(path: &[u32]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![path.len() as u8];
+
+    for index in path {
+        bytes.extend_from_slice(&index.to_be_bytes());
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash_message, scheme::Scheme, wallet::LocalWallet};
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    const PRIVATE_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(PRIVATE_KEY).unwrap()).unwrap()
+    }
+
+    type Apdus = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    /// Ethereum app signing with `wallet()`, recording every APDU it receives.
+    /// Its answers carry `status` instead of signatures when it is not `SW_OK`.
+    struct MockLedger {
+        apdus: Apdus,
+        status: Rc<Cell<u16>>,
+        stream: RefCell<Vec<u8>>,
+    }
+
+    impl Transport for MockLedger {
+        fn exchange(&self, apdu: &[u8]) -> Result<Vec<u8>, String> {
+            self.apdus.borrow_mut().push(apdu.to_vec());
+
+            assert_eq!(apdu[0], CLA);
+            assert_eq!(apdu[4] as usize, apdu.len() - 5);
+
+            let data: &[u8] = &apdu[5..];
+
+            let mut response: Vec<u8> = match (self.status.get(), apdu[1]) {
+                (SW_OK, INS_GET_ADDRESS) => {
+                    let address: String = hex::encode(wallet().address().as_bytes());
+
+                    let mut response: Vec<u8> = vec![65];
+                    response.extend_from_slice(&[0x04; 65]);
+                    response.push(address.len() as u8);
+                    response.extend_from_slice(address.as_bytes());
+                    response
+                }
+                (SW_OK, INS_SIGN_PERSONAL_MESSAGE) => {
+                    let mut stream = self.stream.borrow_mut();
+
+                    if apdu[2] == P1_FIRST_CHUNK {
+                        stream.clear();
+                    }
+                    stream.extend_from_slice(data);
+
+                    let path_size: usize = 1 + 4 * stream[0] as usize;
+                    let mut length: [u8; 4] = [0u8; 4];
+                    length.copy_from_slice(&stream[path_size..path_size + 4]);
+                    let message: &[u8] = &stream[path_size + 4..];
+
+                    match message.len() == u32::from_be_bytes(length) as usize {
+                        true => v_r_s(&wallet().sign_hash(&hash_message(message))),
+                        false => Vec::new(),
+                    }
+                }
+                (SW_OK, INS_SIGN_EIP712_HASHED) => {
+                    let mut bytes: Vec<u8> = vec![0x19, 0x01];
+                    bytes.extend_from_slice(&data[1 + 4 * data[0] as usize..]);
+
+                    v_r_s(&wallet().sign_hash(&crate::keccak256(&bytes)))
+                }
+                (SW_OK, instruction) => panic!("unexpected instruction 0x{:02x}", instruction),
+                (_, _) => Vec::new(),
+            };
+
+            response.extend_from_slice(&self.status.get().to_be_bytes());
+
+            Ok(response)
+        }
+    }
+
+    /// Orders a signature the way the Ethereum app answers.
+    fn v_r_s(signature: &Signature) -> Vec<u8> {
+        let bytes: [u8; 65] = signature.to_bytes();
+
+        let mut response: Vec<u8> = vec![bytes[64]];
+        response.extend_from_slice(&bytes[..64]);
+        response
+    }
+
+    fn ledger() -> (LedgerSigner, Apdus, Rc<Cell<u16>>) {
+        let apdus: Apdus = Rc::new(RefCell::new(Vec::new()));
+        let status: Rc<Cell<u16>> = Rc::new(Cell::new(SW_OK));
+
+        let transport: MockLedger = MockLedger {
+            apdus: apdus.clone(),
+            status: status.clone(),
+            stream: RefCell::new(Vec::new()),
+        };
+
+        let signer: LedgerSigner =
+            LedgerSigner::new(Box::new(transport), "m/44'/60'/0'/0/0").unwrap();
+
+        (signer, apdus, status)
+    }
+
+    #[test]
+    fn the_address_comes_from_the_app() {
+        let (signer, apdus, _) = ledger();
+
+        assert_eq!(signer.address(), wallet().address());
+        // CLA, INS, P1, P2, Lc, then the 5 indexes of m/44'/60'/0'/0/0.
+        assert_eq!(
+            hex::encode(&apdus.borrow()[0]),
+            "e002000015058000002c8000003c800000000000000000000000"
+        );
+    }
+
+    #[test]
+    fn long_messages_are_sent_in_chunks() {
+        let (signer, apdus, _) = ledger();
+        let message: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+        let signature: String = signer.sign_message(&message).unwrap();
+
+        assert_eq!(signature, wallet().sign_message(&message).unwrap());
+
+        // 21 bytes of path and 4 of length come first: 625 bytes in 3 chunks.
+        let apdus = apdus.borrow();
+        let chunks: Vec<(u8, u8, usize)> = apdus[1..]
+            .iter()
+            .map(|apdu| (apdu[1], apdu[2], apdu.len() - 5))
+            .collect();
+
+        assert_eq!(
+            chunks,
+            [
+                (INS_SIGN_PERSONAL_MESSAGE, P1_FIRST_CHUNK, 255),
+                (INS_SIGN_PERSONAL_MESSAGE, P1_MORE_CHUNKS, 255),
+                (INS_SIGN_PERSONAL_MESSAGE, P1_MORE_CHUNKS, 115),
+            ]
+        );
+        assert_eq!(&apdus[1][26..30], &600u32.to_be_bytes());
+    }
+
+    #[test]
+    fn short_messages_fit_in_one_chunk() {
+        let (signer, apdus, _) = ledger();
+
+        let signature: String = signer.sign_message(b"password").unwrap();
+
+        assert_eq!(signature, wallet().sign_message(b"password").unwrap());
+        assert_eq!(apdus.borrow().len(), 2);
+        assert_eq!(apdus.borrow()[1][2], P1_FIRST_CHUNK);
+    }
+
+    #[test]
+    fn typed_data_is_signed_from_its_hashes() {
+        let (signer, apdus, _) = ledger();
+        let typed_data: TypedData = Scheme::parse("eip712", "report")
+            .unwrap()
+            .typed_data(&wallet().address().to_lowercase(), "password")
+            .unwrap();
+
+        let signature: String = signer.sign_typed_data(&typed_data).unwrap();
+
+        assert_eq!(signature, wallet().sign_typed_data(&typed_data).unwrap());
+        assert_eq!(apdus.borrow()[1][1], INS_SIGN_EIP712_HASHED);
+        assert_eq!(apdus.borrow()[1].len(), 5 + 21 + 64);
+    }
+
+    #[test]
+    fn status_words_are_reported() {
+        let (signer, _, status) = ledger();
+
+        for (word, error) in [
+            (SW_DENIED, "The signature was refused on the Ledger"),
+            (SW_LOCKED, "Unlock the Ledger first"),
+            (0x6e00, "Open the Ethereum app on the Ledger first"),
+            (0x6a80, "The Ledger answered with status 0x6a80"),
+        ] {
+            status.set(word);
+
+            assert_eq!(signer.sign_message(b"password").unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        assert!(decode_signature(&[0u8; 64]).is_err());
+
+        let mut response: Vec<u8> = vec![5];
+        response.extend_from_slice(&[1u8; 64]);
+        assert!(decode_signature(&response)
+            .unwrap_err()
+            .starts_with("Invalid signature from the Ledger"));
+    }
+}
@@ -17,61 +17,127 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
 */
+mod address;
+#[cfg(unix)]
+mod agent;
+mod archive;
+mod batch;
+mod challenge;
+mod command;
+mod compare;
+mod credential;
+mod header;
+mod keystore;
+mod ledger;
+mod mnemonic;
+mod per_file;
+mod recipients;
+mod recovery;
+mod rekey;
+mod rpc;
+mod scheme;
+mod secret;
+mod shamir;
+mod signature;
+mod signer;
+mod wallet;
+
+pub use address::{Address, AddressError};
+#[cfg(unix)]
+pub use agent::{default_agent_socket, run_agent, AgentClient, AgentOptions};
+pub use archive::ArchiveOptions;
+pub use batch::{decrypt_batch, encrypt_batch, BatchOptions};
+pub use challenge::challenge;
+pub use command::CommandSigner;
+pub use credential::{AccessOptions, Credential, RecipientKey};
+#[cfg(feature = "ledger-hid")]
+pub use ledger::HidTransport;
+pub use ledger::{LedgerSigner, SpeculosTransport, Transport};
+pub use mnemonic::DEFAULT_DERIVATION_PATH;
+pub use per_file::{decrypt_per_file, encrypt_per_file};
+pub use recipients::{add_recipients, list_recipients, recipient_key, remove_recipients};
+pub use recovery::{generate_escrow_keys, generate_recovery_code, RecoveryKey};
+pub use rekey::rekey;
+pub use rpc::RpcSigner;
+pub use scheme::{Scheme, TypedData};
+pub use secret::{disable_core_dumps, lock_secrets_in_memory};
+pub use signature::{Signature, SignatureError};
+pub use signer::{PresignedSigner, Signer};
+pub use wallet::LocalWallet;
+
+use archive::{compress, decompress, is_incompressible};
 use chacha20poly1305::{
     aead::{stream, KeyInit},
     XChaCha20Poly1305,
 };
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use hex::decode;
+use compare::ct_eq;
+use credential::{Unlock, Wallets};
+use flate2::Compression;
+use header::{Header, KEY_SIZE, NONCE_SIZE};
 use indicatif::{MultiProgress, ProgressBar};
 use secp256k1::{
     ecdsa::{RecoverableSignature, RecoveryId},
     All, Message, PublicKey, Secp256k1,
 };
+use secret::{secret_keccak256, SecretBytes};
+use signer::sign_password;
 use std::{
-    fs::{metadata, remove_dir_all, remove_file, File},
+    fs::{metadata, remove_dir_all, remove_file, symlink_metadata, File},
     io::{Read, Write},
     path::Path,
     time::{Duration, Instant},
 };
-use tar::{Archive, Builder};
 use tiny_keccak::{Hasher, Keccak};
 
 pub fn encrypt(
     from_path: &str,
-    address: &str,
-    signature: &str,
+    signer: &dyn Signer,
     password: &str,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
 ) -> Result<(), String> {
     println!("\nEncrypt '{}'\n", from_path);
 
     let start_time: Instant = Instant::now();
 
-    let progress: MultiProgress = MultiProgress::new();
+    check_output(Path::new(&encrypted_path(from_path, options)?))?;
 
-    match verify_password_from_signature(address, password, signature) {
-        Ok(s) => s,
-        Err(e) => return Err(e),
-    };
+    let access: AccessOptions = verify_access(signer, password, access)?;
 
-    let path: &Path = Path::new(from_path);
+    let output: EncryptedOutput = encrypt_input(from_path, &access, options)?;
 
-    let to_path: &str = match path.file_stem() {
-        Some(file_stem) => match file_stem.to_str() {
-            Some(file_stem_str) => file_stem_str,
-            None => return Err(format!("The file name is not valid UTF-8 ({})", from_path)),
-        },
-        None => {
-            return Err(format!(
-                "The path does not include a valid file name ({})",
-                from_path
-            ))
-        }
-    };
+    let elapsed_time: Duration = start_time.elapsed();
+
+    println!(
+        "\n\n'{}' is encrypted to '{}' in {:?}",
+        from_path, output.path, elapsed_time
+    );
+
+    if !output.compressed {
+        println!("Compression was skipped because a sample of the input barely compressed");
+    }
+
+    Ok(())
+}
+
+pub(crate) struct EncryptedOutput {
+    pub(crate) path: String,
+    pub(crate) compressed: bool,
+}
+
+/// Archives and encrypts a single input for recipients that were already verified.
+pub(crate) fn encrypt_input(
+    from_path: &str,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
+) -> Result<EncryptedOutput, String> {
+    let progress: MultiProgress = MultiProgress::new();
+
+    let to_path: String = output_path(from_path, options)?;
 
     let compressed_archive_path: String = format!("{}_temp", to_path);
 
-    let final_path: String = format!("{}.waba", to_path);
+    let final_path: String = encrypted_path(from_path, options)?;
 
     let compress_bar: ProgressBar = progress.add(ProgressBar::new_spinner());
 
@@ -80,7 +146,12 @@ pub fn encrypt(
 
     let compress_start_time: Instant = Instant::now();
 
-    match compress(from_path, &compressed_archive_path) {
+    let compression: Compression = match is_incompressible(from_path, options)? {
+        true => Compression::none(),
+        false => Compression::default(),
+    };
+
+    match compress(from_path, &compressed_archive_path, compression, options) {
         Ok(s) => s,
         Err(e) => {
             if metadata(&compressed_archive_path).is_ok() {
@@ -100,10 +171,17 @@ pub fn encrypt(
 
     let compress_elapsed_time = compress_start_time.elapsed();
 
-    compress_bar.finish_with_message(format!(
-        "[1/2] Compression completed ({:?})",
-        compress_elapsed_time
-    ));
+    if compression == Compression::none() {
+        compress_bar.finish_with_message(format!(
+            "[1/2] Archiving completed, compression skipped for data that looked incompressible ({:?})",
+            compress_elapsed_time
+        ));
+    } else {
+        compress_bar.finish_with_message(format!(
+            "[1/2] Compression completed ({:?})",
+            compress_elapsed_time
+        ));
+    }
 
     let encrypt_bar: ProgressBar = progress.add(ProgressBar::new_spinner());
 
@@ -112,7 +190,7 @@ pub fn encrypt(
 
     let encrypt_start_time: Instant = Instant::now();
 
-    match encrypt_file(&compressed_archive_path, &final_path, signature, password) {
+    match encrypt_file(&compressed_archive_path, &final_path, access) {
         Ok(s) => s,
         Err(e) => {
             if metadata(&compressed_archive_path).is_ok() {
@@ -187,24 +265,15 @@ pub fn encrypt(
         encrypt_elapsed_time
     ));
 
-    let elapsed_time: Duration = start_time.elapsed();
-
-    println!(
-        "\n\n'{}' is encrypted to '{}' in {:?}",
-        from_path, final_path, elapsed_time
-    );
-
-    return Ok(());
+    Ok(EncryptedOutput {
+        path: final_path,
+        compressed: compression != Compression::none(),
+    })
 }
 
-fn encrypt_file(
-    from_path: &str,
-    to_path: &str,
-    signature: &str,
-    password: &str,
-) -> Result<(), String> {
-    let nonce: [u8; 19] = generate_nonce_from_password(password);
-    let key: [u8; 32] = generate_key_from_signature(signature);
+fn encrypt_file(from_path: &str, to_path: &str, access: &AccessOptions) -> Result<(), String> {
+    let (header, key): (Header, SecretBytes<KEY_SIZE>) = Header::generate(access)?;
+    let nonce: [u8; NONCE_SIZE] = header.nonce;
     let aead = XChaCha20Poly1305::new(key.as_ref().into());
 
     let mut stream_encryptor: stream::Encryptor<_, stream::StreamBE32<_>> =
@@ -216,11 +285,13 @@ fn encrypt_file(
         Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
     };
 
-    let mut dist_file: File = match File::create(&to_path) {
+    let mut dist_file: File = match File::create(to_path) {
         Ok(f) => f,
         Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
     };
 
+    header.write(&mut dist_file)?;
+
     loop {
         let read_count: usize = match source_file.read(&mut buffer) {
             Ok(f) => f,
@@ -251,73 +322,123 @@ fn encrypt_file(
         }
     }
 
-    return Ok(());
+    Ok(())
 }
 
-fn compress(from_path: &str, to_path: &str) -> Result<(), String> {
-    let archive_file: File = match File::create(&to_path) {
-        Ok(f) => f,
-        Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
-    };
+pub fn decrypt(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    cosigners: &[Credential],
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    println!("\nDecrypt '{}' \n", from_path);
 
-    let encoder: GzEncoder<File> = GzEncoder::new(archive_file, Compression::default());
+    let start_time: Instant = Instant::now();
 
-    let mut archive: Builder<GzEncoder<File>> = Builder::new(encoder);
+    check_output(Path::new(&decrypted_path(from_path, options)?))?;
 
-    let from_path_data: &Path = Path::new(from_path);
+    let wallets: Wallets =
+        Wallets::sign(signer, password, cosigners, &[signing_scheme(from_path)?]);
 
-    if from_path_data.is_file() {
-        match archive.append_path(from_path) {
-            Ok(f) => f,
-            Err(_) => {
-                return Err(format!("Failed to archive input file ({})", from_path));
-            }
-        };
-    } else if from_path_data.is_dir() {
-        match archive.append_dir_all("", from_path) {
-            Ok(f) => f,
-            Err(_) => {
-                return Err(format!("Failed to archive input folder ({})", from_path));
-            }
-        };
-    } else {
-        return Err(format!("Invalid input path ({})", from_path));
-    }
+    let to_path: String = decrypt_input(from_path, &Unlock::Wallets(&wallets), options)?;
 
-    return Ok(());
+    let elapsed_time: Duration = start_time.elapsed();
+
+    println!(
+        "\n\n'{}' is decrypted to '{}' in {:?}",
+        from_path, to_path, elapsed_time
+    );
+
+    Ok(())
 }
 
-pub fn decrypt(
+/// Decrypts a file through its recovery or escrow slot, for when the wallets
+/// it was encrypted for are lost.
+pub fn decrypt_with_recovery(
     from_path: &str,
-    address: &str,
-    signature: &str,
-    password: &str,
+    recovery: &RecoveryKey,
+    options: &ArchiveOptions,
 ) -> Result<(), String> {
-    println!("\nDecrypt '{}' \n", from_path);
+    println!("\nDecrypt '{}' with the recovery key \n", from_path);
 
     let start_time: Instant = Instant::now();
 
-    let progress: MultiProgress = MultiProgress::new();
+    check_output(Path::new(&decrypted_path(from_path, options)?))?;
 
-    match verify_password_from_signature(address, password, signature) {
-        Ok(s) => s,
-        Err(e) => return Err(e),
+    let to_path: String = decrypt_input(from_path, &Unlock::Recovery(recovery), options)?;
+
+    let elapsed_time: Duration = start_time.elapsed();
+
+    println!(
+        "\n\n'{}' is decrypted to '{}' in {:?}",
+        from_path, to_path, elapsed_time
+    );
+
+    Ok(())
+}
+
+/// Returns the message scheme the wallets of an encrypted file sign, read from
+/// its header. Files without a header were always signed with `personal_sign`.
+pub fn signing_scheme(from_path: &str) -> Result<Scheme, String> {
+    let mut source_file: File = match File::open(from_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
     };
 
-    let path: &Path = Path::new(from_path);
+    match Header::read(&mut source_file)? {
+        Some(header) => Ok(header.scheme),
+        None => Ok(Scheme::PersonalSign),
+    }
+}
 
-    let to_path: &str = match path.file_stem() {
-        Some(file_stem) => match file_stem.to_str() {
-            Some(file_stem_str) => file_stem_str,
-            None => return Err(format!("The file name is not valid UTF-8 ({})", from_path)),
+/// Returns the file stem of `from_path` in the output directory, or in the
+/// working directory, which names the outputs of `encrypt_input` and
+/// `decrypt_input`.
+fn output_path(from_path: &str, options: &ArchiveOptions) -> Result<String, String> {
+    match Path::new(from_path).file_stem() {
+        Some(file_stem) => match (file_stem.to_str(), &options.output_directory) {
+            (Some(file_stem_str), Some(directory)) => {
+                Ok(directory.join(file_stem_str).to_string_lossy().to_string())
+            }
+            (Some(file_stem_str), None) => Ok(file_stem_str.to_string()),
+            (None, _) => Err(format!("The file name is not valid UTF-8 ({})", from_path)),
         },
-        None => {
-            return Err(format!(
-                "The path does not include a valid file name ({})",
-                from_path
-            ))
-        }
-    };
+        None => Err(format!(
+            "The path does not include a valid file name ({})",
+            from_path
+        )),
+    }
+}
+
+/// Returns the path `encrypt_input` writes `from_path` to.
+pub(crate) fn encrypted_path(from_path: &str, options: &ArchiveOptions) -> Result<String, String> {
+    Ok(format!("{}.waba", output_path(from_path, options)?))
+}
+
+/// Returns the path `decrypt_input` writes `from_path` to.
+pub(crate) fn decrypted_path(from_path: &str, options: &ArchiveOptions) -> Result<String, String> {
+    output_path(from_path, options)
+}
+
+/// Fails when `path` already exists. Inputs are deleted once processed, so an
+/// output written over an existing file would lose data for good.
+pub(crate) fn check_output(path: &Path) -> Result<(), String> {
+    match symlink_metadata(path) {
+        Ok(_) => Err(format!("The output already exists ({})", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Decrypts and unpacks a single `.waba` file with credentials that were already verified.
+pub(crate) fn decrypt_input(
+    from_path: &str,
+    unlock: &Unlock,
+    options: &ArchiveOptions,
+) -> Result<String, String> {
+    let progress: MultiProgress = MultiProgress::new();
+
+    let to_path: String = output_path(from_path, options)?;
 
     let compressed_archive_path: String = format!("{}_temp", to_path);
 
@@ -328,7 +449,7 @@ pub fn decrypt(
 
     let decrypt_start_time: Instant = Instant::now();
 
-    match decrypt_file(from_path, &compressed_archive_path, signature, password) {
+    match decrypt_file(from_path, &compressed_archive_path, unlock) {
         Ok(s) => s,
         Err(e) => {
             if metadata(&compressed_archive_path).is_ok() {
@@ -360,7 +481,7 @@ pub fn decrypt(
 
     let decompress_start_time: Instant = Instant::now();
 
-    match decompress(&compressed_archive_path, to_path) {
+    match decompress(&compressed_archive_path, &to_path, options) {
         Ok(s) => s,
         Err(e) => {
             if metadata(&compressed_archive_path).is_ok() {
@@ -418,53 +539,49 @@ pub fn decrypt(
         decompress_elapsed_time
     ));
 
-    let elapsed_time: Duration = start_time.elapsed();
-
-    println!(
-        "\n\n'{}' is decrypted to '{}' in {:?}",
-        from_path, to_path, elapsed_time
-    );
-
-    return Ok(());
+    Ok(to_path.to_string())
 }
 
-fn decompress(from_path: &str, to_path: &str) -> Result<(), String> {
-    let archive_file: File = match File::open(&from_path) {
-        Ok(f) => f,
-        Err(_) => return Err(format!("Failed to open input ({})", from_path)),
-    };
-
-    let decoder: GzDecoder<File> = GzDecoder::new(archive_file);
-
-    let mut archive: Archive<GzDecoder<File>> = Archive::new(decoder);
-
-    match archive.unpack(to_path) {
+fn decrypt_file(from_path: &str, to_path: &str, unlock: &Unlock) -> Result<(), String> {
+    let mut source_file: File = match File::open(from_path) {
         Ok(f) => f,
-        Err(_) => return Err(format!("Failed to archive input ({})", from_path)),
+        Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
     };
 
-    Ok(())
-}
+    // Files written before headers existed derive the key from the signature
+    // and the nonce from the password directly.
+    let (key, nonce): (SecretBytes<KEY_SIZE>, SecretBytes<NONCE_SIZE>) =
+        match (Header::read(&mut source_file)?, unlock) {
+            (Some(header), Unlock::Wallets(wallets)) => (
+                header.unwrap_key(&wallets.credentials(&header.scheme)?)?,
+                SecretBytes::copy_from(&header.nonce),
+            ),
+            (Some(header), Unlock::Recovery(recovery)) => (
+                header.unwrap_recovery_key(recovery)?,
+                SecretBytes::copy_from(&header.nonce),
+            ),
+            (None, Unlock::Wallets(wallets)) => {
+                let credentials: Vec<Credential> = wallets.credentials(&Scheme::PersonalSign)?;
+
+                (
+                    generate_key_from_signature(&credentials[0].signature),
+                    generate_nonce_from_password(&credentials[0].password),
+                )
+            }
+            (None, Unlock::Recovery(_)) => {
+                return Err(format!(
+                    "The file has no key slots and cannot be recovered ({})",
+                    from_path
+                ))
+            }
+        };
 
-fn decrypt_file(
-    from_path: &str,
-    to_path: &str,
-    signature: &str,
-    password: &str,
-) -> Result<(), String> {
-    let nonce: [u8; 19] = generate_nonce_from_password(password);
-    let key: [u8; 32] = generate_key_from_signature(signature);
     let aead = XChaCha20Poly1305::new(key.as_ref().into());
 
     let mut stream_decryptor: stream::Decryptor<_, stream::StreamBE32<_>> =
         stream::DecryptorBE32::from_aead(aead, nonce.as_ref().into());
     let mut buffer: [u8; 4112] = [0u8; 4112];
 
-    let mut source_file: File = match File::open(from_path) {
-        Ok(f) => f,
-        Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
-    };
-
     let mut dist_file: File = match File::create(to_path) {
         Ok(f) => f,
         Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
@@ -505,46 +622,75 @@ fn decrypt_file(
     Ok(())
 }
 
-fn verify_password_from_signature(
+/// Has `signer` sign for the encrypting wallet, checks the format of the
+/// recipient and recovery keys, and returns the access options with the key of
+/// the encrypting wallet listed first.
+pub(crate) fn verify_access(
+    signer: &dyn Signer,
+    password: &str,
+    access: &AccessOptions,
+) -> Result<AccessOptions, String> {
+    if let Some(code) = &access.recovery_code {
+        recovery::recovery_code_key(code)?;
+    }
+
+    if let Some(public_key) = &access.escrow_key {
+        recovery::escrow_wrap_key(public_key)?;
+    }
+
+    let own: Credential = sign_password(signer, password, &access.scheme)?;
+    let mut recipients: Vec<RecipientKey> = vec![RecipientKey::derive(&own, &access.scheme)?];
+
+    for recipient in &access.recipients {
+        recipient.check_scheme(&access.scheme)?;
+
+        recipients.push(RecipientKey::new(
+            &recipient.address,
+            &recipient.public_key,
+            recipient.scheme.clone(),
+        )?);
+    }
+
+    Ok(AccessOptions {
+        recipients,
+        ..access.clone()
+    })
+}
+
+pub(crate) fn verify_password_from_signature(
     address: &str,
     password: &str,
     signature: &str,
+    scheme: &Scheme,
 ) -> Result<(), String> {
-    let address: String = address.to_string().to_lowercase();
-    let password_hash: [u8; 32] = hash_message(password.to_string());
+    let address: Address = parse_address(address)?;
+    let password_hash: [u8; 32] = scheme.message_hash(&address.to_lowercase(), password);
 
-    let signature_vec: Vec<u8> = match decode(&signature[2..]) {
-        Ok(s) => s,
-        Err(_) => return Err(format!("Invalid signature format ({})", signature)),
-    };
+    let parsed_signature: Signature = parse_signature(signature)?;
 
-    let recovery_id: i32 = signature_vec[64] as i32 - 27;
+    let recovery_id: i32 = parsed_signature.recovery_id() as i32;
 
-    let pubkey: String = match recover(&password_hash, &signature_vec[..64], recovery_id) {
+    let pubkey: Address = match recover(&password_hash, parsed_signature.compact(), recovery_id) {
         Ok(s) => s,
-        Err(_) => {
-            return Err(format!(
-                "Invalid public key format ({}{}{:?})",
-                password, signature, recovery_id
-            ))
-        }
+        Err(e) => return Err(format!("{} for wallet ({})", e, address)),
     };
 
-    if !(address == pubkey) {
-        return Err(format!(
-            "Invalid signature ({}) of password ({}) for wallet ({})",
-            signature, password, address
-        ));
+    // The password and the signature are secrets, errors only name the wallet.
+    if !ct_eq(address.as_bytes(), pubkey.as_bytes()) {
+        Err(format!(
+            "Invalid signature of the password for wallet ({})",
+            address
+        ))
     } else {
-        return Ok(());
+        Ok(())
     }
 }
 
-fn recover(message: &[u8], signature: &[u8], recovery_id: i32) -> Result<String, String> {
+fn recover(message: &[u8], signature: &[u8], recovery_id: i32) -> Result<Address, String> {
     let secp256k1: Secp256k1<All> = Secp256k1::new();
     let message: Message = match Message::from_digest_slice(message) {
         Ok(s) => s,
-        Err(_) => return Err(format!("Invalid message format ({:?})", message)),
+        Err(_) => return Err("Invalid message format".to_string()),
     };
 
     let recovery_id: RecoveryId = match RecoveryId::from_i32(recovery_id) {
@@ -555,57 +701,52 @@ fn recover(message: &[u8], signature: &[u8], recovery_id: i32) -> Result<String,
     let signature: RecoverableSignature =
         match RecoverableSignature::from_compact(signature, recovery_id) {
             Ok(s) => s,
-            Err(_) => return Err(format!("Invalid signature format ({:?})", signature)),
+            Err(_) => return Err("Invalid signature format".to_string()),
         };
 
     let public_key: PublicKey = match secp256k1.recover_ecdsa(&message, &signature) {
         Ok(s) => s,
-        Err(_) => {
-            return Err(format!(
-                "Failed to retrieve ecdsa public key ({:?})",
-                signature
-            ))
-        }
+        Err(_) => return Err("Failed to retrieve ecdsa public key".to_string()),
     };
 
     let public_key: [u8; 65] = public_key.serialize_uncompressed();
 
     let hash: [u8; 32] = keccak256(&public_key[1..]);
 
-    let address_part: &[u8] = &hash[12..32];
+    let mut address: [u8; 20] = [0u8; 20];
+    address.copy_from_slice(&hash[12..32]);
+
+    Ok(Address::from_bytes(address))
+}
 
-    let mut address: String = String::from("0x");
-    address.push_str(&hex::encode(address_part));
+fn parse_signature(signature: &str) -> Result<Signature, String> {
+    match Signature::parse(signature) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Invalid signature format, {}", e)),
+    }
+}
 
-    return Ok(address);
+fn parse_address(address: &str) -> Result<Address, String> {
+    match Address::parse(address) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Invalid address, {} ({})", e, address)),
+    }
 }
 
-fn generate_key_from_signature(key_str: &str) -> [u8; 32] {
-    let mut key: [u8; 32] = [0u8; 32];
-    let mut hasher = Keccak::v256();
-    hasher.update(key_str.as_bytes());
-    hasher.finalize(&mut key);
-    key
+fn generate_key_from_signature(key_str: &str) -> SecretBytes<32> {
+    secret_keccak256(&[key_str.as_bytes()])
 }
 
-fn generate_nonce_from_password(password: &str) -> [u8; 19] {
-    let mut nonce: [u8; 19] = [0u8; 19];
-    let mut hasher = Keccak::v256();
-    hasher.update(password.as_bytes());
-    hasher.finalize(&mut nonce);
-    nonce
+fn generate_nonce_from_password(password: &str) -> SecretBytes<19> {
+    secret_keccak256(&[password.as_bytes()])
 }
 
-fn hash_message(message: String) -> [u8; 32] {
-    keccak256(
-        format!(
-            "{}{}{}",
-            "\x19Ethereum Signed Message:\n",
-            message.len(),
-            message
-        )
-        .as_bytes(),
-    )
+fn hash_message(message: &[u8]) -> [u8; 32] {
+    let mut bytes: Vec<u8> =
+        format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    bytes.extend_from_slice(message);
+
+    keccak256(&bytes)
 }
 
 fn keccak256(bytes: &[u8]) -> [u8; 32] {
@@ -20,48 +20,239 @@ Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
 mod cli;
 use clap::ArgMatches;
 use cli::parse_args;
-use std::process;
+#[cfg(unix)]
+use std::time::Duration;
+use std::{env, path::Path, process};
+use zeroize::Zeroizing;
 
-use wabasen::{decrypt, encrypt};
+use wabasen::{
+    add_recipients, challenge, decrypt, decrypt_batch, decrypt_per_file, decrypt_with_recovery,
+    disable_core_dumps, encrypt, encrypt_batch, encrypt_per_file, generate_escrow_keys,
+    generate_recovery_code, list_recipients, lock_secrets_in_memory, recipient_key, rekey,
+    remove_recipients, AccessOptions, Address, ArchiveOptions, BatchOptions, CommandSigner,
+    Credential, LedgerSigner, LocalWallet, PresignedSigner, RecipientKey, RecoveryKey, RpcSigner,
+    Scheme, Signer, SpeculosTransport, Transport,
+};
+#[cfg(unix)]
+use wabasen::{default_agent_socket, run_agent, AgentClient, AgentOptions};
+
+fn main() {
+    // Keys and passwords stay out of core dumps whatever the command does.
+    if let Err(e) = disable_core_dumps() {
+        eprintln!("Warning: {}", e);
+    }
 
-fn main() -> () {
     let matches: ArgMatches = parse_args();
 
+    if matches.get_flag("lock-memory") {
+        lock_secrets_in_memory();
+    }
+
     match matches.subcommand() {
         Some(("encrypt", args)) => {
-            let input: &str = match args.get_one::<String>("input") {
-                Some(input) => input,
+            let inputs: Vec<String> = match args.get_many::<String>("input") {
+                Some(inputs) => inputs.cloned().collect(),
                 None => {
-                    eprintln!("Error: {}", "input option is required");
+                    eprintln!("Error: input option is required");
                     process::exit(1);
                 }
             };
 
-            let signature: &str = match args.get_one::<String>("signature") {
-                Some(signature) => signature,
-                None => {
-                    eprintln!("Error: {}", "signature option is required");
+            let access: AccessOptions = AccessOptions {
+                recipients: parse_recipients(args),
+                threshold: args.get_one::<usize>("threshold").copied(),
+                recovery_code: match args.get_flag("recovery-code") {
+                    true => {
+                        Some(read_secret("WABASEN_RECOVERY_CODE", "Recovery code: ").to_string())
+                    }
+                    false => None,
+                },
+                escrow_key: args.get_one::<String>("escrow-key").cloned(),
+                scheme: parse_scheme(args),
+            };
+
+            let reproducible: Option<u64> = match args.get_flag("reproducible") {
+                true => match env::var("SOURCE_DATE_EPOCH") {
+                    Ok(epoch) => match epoch.parse::<u64>() {
+                        Ok(epoch) => Some(epoch),
+                        Err(_) => {
+                            eprintln!("Error: SOURCE_DATE_EPOCH is not a valid timestamp");
+                            process::exit(1);
+                        }
+                    },
+                    Err(_) => Some(0),
+                },
+                false => None,
+            };
+
+            let options: ArchiveOptions = ArchiveOptions {
+                xattrs: args.get_flag("xattrs"),
+                acls: args.get_flag("acls"),
+                follow_symlinks: !args.get_flag("no-follow-symlinks"),
+                reproducible,
+                ..ArchiveOptions::default()
+            };
+
+            let batch: BatchOptions = BatchOptions {
+                per_file: args.get_flag("per-file"),
+                keep_going: args.get_flag("keep-going"),
+            };
+
+            #[cfg(unix)]
+            if let Some(socket) = agent_socket(args) {
+                if let Err(e) =
+                    agent_client(args, socket).encrypt(&inputs, &access, &options, &batch)
+                {
+                    eprintln!("Error: {}", e);
                     process::exit(1);
                 }
+
+                return;
+            }
+
+            let signer: Box<dyn Signer> = open_signer(args);
+            let password: &str = required_arg(args, "password");
+
+            let result: Result<(), String> = match single_input(&inputs) {
+                Some(input) if batch.per_file => {
+                    encrypt_per_file(input, signer.as_ref(), password, &access, &options)
+                }
+                Some(input) => encrypt(input, signer.as_ref(), password, &access, &options),
+                None => encrypt_batch(
+                    &inputs,
+                    signer.as_ref(),
+                    password,
+                    &access,
+                    &options,
+                    &batch,
+                ),
             };
 
-            let password: &str = match args.get_one::<String>("password") {
-                Some(password) => password,
+            match result {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            };
+        }
+        Some(("decrypt", args)) => {
+            let inputs: Vec<String> = match args.get_many::<String>("input") {
+                Some(inputs) => inputs.cloned().collect(),
                 None => {
-                    eprintln!("Error: {}", "password option is required");
+                    eprintln!("Error: input option is required");
                     process::exit(1);
                 }
             };
 
-            let address: &str = match args.get_one::<String>("address") {
-                Some(address) => address,
-                None => {
-                    eprintln!("Error: {}", "address option is required");
+            let recovery: Option<RecoveryKey> = match (
+                args.get_flag("recovery-code"),
+                args.get_flag("escrow-secret"),
+            ) {
+                (true, _) => Some(RecoveryKey::Code(
+                    read_secret("WABASEN_RECOVERY_CODE", "Recovery code: ").to_string(),
+                )),
+                (false, true) => Some(RecoveryKey::EscrowSecret(
+                    read_secret("WABASEN_ESCROW_SECRET", "Escrow secret key: ").to_string(),
+                )),
+                (false, false) => None,
+            };
+
+            if let Some(recovery) = recovery {
+                let options: ArchiveOptions = ArchiveOptions {
+                    preserve_owner: args.get_flag("preserve-owner"),
+                    xattrs: args.get_flag("xattrs"),
+                    acls: args.get_flag("acls"),
+                    ..ArchiveOptions::default()
+                };
+
+                let result: Result<(), String> = match single_input(&inputs) {
+                    Some(input) if !args.get_flag("per-file") => {
+                        decrypt_with_recovery(input, &recovery, &options)
+                    }
+                    _ => Err("A recovery key decrypts one file at a time".to_string()),
+                };
+
+                if let Err(e) = result {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+
+                return;
+            }
+
+            let addresses: Vec<String> = args
+                .get_many::<String>("address")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            let signatures: Vec<String> = args
+                .get_many::<String>("signature")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            let passwords: Vec<String> = args
+                .get_many::<String>("password")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+
+            // The first address belongs to the signer, and so does the first
+            // signature unless a signer option or the agent stands in for it.
+            let skipped: usize = match args.contains_id("signer") || agent_socket(args).is_some() {
+                true => 0,
+                false => 1,
+            };
+
+            let cosigners: Vec<Credential> = parse_cosigners(
+                addresses.get(1..).unwrap_or_default(),
+                signatures.get(skipped..).unwrap_or_default(),
+                &passwords,
+            );
+
+            let options: ArchiveOptions = ArchiveOptions {
+                preserve_owner: args.get_flag("preserve-owner"),
+                xattrs: args.get_flag("xattrs"),
+                acls: args.get_flag("acls"),
+                ..ArchiveOptions::default()
+            };
+
+            let batch: BatchOptions = BatchOptions {
+                per_file: args.get_flag("per-file"),
+                keep_going: args.get_flag("keep-going"),
+            };
+
+            #[cfg(unix)]
+            if let Some(socket) = agent_socket(args) {
+                if let Err(e) =
+                    agent_client(args, socket).decrypt(&inputs, &cosigners, &options, &batch)
+                {
+                    eprintln!("Error: {}", e);
                     process::exit(1);
                 }
+
+                return;
+            }
+
+            let signer: Box<dyn Signer> = open_signer(args);
+            let password: &str = required_arg(args, "password");
+
+            let result: Result<(), String> = match single_input(&inputs) {
+                Some(input) if batch.per_file => {
+                    decrypt_per_file(input, signer.as_ref(), password, &cosigners, &options)
+                }
+                Some(input) => decrypt(input, signer.as_ref(), password, &cosigners, &options),
+                None => decrypt_batch(
+                    &inputs,
+                    signer.as_ref(),
+                    password,
+                    &cosigners,
+                    &options,
+                    &batch,
+                ),
             };
 
-            match encrypt(input, address, signature, password) {
+            match result {
                 Ok(s) => s,
                 Err(e) => {
                     eprintln!("Error: {}", e);
@@ -69,40 +260,158 @@ fn main() -> () {
                 }
             };
         }
-        Some(("decrypt", args)) => {
-            let input: &str = match args.get_one::<String>("input") {
-                Some(input) => input,
-                None => {
-                    eprintln!("Error: {}", "input option is required");
-                    process::exit(1);
+        Some(("recipients", args)) => {
+            let result: Result<(), String> = match args.subcommand() {
+                Some(("list", args)) => {
+                    list_recipients(required_arg(args, "input")).map(|recipients| {
+                        for recipient in recipients {
+                            println!("{}", recipient);
+                        }
+                    })
                 }
+                Some(("key", args)) => recipient_key(
+                    presigned_signer(args).as_ref(),
+                    required_arg(args, "password"),
+                    &parse_scheme(args),
+                )
+                .map(|key| println!("{}", key)),
+                Some(("add", args)) => add_recipients(
+                    required_arg(args, "input"),
+                    presigned_signer(args).as_ref(),
+                    required_arg(args, "password"),
+                    &parse_recipients(args),
+                ),
+                Some(("remove", args)) => {
+                    let addresses: Vec<String> = match args.get_many::<String>("recipient") {
+                        Some(addresses) => addresses.cloned().collect(),
+                        None => {
+                            eprintln!("Error: recipient option is required");
+                            process::exit(1);
+                        }
+                    };
+
+                    remove_recipients(
+                        required_arg(args, "input"),
+                        presigned_signer(args).as_ref(),
+                        required_arg(args, "password"),
+                        &addresses,
+                    )
+                }
+                _ => Err("no specific subcommand".to_string()),
             };
 
-            let signature: &str = match args.get_one::<String>("signature") {
-                Some(signature) => signature,
-                None => {
-                    eprintln!("Error: {}", "signature option is required");
+            match result {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
                     process::exit(1);
                 }
             };
-
-            let password: &str = match args.get_one::<String>("password") {
-                Some(password) => password,
-                None => {
-                    eprintln!("Error: {}", "password option is required");
+        }
+        Some(("challenge", args)) => {
+            match challenge(
+                args.get_one::<String>("address")
+                    .map(|address| address.as_str()),
+                required_arg(args, "password"),
+                &parse_scheme(args),
+                args.get_flag("json"),
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
                     process::exit(1);
                 }
             };
+        }
+        Some(("recovery", args)) => match args.subcommand() {
+            Some(("code", _)) => match generate_recovery_code() {
+                Ok(code) => {
+                    println!("{}", code);
+                    eprintln!(
+                        "\nWrite this recovery code down and keep it offline, it is not stored anywhere.\n\
+                         Pass it to `encrypt --recovery-code` to let it decrypt files."
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            },
+            Some(("escrow", _)) => {
+                let (secret_key, public_key): (String, String) = generate_escrow_keys();
 
-            let address: &str = match args.get_one::<String>("address") {
-                Some(address) => address,
-                None => {
-                    eprintln!("Error: {}", "address option is required");
+                println!("Secret key: {}", secret_key);
+                println!("Public key: {}", public_key);
+                eprintln!(
+                    "\nKeep the secret key offline, it is not stored anywhere.\n\
+                     Pass the public key to `encrypt --escrow-key` to let it decrypt files."
+                );
+            }
+            _ => {
+                eprintln!("Error: no specific subcommand");
+                process::exit(1);
+            }
+        },
+        Some(("rekey", args)) => {
+            let new: Credential = Credential::new(
+                required_arg(args, "new-address"),
+                required_arg(args, "new-password"),
+                required_arg(args, "new-signature"),
+            );
+
+            match rekey(
+                required_arg(args, "input"),
+                open_signer(args).as_ref(),
+                required_arg(args, "password"),
+                &new,
+                parse_new_scheme(args).as_ref(),
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
                     process::exit(1);
                 }
             };
+        }
+        Some(("agent", args)) => {
+            #[cfg(unix)]
+            let result: Result<(), String> = match args.subcommand() {
+                Some(("start", args)) => {
+                    let options: AgentOptions = AgentOptions {
+                        ttl: args
+                            .get_one::<u64>("ttl")
+                            .map(|ttl| Duration::from_secs(*ttl)),
+                        idle_timeout: args
+                            .get_one::<u64>("idle-timeout")
+                            .map(|timeout| Duration::from_secs(*timeout)),
+                    };
+
+                    run_agent(
+                        &socket_path(args),
+                        open_signer(args),
+                        required_arg(args, "password"),
+                        &options,
+                    )
+                }
+                Some(("lock", args)) => AgentClient::new(&socket_path(args), None, None)
+                    .lock()
+                    .map(|_| println!("The agent is locked")),
+                Some(("unlock", args)) => AgentClient::new(&socket_path(args), None, None)
+                    .unlock(required_arg(args, "password"))
+                    .map(|_| println!("The agent is unlocked")),
+                Some(("forget", args)) => AgentClient::new(&socket_path(args), None, None)
+                    .forget()
+                    .map(|_| println!("The agent forgot the wallet")),
+                _ => Err("no specific subcommand".to_string()),
+            };
 
-            match decrypt(input, address, signature, password) {
+            #[cfg(not(unix))]
+            let result: Result<(), String> = {
+                let _ = args;
+                Err("The agent needs Unix domain sockets".to_string())
+            };
+
+            match result {
                 Ok(s) => s,
                 Err(e) => {
                     eprintln!("Error: {}", e);
@@ -111,8 +420,272 @@ fn main() -> () {
             };
         }
         _ => {
-            eprintln!("Error: {}", "no specific subcommand");
+            eprintln!("Error: no specific subcommand");
             process::exit(1);
         }
     }
 }
+
+/// Returns the socket of the agent when it has to sign for the first wallet,
+/// which is when neither a signer nor the signature of that wallet is given.
+fn agent_socket(args: &ArgMatches) -> Option<&str> {
+    let count = |name: &str| {
+        args.get_many::<String>(name)
+            .map_or(0, |values| values.len())
+    };
+
+    match args.get_one::<String>("agent") {
+        Some(socket)
+            if !args.contains_id("signer") && count("signature") < count("address").max(1) =>
+        {
+            Some(socket)
+        }
+        _ => None,
+    }
+}
+
+/// Connects to the agent for the first wallet, which must hold the password
+/// and the address given on the command line.
+#[cfg(unix)]
+fn agent_client(args: &ArgMatches, socket: &str) -> AgentClient {
+    AgentClient::new(
+        socket,
+        args.get_one::<String>("address").map(String::as_str),
+        args.get_one::<String>("password").map(String::as_str),
+    )
+}
+
+#[cfg(unix)]
+fn socket_path(args: &ArgMatches) -> String {
+    match args.get_one::<String>("socket") {
+        Some(socket) => socket.clone(),
+        None => default_agent_socket(),
+    }
+}
+
+/// Returns the input when exactly one existing path is given, which keeps the
+/// detailed single-input output; anything else goes through batch mode.
+fn single_input(inputs: &[String]) -> Option<&str> {
+    match inputs {
+        [input] if Path::new(input).exists() => Some(input),
+        _ => None,
+    }
+}
+
+fn required_arg<'a>(args: &'a ArgMatches, name: &str) -> &'a str {
+    match args.get_one::<String>(name) {
+        Some(value) => value,
+        None => {
+            eprintln!("Error: {} option is required", name);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_recipients(args: &ArgMatches) -> Vec<RecipientKey> {
+    let mut recipients: Vec<RecipientKey> = Vec::new();
+
+    for recipient in args.get_many::<String>("recipient").unwrap_or_default() {
+        match RecipientKey::parse(recipient) {
+            Ok(recipient) => recipients.push(recipient),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        };
+    }
+
+    recipients
+}
+
+/// Pairs the cosigner addresses and signatures, used to decrypt files that need
+/// several wallets. `passwords` starts with the one of the first wallet, and a
+/// single password applies to every wallet. It is empty when an agent holds
+/// the password of the only wallet.
+fn parse_cosigners(
+    addresses: &[String],
+    signatures: &[String],
+    passwords: &[String],
+) -> Vec<Credential> {
+    let counted: bool = match passwords.len() {
+        0 => addresses.is_empty(),
+        1 => true,
+        count => count == addresses.len() + 1,
+    };
+
+    if signatures.len() != addresses.len() || !counted {
+        eprintln!("Error: each address needs one signature and one password");
+        process::exit(1);
+    }
+
+    addresses
+        .iter()
+        .zip(signatures)
+        .enumerate()
+        .map(|(index, (address, signature))| {
+            Credential::new(
+                address,
+                &passwords[(index + 1).min(passwords.len() - 1)],
+                signature,
+            )
+        })
+        .collect()
+}
+
+/// Opens the signer of the first wallet: the one given in place of
+/// `--signature`, checked against `--address`, or the signature itself.
+fn open_signer(args: &ArgMatches) -> Box<dyn Signer> {
+    let result: Result<Box<dyn Signer>, String> =
+        if let Some(command) = args.get_one::<String>("signer-command") {
+            CommandSigner::new(command, required_arg(args, "address"))
+                .map(|signer| Box::new(signer) as Box<dyn Signer>)
+        } else if let Some(url) = args.get_one::<String>("rpc-url") {
+            RpcSigner::new(url, required_arg(args, "address"))
+                .map(|signer| Box::new(signer) as Box<dyn Signer>)
+        } else if let Some(transport) = open_ledger_transport(args) {
+            transport
+                .and_then(|transport| {
+                    LedgerSigner::new(transport, required_arg(args, "derivation-path"))
+                })
+                .map(|signer| Box::new(signer) as Box<dyn Signer>)
+        } else if let Some(wallet) = open_wallet(args) {
+            wallet.map(|signer| Box::new(signer) as Box<dyn Signer>)
+        } else {
+            return presigned_signer(args);
+        };
+
+    let signer: Box<dyn Signer> = match result {
+        Ok(signer) => signer,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Some(address) = args.get_one::<String>("address") {
+        match Address::parse(address) {
+            Ok(address) if address == signer.address() => {}
+            Ok(_) => {
+                eprintln!(
+                    "Error: The signer is wallet '{}', not '{}'",
+                    signer.address(),
+                    address
+                );
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error: Invalid address, {} ({})", e, address);
+                process::exit(1);
+            }
+        };
+    }
+
+    signer
+}
+
+/// Wraps the `--address` and `--signature` values given on the command line.
+fn presigned_signer(args: &ArgMatches) -> Box<dyn Signer> {
+    match PresignedSigner::new(
+        required_arg(args, "address"),
+        required_arg(args, "signature"),
+    ) {
+        Ok(signer) => Box::new(signer),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Connects to the `--speculos` emulator or the `--ledger` device, if asked to.
+fn open_ledger_transport(args: &ArgMatches) -> Option<Result<Box<dyn Transport>, String>> {
+    if let Some(address) = args.get_one::<String>("speculos") {
+        return Some(
+            SpeculosTransport::connect(address)
+                .map(|transport| Box::new(transport) as Box<dyn Transport>),
+        );
+    }
+
+    if !args.get_flag("ledger") {
+        return None;
+    }
+
+    #[cfg(feature = "ledger-hid")]
+    return Some(
+        wabasen::HidTransport::open().map(|transport| Box::new(transport) as Box<dyn Transport>),
+    );
+
+    #[cfg(not(feature = "ledger-hid"))]
+    Some(Err(
+        "This build has no USB Ledger support, rebuild it with the ledger-hid feature".to_string(),
+    ))
+}
+
+/// Opens the `--keystore` or `--mnemonic-file` wallet, if any. Passphrases are
+/// asked for unless `WABASEN_KEYSTORE_PASSPHRASE` or `WABASEN_MNEMONIC_PASSPHRASE`
+/// is set.
+fn open_wallet(args: &ArgMatches) -> Option<Result<LocalWallet, String>> {
+    let result: Result<LocalWallet, String> = match (
+        args.get_one::<String>("keystore"),
+        args.get_one::<String>("mnemonic-file"),
+    ) {
+        (Some(path), _) => LocalWallet::from_keystore(
+            path,
+            &read_secret("WABASEN_KEYSTORE_PASSPHRASE", "Keystore passphrase: "),
+        ),
+        (None, Some(path)) => {
+            let passphrase: Zeroizing<String> = match args.get_flag("mnemonic-passphrase") {
+                true => read_secret("WABASEN_MNEMONIC_PASSPHRASE", "BIP-39 passphrase: "),
+                false => {
+                    Zeroizing::new(env::var("WABASEN_MNEMONIC_PASSPHRASE").unwrap_or_default())
+                }
+            };
+
+            LocalWallet::from_mnemonic_file(
+                path,
+                &passphrase,
+                required_arg(args, "derivation-path"),
+            )
+        }
+        (None, None) => return None,
+    };
+
+    Some(result)
+}
+
+fn parse_scheme(args: &ArgMatches) -> Scheme {
+    match Scheme::parse(required_arg(args, "scheme"), required_arg(args, "file-id")) {
+        Ok(scheme) => scheme,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_new_scheme(args: &ArgMatches) -> Option<Scheme> {
+    args.get_one::<String>("new-scheme").map(|name| {
+        match Scheme::parse(name, required_arg(args, "file-id")) {
+            Ok(scheme) => scheme,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    })
+}
+
+/// Reads a secret from the environment variable `variable`, or asks for it on
+/// the terminal, so that it never shows in the process arguments.
+fn read_secret(variable: &str, prompt: &str) -> Zeroizing<String> {
+    match env::var(variable) {
+        Ok(secret) => Zeroizing::new(secret),
+        Err(_) => match rpassword::prompt_password(prompt) {
+            Ok(secret) => Zeroizing::new(secret),
+            Err(_) => {
+                eprintln!("Error: Failed to read the secret, set {} instead", variable);
+                process::exit(1);
+            }
+        },
+    }
+}
@@ -0,0 +1,264 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::wallet::LocalWallet;
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::Sha512;
+use std::fs::read_to_string;
+use zeroize::Zeroizing;
+
+/// Derivation path of the first account of Ethereum wallets (BIP-44).
+pub const DEFAULT_DERIVATION_PATH: &str = "m/44'/60'/0'/0/0";
+
+const HARDENED: u32 = 0x8000_0000;
+
+impl LocalWallet {
+    /// Derives the wallet of a BIP-39 mnemonic stored in a file, with its
+    /// optional passphrase, along a BIP-32 derivation path such as
+    /// `DEFAULT_DERIVATION_PATH`. Nothing leaves the machine.
+    pub fn from_mnemonic_file(
+        path: &str,
+        passphrase: &str,
+        derivation_path: &str,
+    ) -> Result<LocalWallet, String> {
+        let phrase: Zeroizing<String> = match read_to_string(path) {
+            Ok(f) => Zeroizing::new(f),
+            Err(_) => return Err(format!("Failed to read mnemonic file ({})", path)),
+        };
+
+        LocalWallet::from_mnemonic(&phrase, passphrase, derivation_path)
+    }
+
+    /// Derives the wallet of a BIP-39 mnemonic along a BIP-32 derivation path.
+    pub fn from_mnemonic(
+        phrase: &str,
+        passphrase: &str,
+        derivation_path: &str,
+    ) -> Result<LocalWallet, String> {
+        let words: Vec<&str> = phrase.split_whitespace().collect();
+
+        let normalized: Zeroizing<String> = Zeroizing::new(words.join(" "));
+
+        let mnemonic: Mnemonic = match Mnemonic::parse_normalized(&normalized) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Invalid mnemonic, {}", e)),
+        };
+
+        let seed: Zeroizing<[u8; 64]> = Zeroizing::new(mnemonic.to_seed(passphrase));
+
+        LocalWallet::from_seed(&*seed, derivation_path)
+    }
+
+    /// Derives the wallet of a BIP-32 seed along a derivation path. The keys
+    /// and chain codes met along the way are wiped.
+    pub fn from_seed(seed: &[u8], derivation_path: &str) -> Result<LocalWallet, String> {
+        let indexes: Vec<u32> = parse_derivation_path(derivation_path)?;
+
+        let (mut secret_key, mut chain_code): (SecretKey, Zeroizing<[u8; 32]>) =
+            split_key(&hmac_sha512(b"Bitcoin seed", &[seed]))?;
+
+        for index in indexes {
+            let tweak: Zeroizing<[u8; 64]> = match index >= HARDENED {
+                true => hmac_sha512(
+                    &*chain_code,
+                    &[
+                        &[0u8],
+                        &*Zeroizing::new(secret_key.secret_bytes()),
+                        &index.to_be_bytes(),
+                    ],
+                ),
+                false => hmac_sha512(
+                    &*chain_code,
+                    &[
+                        &PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret_key)
+                            .serialize(),
+                        &index.to_be_bytes(),
+                    ],
+                ),
+            };
+
+            let (mut child_tweak, child_chain_code): (SecretKey, Zeroizing<[u8; 32]>) =
+                split_key(&tweak)?;
+
+            let child_key: Result<SecretKey, _> = secret_key.add_tweak(&Scalar::from(child_tweak));
+            secret_key.non_secure_erase();
+            child_tweak.non_secure_erase();
+
+            secret_key = match child_key {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Invalid derived key ({})", derivation_path)),
+            };
+            chain_code = child_chain_code;
+        }
+
+        let wallet: Result<LocalWallet, String> =
+            LocalWallet::from_private_key(&*Zeroizing::new(secret_key.secret_bytes()));
+        secret_key.non_secure_erase();
+
+        wallet
+    }
+}
+
+/// Parses a path like `m/44'/60'/0'/0/0`, where `'` or `h` marks hardened indexes.
+pub(crate) fn parse_derivation_path(derivation_path: &str) -> Result<Vec<u32>, String> {
+    let mut components = derivation_path.trim().split('/');
+
+    if components.next() != Some("m") {
+        return Err(format!("Invalid derivation path ({})", derivation_path));
+    }
+
+    components
+        .map(|component| {
+            let (number, offset): (&str, u32) = match component
+                .strip_suffix('\'')
+                .or_else(|| component.strip_suffix('h'))
+                .or_else(|| component.strip_suffix('H'))
+            {
+                Some(number) => (number, HARDENED),
+                None => (component, 0),
+            };
+
+            match number.parse::<u32>() {
+                Ok(index) if index < HARDENED => Ok(index + offset),
+                _ => Err(format!("Invalid derivation path ({})", derivation_path)),
+            }
+        })
+        .collect()
+}
+
+/// Splits an extended key into its secret key and chain code.
+fn split_key(extended_key: &[u8; 64]) -> Result<(SecretKey, Zeroizing<[u8; 32]>), String> {
+    let secret_key: SecretKey = match SecretKey::from_slice(&extended_key[..32]) {
+        Ok(f) => f,
+        Err(_) => return Err("Invalid derived key".to_string()),
+    };
+
+    let mut chain_code: Zeroizing<[u8; 32]> = Zeroizing::new([0u8; 32]);
+    chain_code.copy_from_slice(&extended_key[32..]);
+
+    Ok((secret_key, chain_code))
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> Zeroizing<[u8; 64]> {
+    let mut mac: Hmac<Sha512> = match Hmac::<Sha512>::new_from_slice(key) {
+        Ok(f) => f,
+        Err(_) => unreachable!("HMAC accepts keys of any length"),
+    };
+
+    for part in data {
+        mac.update(part);
+    }
+
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::Signer;
+
+    const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+    /// Returns the wallet of the private key serialized in a BIP-32 `xprv`.
+    fn xprv_wallet(xprv: &str) -> LocalWallet {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        for c in xprv.bytes() {
+            let mut carry: u32 = BASE58_ALPHABET.iter().position(|a| *a == c).unwrap() as u32;
+
+            for byte in bytes.iter_mut().rev() {
+                carry += *byte as u32 * 58;
+                *byte = carry as u8;
+                carry >>= 8;
+            }
+
+            while carry > 0 {
+                bytes.insert(0, carry as u8);
+                carry >>= 8;
+            }
+        }
+
+        // Version, depth, fingerprint, child number, chain code, then 0x00 and
+        // the key, followed by a 4-byte checksum.
+        assert_eq!(bytes.len(), 82);
+        assert_eq!(bytes[45], 0);
+
+        LocalWallet::from_private_key(&bytes[46..78]).unwrap()
+    }
+
+    fn assert_derives(seed: &str, vectors: &[(&str, &str)]) {
+        let seed: Vec<u8> = hex::decode(seed).unwrap();
+
+        for (path, xprv) in vectors {
+            assert_eq!(
+                LocalWallet::from_seed(&seed, path).unwrap().address(),
+                xprv_wallet(xprv).address(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn bip32_test_vector_1() {
+        assert_derives(
+            "000102030405060708090a0b0c0d0e0f",
+            &[
+                ("m", "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi"),
+                ("m/0'", "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7"),
+                ("m/0'/1", "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs"),
+                ("m/0'/1/2'", "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM"),
+                ("m/0'/1/2'/2", "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334"),
+                ("m/0'/1/2'/2/1000000000", "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76"),
+            ],
+        );
+    }
+
+    #[test]
+    fn bip32_test_vector_2() {
+        assert_derives(
+            "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542",
+            &[
+                ("m", "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U"),
+                ("m/0", "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt"),
+                ("m/0/2147483647'", "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9"),
+                ("m/0/2147483647'/1", "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef"),
+                ("m/0/2147483647'/1/2147483646'", "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc"),
+                ("m/0/2147483647'/1/2147483646'/2", "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j"),
+            ],
+        );
+    }
+
+    #[test]
+    fn mnemonic_derives_the_first_anvil_account() {
+        let wallet: LocalWallet = LocalWallet::from_mnemonic(
+            "test test test test test test test test test test test junk",
+            "",
+            DEFAULT_DERIVATION_PATH,
+        )
+        .unwrap();
+
+        assert_eq!(
+            wallet.address().to_checksum(),
+            "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+        );
+    }
+}
@@ -0,0 +1,492 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    archive::{compress, decompress, is_incompressible},
+    check_output,
+    credential::{Unlock, Wallets},
+    decrypt_file, encrypt_file,
+    scheme::Scheme,
+    signer::Signer,
+    signing_scheme, verify_access, AccessOptions, ArchiveOptions, Credential,
+};
+use flate2::Compression;
+use indicatif::ProgressBar;
+use std::{
+    fs::{
+        metadata, read_dir, remove_dir_all, remove_file, rename, symlink_metadata, DirEntry,
+        Metadata,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Encrypts every file below `from_path` into a sibling `<name>.waba`, keeping
+/// the folder layout so that sync tools only see the files that changed.
+pub fn encrypt_per_file(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    println!("\nEncrypt each file of '{}'\n", from_path);
+
+    let start_time: Instant = Instant::now();
+
+    let access: AccessOptions = verify_access(signer, password, access)?;
+
+    let count: usize = encrypt_tree(from_path, &access, options)?;
+
+    let elapsed_time: Duration = start_time.elapsed();
+
+    println!(
+        "\n\n{} files in '{}' are encrypted in {:?}",
+        count, from_path, elapsed_time
+    );
+
+    Ok(())
+}
+
+/// Decrypts every `.waba` file below `from_path` in place, undoing `encrypt_per_file`.
+pub fn decrypt_per_file(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    cosigners: &[Credential],
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    println!("\nDecrypt each file of '{}'\n", from_path);
+
+    let start_time: Instant = Instant::now();
+
+    let wallets: Wallets = Wallets::sign(
+        signer,
+        password,
+        cosigners,
+        &tree_schemes(from_path, options),
+    );
+
+    let count: usize = decrypt_tree(from_path, &wallets, options)?;
+
+    let elapsed_time: Duration = start_time.elapsed();
+
+    println!(
+        "\n\n{} files in '{}' are decrypted in {:?}",
+        count, from_path, elapsed_time
+    );
+
+    Ok(())
+}
+
+/// Encrypts every file below `from_path` for recipients that were already
+/// verified and returns how many files were encrypted.
+pub(crate) fn encrypt_tree(
+    from_path: &str,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
+) -> Result<usize, String> {
+    let files: Vec<PathBuf> =
+        list_files(Path::new(from_path), options, &|path| !is_waba_file(path))?;
+
+    let failures: Vec<String> = run_parallel(&files, "Encryption", |file| {
+        encrypt_single_file(file, access, options)
+    });
+
+    check_failures(from_path, "encrypted", files.len(), failures)
+}
+
+/// Decrypts every `.waba` file below `from_path` with wallets that already
+/// signed and returns how many files were decrypted.
+pub(crate) fn decrypt_tree(
+    from_path: &str,
+    wallets: &Wallets,
+    options: &ArchiveOptions,
+) -> Result<usize, String> {
+    let files: Vec<PathBuf> = list_files(Path::new(from_path), options, &is_waba_file)?;
+
+    let failures: Vec<String> = run_parallel(&files, "Decryption", |file| {
+        decrypt_single_file(file, wallets, options)
+    });
+
+    check_failures(from_path, "decrypted", files.len(), failures)
+}
+
+fn encrypt_single_file(
+    file: &Path,
+    access: &AccessOptions,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let from_path: &str = match file.to_str() {
+        Some(f) => f,
+        None => {
+            return Err(format!(
+                "The file name is not valid UTF-8 ({})",
+                file.display()
+            ))
+        }
+    };
+
+    let final_path: String = format!("{}.waba", from_path);
+
+    check_output(Path::new(&final_path))?;
+
+    let compressed_archive_path: String = format!("{}_temp", final_path);
+
+    let compression: Compression = match is_incompressible(from_path, options)? {
+        true => Compression::none(),
+        false => Compression::default(),
+    };
+
+    let result: Result<(), String> =
+        compress(from_path, &compressed_archive_path, compression, options)
+            .and_then(|_| encrypt_file(&compressed_archive_path, &final_path, access));
+
+    remove_if_exists(&compressed_archive_path, "temporary file")?;
+
+    if let Err(e) = result {
+        remove_if_exists(&final_path, "encrypted file")?;
+        return Err(e);
+    }
+
+    match remove_file(from_path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("Failed to delete input ({})", from_path)),
+    };
+
+    Ok(())
+}
+
+fn decrypt_single_file(
+    file: &Path,
+    wallets: &Wallets,
+    options: &ArchiveOptions,
+) -> Result<(), String> {
+    let from_path: &str = match file.to_str() {
+        Some(f) => f,
+        None => {
+            return Err(format!(
+                "The file name is not valid UTF-8 ({})",
+                file.display()
+            ))
+        }
+    };
+
+    let to_path: &Path = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    if let Some(stem) = file.file_stem() {
+        check_output(&to_path.join(stem))?;
+    }
+
+    let compressed_archive_path: String = format!("{}_temp", from_path);
+    let unpack_path: String = format!("{}_temp_dir", from_path);
+
+    // The archive is unpacked aside and its entries moved next to the input
+    // only when nothing there has the same name.
+    let result: Result<(), String> = decrypt_file(
+        from_path,
+        &compressed_archive_path,
+        &Unlock::Wallets(wallets),
+    )
+    .and_then(|_| decompress(&compressed_archive_path, &unpack_path, options))
+    .and_then(|_| move_entries(Path::new(&unpack_path), to_path));
+
+    remove_if_exists(&compressed_archive_path, "temporary file")?;
+
+    if metadata(&unpack_path).is_ok() && remove_dir_all(&unpack_path).is_err() {
+        return Err(format!(
+            "Failed to delete the temporary folder ({})",
+            unpack_path
+        ));
+    }
+
+    result?;
+
+    match remove_file(from_path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("Failed to delete input file ({})", from_path)),
+    };
+
+    Ok(())
+}
+
+/// Moves the entries of `from_path` into `to_path`, failing before any move
+/// if one of them already exists there.
+fn move_entries(from_path: &Path, to_path: &Path) -> Result<(), String> {
+    let entries: Vec<DirEntry> = match read_dir(from_path).and_then(|entries| entries.collect()) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read output folder ({})",
+                from_path.display()
+            ))
+        }
+    };
+
+    for entry in &entries {
+        check_output(&to_path.join(entry.file_name()))?;
+    }
+
+    for entry in &entries {
+        let target: PathBuf = to_path.join(entry.file_name());
+
+        match rename(entry.path(), &target) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to write output ({})", target.display())),
+        };
+    }
+
+    Ok(())
+}
+
+/// Returns the schemes the `.waba` files below `from_path` are signed with.
+/// Files that cannot be read are left to fail when they are decrypted.
+pub(crate) fn tree_schemes(from_path: &str, options: &ArchiveOptions) -> Vec<Scheme> {
+    list_files(Path::new(from_path), options, &is_waba_file)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|file| signing_scheme(file.to_str()?).ok())
+        .collect()
+}
+
+/// Runs `job` over `files` on one worker per available core and returns the
+/// error message of every file that failed.
+fn run_parallel<F>(files: &[PathBuf], step: &str, job: F) -> Vec<String>
+where
+    F: Fn(&Path) -> Result<(), String> + Sync,
+{
+    let bar: ProgressBar = ProgressBar::new(files.len() as u64);
+
+    bar.enable_steady_tick(Duration::from_millis(100));
+    bar.set_message(format!("{}...", step));
+
+    let next: AtomicUsize = AtomicUsize::new(0);
+    let failures: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    let workers: usize = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(files.len())
+        .max(1);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Err(e) = job(file) {
+                        if let Ok(mut failures) = failures.lock() {
+                            failures.push(e);
+                        }
+                    }
+
+                    bar.inc(1);
+                }
+            });
+        }
+    });
+
+    bar.finish_with_message(format!("{} completed", step));
+
+    failures.into_inner().unwrap_or_default()
+}
+
+fn check_failures(
+    from_path: &str,
+    action: &str,
+    total: usize,
+    failures: Vec<String>,
+) -> Result<usize, String> {
+    for failure in &failures {
+        eprintln!("Error: {}", failure);
+    }
+
+    match failures.is_empty() {
+        true => Ok(total),
+        false => Err(format!(
+            "{} of {} files could not be {} ({})",
+            failures.len(),
+            total,
+            action,
+            from_path
+        )),
+    }
+}
+
+fn list_files(
+    dir_path: &Path,
+    options: &ArchiveOptions,
+    filter: &dyn Fn(&Path) -> bool,
+) -> Result<Vec<PathBuf>, String> {
+    if !dir_path.is_dir() {
+        return Err(format!("Invalid input directory ({})", dir_path.display()));
+    }
+
+    let entries = match read_dir(dir_path) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read input folder ({})",
+                dir_path.display()
+            ))
+        }
+    };
+
+    let mut entries: Vec<DirEntry> = match entries.collect() {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(format!(
+                "Failed to read input folder ({})",
+                dir_path.display()
+            ))
+        }
+    };
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut files: Vec<PathBuf> = Vec::new();
+
+    for entry in entries {
+        let path: PathBuf = entry.path();
+
+        let meta: Metadata = match options.follow_symlinks {
+            true => metadata(&path),
+            false => symlink_metadata(&path),
+        }
+        .map_err(|_| format!("Failed to read input metadata ({})", path.display()))?;
+
+        if meta.is_dir() {
+            files.extend(list_files(&path, options, filter)?);
+        } else if meta.is_file() && filter(&path) {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_waba_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "waba")
+}
+
+fn remove_if_exists(path: &str, description: &str) -> Result<(), String> {
+    if metadata(path).is_ok() {
+        match remove_file(path) {
+            Ok(s) => s,
+            Err(_) => return Err(format!("Failed to delete the {} ({})", description, path)),
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::LocalWallet;
+    use std::fs::{create_dir_all, read, write};
+    use tempfile::TempDir;
+
+    const KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(KEY).unwrap()).unwrap()
+    }
+
+    fn encrypt(folder: &Path) -> Result<usize, String> {
+        let access: AccessOptions =
+            verify_access(&wallet(), "password", &AccessOptions::default()).unwrap();
+
+        encrypt_tree(
+            folder.to_str().unwrap(),
+            &access,
+            &ArchiveOptions::default(),
+        )
+    }
+
+    fn decrypt(folder: &Path) -> Result<usize, String> {
+        let wallets: Wallets = Wallets::sign(&wallet(), "password", &[], &[Scheme::default()]);
+
+        decrypt_tree(
+            folder.to_str().unwrap(),
+            &wallets,
+            &ArchiveOptions::default(),
+        )
+    }
+
+    #[test]
+    fn files_round_trip_in_place() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let first: PathBuf = folder.path().join("a.txt");
+        let second: PathBuf = folder.path().join("sub").join("b.txt");
+
+        create_dir_all(second.parent().unwrap()).unwrap();
+        write(&first, b"first").unwrap();
+        write(&second, b"second").unwrap();
+
+        assert_eq!(encrypt(folder.path()), Ok(2));
+        assert!(!first.exists() && !second.exists());
+        assert!(folder.path().join("a.txt.waba").exists());
+        assert!(folder.path().join("sub").join("b.txt.waba").exists());
+
+        assert_eq!(decrypt(folder.path()), Ok(2));
+        assert_eq!(read(&first).unwrap(), b"first");
+        assert_eq!(read(&second).unwrap(), b"second");
+        assert!(!folder.path().join("a.txt.waba").exists());
+        assert!(!folder.path().join("sub").join("b.txt.waba").exists());
+    }
+
+    #[test]
+    fn encryption_keeps_an_existing_output() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let input: PathBuf = folder.path().join("a.txt");
+        let output: PathBuf = folder.path().join("a.txt.waba");
+
+        write(&input, b"newer").unwrap();
+        write(&output, b"earlier ciphertext").unwrap();
+
+        assert!(encrypt(folder.path()).is_err());
+        assert_eq!(read(&input).unwrap(), b"newer");
+        assert_eq!(read(&output).unwrap(), b"earlier ciphertext");
+    }
+
+    #[test]
+    fn decryption_keeps_existing_plaintext() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let input: PathBuf = folder.path().join("a.txt");
+
+        write(&input, b"earlier").unwrap();
+        assert_eq!(encrypt(folder.path()), Ok(1));
+
+        write(&input, b"newer").unwrap();
+
+        assert!(decrypt(folder.path()).is_err());
+        assert_eq!(read(&input).unwrap(), b"newer");
+        assert!(folder.path().join("a.txt.waba").exists());
+        assert!(!folder.path().join("a.txt.waba_temp_dir").exists());
+    }
+}
@@ -0,0 +1,211 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    credential::RecipientKey,
+    header::{Header, KEY_SIZE},
+    parse_address,
+    scheme::Scheme,
+    secret::SecretBytes,
+    signer::{sign_password, Signer},
+};
+
+/// Returns the addresses of the wallets that can decrypt the file at `from_path`.
+pub fn list_recipients(from_path: &str) -> Result<Vec<String>, String> {
+    Ok(Header::open(from_path)?.recipients())
+}
+
+/// Returns the key that others encrypt files for the wallet of `signer` with,
+/// once it signed `password` under `scheme`. Files encrypted for it need the
+/// same password and scheme to decrypt.
+pub fn recipient_key(
+    signer: &dyn Signer,
+    password: &str,
+    scheme: &Scheme,
+) -> Result<RecipientKey, String> {
+    RecipientKey::derive(&sign_password(signer, password, scheme)?, scheme)
+}
+
+/// Grants `recipients` access to the file at `from_path`. The file key is
+/// unwrapped with the given credential and only the header is rewritten.
+pub fn add_recipients(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    recipients: &[RecipientKey],
+) -> Result<(), String> {
+    let mut header: Header = Header::open(from_path)?;
+
+    for recipient in recipients {
+        recipient.check_scheme(&header.scheme)?;
+    }
+
+    let file_key: SecretBytes<KEY_SIZE> = unlock(&header, signer, password)?;
+
+    for recipient in recipients {
+        header.add_recipient(&file_key, recipient)?;
+    }
+
+    header.rewrite(from_path)?;
+
+    for recipient in recipients {
+        println!("'{}' can now decrypt '{}'", recipient.address, from_path);
+    }
+
+    Ok(())
+}
+
+/// Removes the key slots of `addresses` from the file at `from_path`, after
+/// checking that the given credential is itself allowed to decrypt it.
+pub fn remove_recipients(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    addresses: &[String],
+) -> Result<(), String> {
+    let mut header: Header = Header::open(from_path)?;
+
+    unlock(&header, signer, password)?;
+
+    for removed in addresses {
+        if !header.remove_slot(parse_address(removed)?.as_bytes()) {
+            return Err(format!(
+                "The wallet is not a recipient of the file ({})",
+                removed
+            ));
+        }
+    }
+
+    if header.recipients().is_empty() {
+        return Err(format!(
+            "Removing every recipient would make the file impossible to decrypt ({})",
+            from_path
+        ));
+    }
+
+    header.rewrite(from_path)?;
+
+    for removed in addresses {
+        println!("'{}' can no longer decrypt '{}'", removed, from_path);
+    }
+
+    println!(
+        "\nWarning: copies of '{}' made before this change still accept the removed wallets, \
+         and they may already know the file key. Re-encrypt the content to fully revoke them.",
+        from_path
+    );
+
+    Ok(())
+}
+
+fn unlock(
+    header: &Header,
+    signer: &dyn Signer,
+    password: &str,
+) -> Result<SecretBytes<KEY_SIZE>, String> {
+    header.unwrap_key(&[sign_password(signer, password, &header.scheme)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credential::{Unlock, Wallets},
+        decrypt_file, encrypt_file, verify_access,
+        wallet::LocalWallet,
+        AccessOptions,
+    };
+    use std::{
+        fs::{read, write},
+        path::PathBuf,
+    };
+    use tempfile::TempDir;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const SECOND_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn wallet(private_key: &str) -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(private_key).unwrap()).unwrap()
+    }
+
+    /// Encrypts a file for `signer` alone and returns its folder and path.
+    fn encrypted_file(signer: &LocalWallet) -> (TempDir, String) {
+        let folder: TempDir = TempDir::new().unwrap();
+        let plain: PathBuf = folder.path().join("plain");
+        let encrypted: PathBuf = folder.path().join("plain.waba");
+
+        write(&plain, b"content").unwrap();
+
+        let access: AccessOptions =
+            verify_access(signer, "password", &AccessOptions::default()).unwrap();
+        encrypt_file(
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            &access,
+        )
+        .unwrap();
+
+        (folder, encrypted.to_str().unwrap().to_string())
+    }
+
+    fn decrypt(from_path: &str, signer: &LocalWallet) -> Result<Vec<u8>, String> {
+        let to_path: String = format!("{}_plain", from_path);
+        let wallets: Wallets = Wallets::sign(signer, "password", &[], &[Scheme::default()]);
+
+        decrypt_file(from_path, &to_path, &Unlock::Wallets(&wallets))?;
+
+        Ok(read(to_path).unwrap())
+    }
+
+    #[test]
+    fn added_wallets_decrypt() {
+        let first: LocalWallet = wallet(FIRST_KEY);
+        let second: LocalWallet = wallet(SECOND_KEY);
+        let (_folder, path) = encrypted_file(&first);
+
+        assert!(decrypt(&path, &second).is_err());
+
+        let key: RecipientKey = recipient_key(&second, "password", &Scheme::default()).unwrap();
+        add_recipients(&path, &first, "password", &[key]).unwrap();
+
+        assert_eq!(decrypt(&path, &second).unwrap(), b"content");
+        assert_eq!(decrypt(&path, &first).unwrap(), b"content");
+        assert_eq!(
+            list_recipients(&path).unwrap(),
+            vec![first.address().to_string(), second.address().to_string()]
+        );
+    }
+
+    #[test]
+    fn removed_wallets_no_longer_decrypt() {
+        let first: LocalWallet = wallet(FIRST_KEY);
+        let second: LocalWallet = wallet(SECOND_KEY);
+        let (_folder, path) = encrypted_file(&first);
+
+        let key: RecipientKey = recipient_key(&second, "password", &Scheme::default()).unwrap();
+        add_recipients(&path, &first, "password", &[key]).unwrap();
+        remove_recipients(&path, &first, "password", &[second.address().to_string()]).unwrap();
+
+        assert!(decrypt(&path, &second).is_err());
+        assert_eq!(decrypt(&path, &first).unwrap(), b"content");
+        assert!(
+            remove_recipients(&path, &first, "password", &[first.address().to_string()]).is_err()
+        );
+    }
+}
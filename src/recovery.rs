@@ -0,0 +1,249 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    compare::ct_eq,
+    header::KEY_SIZE,
+    keccak256,
+    secret::{secret_keccak256, SecretBytes},
+};
+use bip39::Mnemonic;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+const RECOVERY_DOMAIN: &[u8] = b"Wabasen recovery code v1";
+const ESCROW_DOMAIN: &[u8] = b"Wabasen escrow slot v1";
+
+const FINGERPRINT_SIZE: usize = 4;
+const ESCROW_KEY_SIZE: usize = 32;
+
+/// Secret opening the recovery slot of a file when its wallets are lost.
+pub enum RecoveryKey {
+    /// Recovery code printed by `generate_recovery_code`.
+    Code(String),
+    /// Hex secret key of the escrow key pair printed by `generate_escrow_keys`.
+    EscrowSecret(String),
+}
+
+impl Drop for RecoveryKey {
+    fn drop(&mut self) {
+        match self {
+            RecoveryKey::Code(secret) | RecoveryKey::EscrowSecret(secret) => secret.zeroize(),
+        }
+    }
+}
+
+/// Generates a recovery code of 24 words, 256 bits of entropy with a checksum.
+/// It is returned once and never stored.
+pub fn generate_recovery_code() -> Result<String, String> {
+    let mut entropy: Zeroizing<[u8; 32]> = Zeroizing::new([0u8; 32]);
+
+    OsRng.fill_bytes(&mut *entropy);
+
+    match Mnemonic::from_entropy(&*entropy) {
+        Ok(f) => Ok(f.to_string()),
+        Err(_) => Err("Failed to generate recovery code".to_string()),
+    }
+}
+
+/// Generates an X25519 escrow key pair and returns the secret and public keys
+/// as hex. Only the public key is needed to encrypt.
+pub fn generate_escrow_keys() -> (String, String) {
+    let secret: StaticSecret = StaticSecret::random_from_rng(OsRng);
+    let public: PublicKey = PublicKey::from(&secret);
+
+    (
+        hex::encode(secret.to_bytes()),
+        hex::encode(public.as_bytes()),
+    )
+}
+
+/// Derives the key wrapping a recovery slot from a recovery code, along with
+/// the fingerprint identifying the slot. The word checksum is verified first.
+pub(crate) fn recovery_code_key(code: &str) -> Result<(SecretBytes<KEY_SIZE>, Vec<u8>), String> {
+    let normalized: Zeroizing<String> = Zeroizing::new(code.to_lowercase());
+
+    let mnemonic: Mnemonic = match Mnemonic::parse_normalized(&normalized) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Invalid recovery code ({})", e)),
+    };
+
+    let entropy: Zeroizing<Vec<u8>> = Zeroizing::new(mnemonic.to_entropy());
+
+    let kek: SecretBytes<KEY_SIZE> = secret_keccak256(&[RECOVERY_DOMAIN, &entropy]);
+    let fingerprint: Vec<u8> = keccak256(&*kek)[..FINGERPRINT_SIZE].to_vec();
+
+    Ok((kek, fingerprint))
+}
+
+/// Derives a fresh key wrapping an escrow slot for the hex X25519 `public_key`.
+/// The slot id returned holds the escrow public key and the ephemeral one.
+pub(crate) fn escrow_wrap_key(
+    public_key: &str,
+) -> Result<(SecretBytes<KEY_SIZE>, Vec<u8>), String> {
+    let public: PublicKey = PublicKey::from(*decode_escrow_key(public_key)?);
+    let ephemeral: EphemeralSecret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public: PublicKey = PublicKey::from(&ephemeral);
+
+    let shared: SharedSecret = ephemeral.diffie_hellman(&public);
+
+    let mut id: Vec<u8> = public.as_bytes().to_vec();
+    id.extend_from_slice(ephemeral_public.as_bytes());
+
+    Ok((escrow_kek(shared.as_bytes(), &id), id))
+}
+
+/// Derives the key of the escrow slot identified by `id` when it was written
+/// for the hex X25519 `secret_key`, and `None` otherwise.
+pub(crate) fn escrow_unwrap_key(
+    secret_key: &str,
+    id: &[u8],
+) -> Result<Option<SecretBytes<KEY_SIZE>>, String> {
+    let secret: StaticSecret = StaticSecret::from(*decode_escrow_key(secret_key)?);
+
+    if id.len() != 2 * ESCROW_KEY_SIZE
+        || !ct_eq(PublicKey::from(&secret).as_bytes(), &id[..ESCROW_KEY_SIZE])
+    {
+        return Ok(None);
+    }
+
+    let mut ephemeral_public: [u8; ESCROW_KEY_SIZE] = [0u8; ESCROW_KEY_SIZE];
+    ephemeral_public.copy_from_slice(&id[ESCROW_KEY_SIZE..]);
+
+    let shared: SharedSecret = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+
+    Ok(Some(escrow_kek(shared.as_bytes(), id)))
+}
+
+fn escrow_kek(shared: &[u8; 32], id: &[u8]) -> SecretBytes<KEY_SIZE> {
+    secret_keccak256(&[ESCROW_DOMAIN, shared, id])
+}
+
+/// Decodes a hex X25519 key. Both halves of the key pair go through here, so
+/// the bytes are wiped once dropped.
+fn decode_escrow_key(key: &str) -> Result<Zeroizing<[u8; ESCROW_KEY_SIZE]>, String> {
+    match hex::decode(key.trim_start_matches("0x")).map(Zeroizing::new) {
+        Ok(bytes) if bytes.len() == ESCROW_KEY_SIZE => {
+            let mut output: Zeroizing<[u8; ESCROW_KEY_SIZE]> =
+                Zeroizing::new([0u8; ESCROW_KEY_SIZE]);
+            output.copy_from_slice(&bytes);
+            Ok(output)
+        }
+        _ => Err("Invalid escrow key format".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credential::{AccessOptions, Unlock},
+        decrypt_file, encrypt_file, verify_access,
+        wallet::LocalWallet,
+    };
+    use std::{
+        fs::{read, write},
+        path::PathBuf,
+    };
+    use tempfile::TempDir;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    /// Encrypts a file for a wallet along with `options`, and returns its
+    /// folder and path.
+    fn encrypted_file(options: &AccessOptions) -> (TempDir, String) {
+        let wallet: LocalWallet =
+            LocalWallet::from_private_key(&hex::decode(FIRST_KEY).unwrap()).unwrap();
+        let folder: TempDir = TempDir::new().unwrap();
+        let plain: PathBuf = folder.path().join("plain");
+        let encrypted: PathBuf = folder.path().join("plain.waba");
+
+        write(&plain, b"content").unwrap();
+
+        let access: AccessOptions = verify_access(&wallet, "password", options).unwrap();
+        encrypt_file(
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            &access,
+        )
+        .unwrap();
+
+        (folder, encrypted.to_str().unwrap().to_string())
+    }
+
+    fn decrypt(from_path: &str, recovery: RecoveryKey) -> Result<Vec<u8>, String> {
+        let to_path: String = format!("{}_plain", from_path);
+
+        decrypt_file(from_path, &to_path, &Unlock::Recovery(&recovery))?;
+
+        Ok(read(to_path).unwrap())
+    }
+
+    #[test]
+    fn recovery_codes_decrypt() {
+        let code: String = generate_recovery_code().unwrap();
+        let options: AccessOptions = AccessOptions {
+            recovery_code: Some(code.clone()),
+            ..AccessOptions::default()
+        };
+        let (_folder, path) = encrypted_file(&options);
+
+        assert_eq!(
+            decrypt(&path, RecoveryKey::Code(code.to_uppercase())).unwrap(),
+            b"content"
+        );
+    }
+
+    #[test]
+    fn escrow_secrets_decrypt() {
+        let (secret_key, public_key) = generate_escrow_keys();
+        let options: AccessOptions = AccessOptions {
+            escrow_key: Some(public_key),
+            ..AccessOptions::default()
+        };
+        let (_folder, path) = encrypted_file(&options);
+
+        assert_eq!(
+            decrypt(&path, RecoveryKey::EscrowSecret(secret_key)).unwrap(),
+            b"content"
+        );
+
+        let (other_secret_key, _) = generate_escrow_keys();
+        assert!(decrypt(&path, RecoveryKey::EscrowSecret(other_secret_key)).is_err());
+    }
+
+    #[test]
+    fn wrong_recovery_codes_are_rejected() {
+        let code: String = generate_recovery_code().unwrap();
+        let options: AccessOptions = AccessOptions {
+            recovery_code: Some(code.clone()),
+            ..AccessOptions::default()
+        };
+        let (_folder, path) = encrypted_file(&options);
+
+        let other: String = generate_recovery_code().unwrap();
+        assert!(decrypt(&path, RecoveryKey::Code(other)).is_err());
+
+        let zero: String = format!("{} art", ["abandon"; 23].join(" "));
+        assert!(recovery_code_key(&zero).is_ok());
+        assert!(recovery_code_key(&["abandon"; 24].join(" ")).is_err());
+        assert!(recovery_code_key("abandon abandon").is_err());
+    }
+}
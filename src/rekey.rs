@@ -0,0 +1,314 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    credential::{AccessOptions, Credential, RecipientKey},
+    generate_key_from_signature, generate_nonce_from_password,
+    header::{Header, KEY_SIZE, NONCE_SIZE},
+    scheme::Scheme,
+    secret::SecretBytes,
+    signer::{sign_password, Signer},
+};
+use chacha20poly1305::{aead::stream, KeyInit, XChaCha20Poly1305};
+use std::{
+    fs::{remove_file, rename, File},
+    io::{Read, Write},
+};
+
+/// Moves the file at `from_path` from the wallet of `signer` to `new`. Files with
+/// key slots only get the old slot wrapped again for the new wallet; older
+/// files are decrypted and encrypted again chunk by chunk in memory. In both
+/// cases the plaintext never touches the disk and the file is replaced
+/// atomically.
+///
+/// `scheme` is what the new signature was made with. It defaults to the
+/// scheme of the file, or to the default scheme for files without key slots,
+/// which is how files signed with the bare password are migrated.
+pub fn rekey(
+    from_path: &str,
+    signer: &dyn Signer,
+    password: &str,
+    new: &Credential,
+    scheme: Option<&Scheme>,
+) -> Result<(), String> {
+    let mut source_file: File = match File::open(from_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to open input file ({})", from_path)),
+    };
+
+    match Header::read(&mut source_file)? {
+        Some(mut header) => {
+            let old: Credential = sign_password(signer, password, &header.scheme)?;
+
+            header.rekey_slot(&old, new, scheme)?;
+            header.rewrite(from_path)?;
+        }
+        None => {
+            let scheme: Scheme = scheme.cloned().unwrap_or_default();
+
+            let old: Credential = sign_password(signer, password, &Scheme::PersonalSign)?;
+            new.verify(&scheme)?;
+
+            let temp_path: String = format!("{}_temp", from_path);
+
+            let result: Result<(), String> =
+                reencrypt_legacy(&mut source_file, from_path, &temp_path, &old, new, &scheme)
+                    .and_then(|_| match rename(&temp_path, from_path) {
+                        Ok(f) => Ok(f),
+                        Err(_) => Err(format!(
+                            "Failed to replace the encrypted file ({})",
+                            from_path
+                        )),
+                    });
+
+            if result.is_err() {
+                let _ = remove_file(&temp_path);
+                return result;
+            }
+        }
+    };
+
+    println!("'{}' is rekeyed for wallet '{}'", from_path, new.address);
+
+    Ok(())
+}
+
+/// Streams a file written before headers existed into a new file with a key
+/// slot for `new`, mapping every decrypted chunk to one encrypted chunk.
+fn reencrypt_legacy(
+    source_file: &mut File,
+    from_path: &str,
+    to_path: &str,
+    old: &Credential,
+    new: &Credential,
+    scheme: &Scheme,
+) -> Result<(), String> {
+    let old_key: SecretBytes<KEY_SIZE> = generate_key_from_signature(&old.signature);
+    let old_nonce: SecretBytes<NONCE_SIZE> = generate_nonce_from_password(&old.password);
+
+    let mut stream_decryptor: stream::Decryptor<_, stream::StreamBE32<_>> =
+        stream::DecryptorBE32::from_aead(
+            XChaCha20Poly1305::new(old_key.as_ref().into()),
+            old_nonce.as_ref().into(),
+        );
+
+    let access: AccessOptions = AccessOptions {
+        recipients: vec![RecipientKey::derive(new, scheme)?],
+        scheme: scheme.clone(),
+        ..AccessOptions::default()
+    };
+    let (header, new_key): (Header, SecretBytes<KEY_SIZE>) = Header::generate(&access)?;
+
+    let mut stream_encryptor: stream::Encryptor<_, stream::StreamBE32<_>> =
+        stream::EncryptorBE32::from_aead(
+            XChaCha20Poly1305::new(new_key.as_ref().into()),
+            header.nonce.as_ref().into(),
+        );
+
+    let mut buffer: [u8; 4112] = [0u8; 4112];
+
+    let mut dist_file: File = match File::create(to_path) {
+        Ok(f) => f,
+        Err(_) => return Err(format!("Failed to create output file ({})", to_path)),
+    };
+
+    header.write(&mut dist_file)?;
+
+    loop {
+        let read_count: usize = match source_file.read(&mut buffer) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Failed to read input file ({})", from_path)),
+        };
+
+        if read_count == 4112 {
+            let plaintext: Vec<u8> = match stream_decryptor.decrypt_next(buffer.as_slice()) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to decrypt file ({})", from_path)),
+            };
+
+            let ciphertext: Vec<u8> = match stream_encryptor.encrypt_next(plaintext.as_slice()) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to encrypt file ({})", from_path)),
+            };
+
+            match dist_file.write_all(&ciphertext) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to write file ({})", to_path)),
+            };
+        } else {
+            let plaintext: Vec<u8> = match stream_decryptor.decrypt_last(&buffer[..read_count]) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to decrypt file ({})", from_path)),
+            };
+
+            let ciphertext: Vec<u8> = match stream_encryptor.encrypt_last(plaintext.as_slice()) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to encrypt file ({})", from_path)),
+            };
+
+            match dist_file.write_all(&ciphertext) {
+                Ok(f) => f,
+                Err(_) => return Err(format!("Failed to write file ({})", to_path)),
+            };
+            break;
+        }
+    }
+
+    match dist_file.sync_all() {
+        Ok(f) => Ok(f),
+        Err(_) => Err(format!("Failed to write file ({})", to_path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credential::{Unlock, Wallets},
+        decrypt_file, encrypt_file, verify_access,
+        wallet::LocalWallet,
+    };
+    use std::{
+        fs::{read, write},
+        path::{Path, PathBuf},
+    };
+    use tempfile::TempDir;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const SECOND_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn wallet(private_key: &str) -> LocalWallet {
+        LocalWallet::from_private_key(&hex::decode(private_key).unwrap()).unwrap()
+    }
+
+    /// Spans several chunks, so a rekey fails after writing part of its output.
+    fn plaintext() -> Vec<u8> {
+        (0..10_000u32).map(|index| index as u8).collect()
+    }
+
+    fn encrypted_file(folder: &Path, signer: &LocalWallet) -> String {
+        let plain: PathBuf = folder.join("plain");
+        let encrypted: PathBuf = folder.join("plain.waba");
+
+        write(&plain, plaintext()).unwrap();
+
+        let access: AccessOptions =
+            verify_access(signer, "password", &AccessOptions::default()).unwrap();
+        encrypt_file(
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            &access,
+        )
+        .unwrap();
+
+        encrypted.to_str().unwrap().to_string()
+    }
+
+    /// Writes a file the way versions without key slots did: no header, the
+    /// key derived from the `personal_sign` signature and the nonce from the
+    /// password.
+    fn legacy_file(folder: &Path, signer: &LocalWallet) -> String {
+        let old: Credential = sign_password(signer, "password", &Scheme::PersonalSign).unwrap();
+        let key: SecretBytes<KEY_SIZE> = generate_key_from_signature(&old.signature);
+        let nonce: SecretBytes<NONCE_SIZE> = generate_nonce_from_password(&old.password);
+
+        let mut encryptor: stream::Encryptor<_, stream::StreamBE32<_>> =
+            stream::EncryptorBE32::from_aead(
+                XChaCha20Poly1305::new(key.as_ref().into()),
+                nonce.as_ref().into(),
+            );
+
+        let plaintext: Vec<u8> = plaintext();
+        let chunks: Vec<&[u8]> = plaintext.chunks(4096).collect();
+        let mut bytes: Vec<u8> = Vec::new();
+
+        for chunk in &chunks[..chunks.len() - 1] {
+            bytes.extend(encryptor.encrypt_next(*chunk).unwrap());
+        }
+        bytes.extend(encryptor.encrypt_last(chunks[chunks.len() - 1]).unwrap());
+
+        let path: PathBuf = folder.join("legacy.waba");
+        write(&path, bytes).unwrap();
+
+        path.to_str().unwrap().to_string()
+    }
+
+    fn decrypt(from_path: &str, signer: &LocalWallet) -> Result<Vec<u8>, String> {
+        let to_path: String = format!("{}_plain", from_path);
+        let wallets: Wallets = Wallets::sign(signer, "password", &[], &[Scheme::default()]);
+
+        decrypt_file(from_path, &to_path, &Unlock::Wallets(&wallets))?;
+
+        Ok(read(to_path).unwrap())
+    }
+
+    fn credential(signer: &LocalWallet) -> Credential {
+        sign_password(signer, "password", &Scheme::default()).unwrap()
+    }
+
+    #[test]
+    fn rekeyed_slots_open_for_the_new_wallet_only() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let first: LocalWallet = wallet(FIRST_KEY);
+        let second: LocalWallet = wallet(SECOND_KEY);
+        let path: String = encrypted_file(folder.path(), &first);
+
+        rekey(&path, &first, "password", &credential(&second), None).unwrap();
+
+        assert_eq!(decrypt(&path, &second).unwrap(), plaintext());
+        assert!(decrypt(&path, &first).is_err());
+    }
+
+    #[test]
+    fn legacy_files_migrate_to_key_slots() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let first: LocalWallet = wallet(FIRST_KEY);
+        let second: LocalWallet = wallet(SECOND_KEY);
+        let path: String = legacy_file(folder.path(), &first);
+
+        rekey(&path, &first, "password", &credential(&second), None).unwrap();
+
+        assert_eq!(Header::open(&path).unwrap().scheme, Scheme::default());
+        assert_eq!(decrypt(&path, &second).unwrap(), plaintext());
+        assert!(decrypt(&path, &first).is_err());
+    }
+
+    #[test]
+    fn failed_rekeys_leave_the_file_intact() {
+        let folder: TempDir = TempDir::new().unwrap();
+        let first: LocalWallet = wallet(FIRST_KEY);
+        let second: LocalWallet = wallet(SECOND_KEY);
+
+        let path: String = encrypted_file(folder.path(), &first);
+        let original: Vec<u8> = read(&path).unwrap();
+
+        assert!(rekey(&path, &first, "wrong password", &credential(&second), None).is_err());
+        assert_eq!(read(&path).unwrap(), original);
+
+        // The last chunk fails to decrypt once the others were written out.
+        let path: String = legacy_file(folder.path(), &first);
+        let mut tampered: Vec<u8> = read(&path).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        write(&path, &tampered).unwrap();
+
+        assert!(rekey(&path, &first, "password", &credential(&second), None).is_err());
+        assert_eq!(read(&path).unwrap(), tampered);
+        assert!(!Path::new(&format!("{}_temp", path)).exists());
+    }
+}
@@ -0,0 +1,278 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{address::Address, parse_address, scheme::TypedData, signer::Signer};
+use serde_json::{json, Value};
+use std::time::Duration;
+use ureq::Agent;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The node signs with an unlocked account, so it answers without waiting on
+/// anyone.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Ethereum node, such as Anvil, Hardhat or geth, that signs with an account it
+/// keeps unlocked, through `personal_sign` or `eth_signTypedData_v4`.
+pub struct RpcSigner {
+    url: String,
+    address: Address,
+    agent: Agent,
+}
+
+impl RpcSigner {
+    pub fn new(url: &str, address: &str) -> Result<RpcSigner, String> {
+        RpcSigner::with_timeouts(url, address, CONNECT_TIMEOUT, RESPONSE_TIMEOUT)
+    }
+
+    fn with_timeouts(
+        url: &str,
+        address: &str,
+        connect: Duration,
+        response: Duration,
+    ) -> Result<RpcSigner, String> {
+        let agent: Agent = Agent::config_builder()
+            .timeout_connect(Some(connect))
+            .timeout_recv_response(Some(response))
+            .timeout_recv_body(Some(response))
+            .build()
+            .into();
+
+        Ok(RpcSigner {
+            url: url.to_string(),
+            address: parse_address(address)?,
+            agent,
+        })
+    }
+}
+
+impl Signer for RpcSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        self.call(
+            "personal_sign",
+            json!([
+                format!("0x{}", hex::encode(message)),
+                self.address.to_lowercase()
+            ]),
+        )
+    }
+
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        self.call(
+            "eth_signTypedData_v4",
+            json!([self.address.to_lowercase(), typed_data.json()]),
+        )
+    }
+}
+
+impl RpcSigner {
+    /// Calls `method` on the node and returns its string result.
+    fn call(&self, method: &str, params: Value) -> Result<String, String> {
+        let request: Value = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+
+        let body: String = match self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .send(request.to_string())
+            .and_then(|mut response| response.body_mut().read_to_string())
+        {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Failed to reach the node, {} ({})", e, self.url)),
+        };
+
+        let response: Value = match serde_json::from_str(&body) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Invalid response from the node ({})", self.url)),
+        };
+
+        match (response["result"].as_str(), &response["error"]) {
+            (Some(signature), _) => Ok(signature.to_string()),
+            (None, Value::Null) => Err(format!("Invalid response from the node ({})", self.url)),
+            (None, error) => Err(format!(
+                "The node refused to sign, {} ({})",
+                error["message"].as_str().unwrap_or("unknown error"),
+                self.url
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+        time::Instant,
+    };
+
+    const ADDRESS: &str = "0x2c7536E3605D9C16a7a3D7b1898e529396a65c23";
+    const SIGNATURE: &str = "0xc07aad738053c6e45393c2cbda6d49085dc19df82ef95a1f28f54c699684d48848fa5eacb17a237448f60075938ba3bf6e6b557fa7c8eacbb4239a9ed80edcf51b";
+
+    /// Answers a single HTTP request with `body` and returns the URL to call,
+    /// along with a handle yielding the JSON request the node received.
+    fn serve(body: String) -> (String, JoinHandle<Value>) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: String = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle: JoinHandle<Value> = thread::spawn(move || {
+            let (mut stream, _): (TcpStream, _) = listener.accept().unwrap();
+            let mut reader: BufReader<TcpStream> = BufReader::new(stream.try_clone().unwrap());
+            let mut length: usize = 0;
+
+            loop {
+                let mut line: String = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                if line == "\r\n" {
+                    break;
+                }
+
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut request: Vec<u8> = vec![0u8; length];
+            reader.read_exact(&mut request).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+
+            serde_json::from_slice(&request).unwrap()
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn the_result_is_the_signature() {
+        let (url, request) =
+            serve(json!({ "jsonrpc": "2.0", "id": 1, "result": SIGNATURE }).to_string());
+
+        let signer: RpcSigner = RpcSigner::new(&url, ADDRESS).unwrap();
+
+        assert_eq!(signer.sign_message(b"password").unwrap(), SIGNATURE);
+
+        let request: Value = request.join().unwrap();
+        assert_eq!(request["method"], "personal_sign");
+        assert_eq!(
+            request["params"],
+            json!(["0x70617373776f7264", ADDRESS.to_lowercase()])
+        );
+    }
+
+    #[test]
+    fn an_error_reply_is_reported() {
+        let (url, request) = serve(
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": -32000, "message": "account is locked" },
+            })
+            .to_string(),
+        );
+
+        let signer: RpcSigner = RpcSigner::new(&url, ADDRESS).unwrap();
+        let error: String = signer.sign_message(b"password").unwrap_err();
+
+        assert!(
+            error.starts_with("The node refused to sign, account is locked"),
+            "{}",
+            error
+        );
+        request.join().unwrap();
+    }
+
+    #[test]
+    fn a_reply_that_is_not_json_is_invalid() {
+        let (url, request) = serve("<html>Bad gateway</html>".to_string());
+
+        let signer: RpcSigner = RpcSigner::new(&url, ADDRESS).unwrap();
+        let error: String = signer.sign_message(b"password").unwrap_err();
+
+        assert!(
+            error.starts_with("Invalid response from the node"),
+            "{}",
+            error
+        );
+        request.join().unwrap();
+    }
+
+    #[test]
+    fn a_reply_without_result_or_error_is_invalid() {
+        let (url, request) = serve(json!({ "jsonrpc": "2.0", "id": 1 }).to_string());
+
+        let signer: RpcSigner = RpcSigner::new(&url, ADDRESS).unwrap();
+        let error: String = signer.sign_message(b"password").unwrap_err();
+
+        assert!(
+            error.starts_with("Invalid response from the node"),
+            "{}",
+            error
+        );
+        request.join().unwrap();
+    }
+
+    #[test]
+    fn a_node_that_never_answers_times_out() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: String = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle: JoinHandle<()> = thread::spawn(move || {
+            let (stream, _): (TcpStream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(3));
+            drop(stream);
+        });
+
+        let signer: RpcSigner = RpcSigner::with_timeouts(
+            &url,
+            ADDRESS,
+            Duration::from_millis(500),
+            Duration::from_millis(500),
+        )
+        .unwrap();
+
+        let started: Instant = Instant::now();
+        let error: String = signer.sign_message(b"password").unwrap_err();
+
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        assert!(error.starts_with("Failed to reach the node"), "{}", error);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{compare::ct_eq, hash_message, keccak256, secret::secret_keccak256};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use scrypt::{scrypt, Params};
+use serde_json::{json, Value};
+use std::sync::{Mutex, OnceLock};
+use zeroize::Zeroize;
+
+pub(crate) const APP_NAME: &str = "Wabasen";
+pub(crate) const APP_VERSION: &str = "1";
+
+const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version)";
+const UNLOCK_TYPE: &str =
+    "WabasenUnlock(string app,string version,string fileId,bytes32 passwordHash)";
+
+const STATEMENT_TITLE: &str = "Wabasen unlock v1";
+const KEY_ID_DOMAIN: &[u8] = b"Wabasen key id v1";
+const KEY_ID_SIZE: usize = 16;
+const PASSWORD_HASH_DOMAIN: &[u8] = b"Wabasen password hash v1";
+
+/// scrypt cost of the password digests shown to the wallet: 2^15 iterations
+/// with blocks of 8, about 32 MiB of memory.
+const DIGEST_LOG_N: u8 = 15;
+const DIGEST_R: u32 = 8;
+const DIGEST_P: u32 = 1;
+const DIGEST_CACHE_SIZE: usize = 16;
+
+/// Digests derived by this process, keyed by a hash of their inputs under a
+/// random key, so that checking a signature for many files runs scrypt once.
+static DIGESTS: Mutex<Vec<([u8; 32], [u8; 32])>> = Mutex::new(Vec::new());
+static DIGEST_CACHE_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+const SCHEME_PERSONAL_SIGN: u8 = 0;
+const SCHEME_TYPED_DATA: u8 = 1;
+const SCHEME_STATEMENT: u8 = 2;
+
+/// What the wallet signs to unlock a file. It is recorded in the file header
+/// so decryption checks signatures the same way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scheme {
+    /// Domain-separated Wabasen statement naming the wallet address and a key
+    /// id derived from the password, which itself is never shown to the wallet.
+    /// This is the default for new files.
+    Statement { file_id: String },
+    /// EIP-191 `personal_sign` of the password itself, used by files written
+    /// before signing schemes existed. Any dApp getting the same string signed
+    /// learns the key, so new files should not use it.
+    PersonalSign,
+    /// EIP-712 typed data `WabasenUnlock { app, version, fileId, passwordHash }`,
+    /// shown as structured fields by wallets that support it.
+    TypedData { file_id: String },
+}
+
+/// EIP-712 typed data that a wallet signs with `eth_signTypedData_v4`.
+#[derive(Clone, Debug)]
+pub struct TypedData {
+    domain_separator: [u8; 32],
+    struct_hash: [u8; 32],
+    json: Value,
+}
+
+impl TypedData {
+    pub fn domain_separator(&self) -> [u8; 32] {
+        self.domain_separator
+    }
+
+    pub fn struct_hash(&self) -> [u8; 32] {
+        self.struct_hash
+    }
+
+    /// Returns the digest `keccak256(0x1901 ‖ domainSeparator ‖ structHash)`.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut bytes: Vec<u8> = vec![0x19, 0x01];
+        bytes.extend_from_slice(&self.domain_separator);
+        bytes.extend_from_slice(&self.struct_hash);
+
+        keccak256(&bytes)
+    }
+
+    /// Returns the `eth_signTypedData_v4` payload.
+    pub fn json(&self) -> &Value {
+        &self.json
+    }
+}
+
+impl Default for Scheme {
+    fn default() -> Scheme {
+        Scheme::Statement {
+            file_id: String::new(),
+        }
+    }
+}
+
+impl Scheme {
+    /// Parses the `--scheme` value, `statement`, `personal-sign` or `eip712`.
+    pub fn parse(name: &str, file_id: &str) -> Result<Scheme, String> {
+        match name {
+            "statement" => Ok(Scheme::Statement {
+                file_id: file_id.to_string(),
+            }),
+            "personal-sign" => Ok(Scheme::PersonalSign),
+            "eip712" => Ok(Scheme::TypedData {
+                file_id: file_id.to_string(),
+            }),
+            _ => Err(format!("Unknown signing scheme ({})", name)),
+        }
+    }
+
+    /// Returns the text signed with `personal_sign` by `address` for
+    /// `password`, or `None` for typed data.
+    pub(crate) fn message(&self, address: &str, password: &str) -> Option<String> {
+        match self {
+            Scheme::Statement { file_id } => Some(statement(address, file_id, password)),
+            Scheme::PersonalSign => Some(password.to_string()),
+            Scheme::TypedData { .. } => None,
+        }
+    }
+
+    /// Returns the typed data signed with `eth_signTypedData_v4` by `address`
+    /// for `password`, or `None` for `personal_sign` messages.
+    pub(crate) fn typed_data(&self, address: &str, password: &str) -> Option<TypedData> {
+        match self {
+            Scheme::TypedData { file_id } => Some(unlock_typed_data(address, file_id, password)),
+            Scheme::Statement { .. } | Scheme::PersonalSign => None,
+        }
+    }
+
+    /// Returns the 32-byte digest signed by `address` for `password`.
+    pub(crate) fn message_hash(&self, address: &str, password: &str) -> [u8; 32] {
+        match self {
+            Scheme::Statement { file_id } => {
+                hash_message(statement(address, file_id, password).as_bytes())
+            }
+            Scheme::PersonalSign => hash_message(password.as_bytes()),
+            Scheme::TypedData { file_id } => unlock_typed_data(address, file_id, password).hash(),
+        }
+    }
+
+    /// Returns the `--scheme` value naming this scheme.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Scheme::Statement { .. } => "statement",
+            Scheme::PersonalSign => "personal-sign",
+            Scheme::TypedData { .. } => "eip712",
+        }
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Scheme::Statement { .. } => SCHEME_STATEMENT,
+            Scheme::PersonalSign => SCHEME_PERSONAL_SIGN,
+            Scheme::TypedData { .. } => SCHEME_TYPED_DATA,
+        }
+    }
+
+    pub(crate) fn file_id(&self) -> &str {
+        match self {
+            Scheme::Statement { file_id } | Scheme::TypedData { file_id } => file_id,
+            Scheme::PersonalSign => "",
+        }
+    }
+
+    /// Rebuilds the scheme recorded in a file header.
+    pub(crate) fn from_header(id: u8, file_id: String) -> Result<Scheme, String> {
+        match id {
+            SCHEME_STATEMENT => Ok(Scheme::Statement { file_id }),
+            SCHEME_PERSONAL_SIGN => Ok(Scheme::PersonalSign),
+            SCHEME_TYPED_DATA => Ok(Scheme::TypedData { file_id }),
+            _ => Err(format!("Unsupported signing scheme ({})", id)),
+        }
+    }
+}
+
+/// Builds the statement of the default scheme. The address is written in
+/// lowercase so the message does not depend on how it was typed.
+fn statement(address: &str, file_id: &str, password: &str) -> String {
+    let mut message: String = format!(
+        "{}\nAddress: 0x{}\nKey-ID: 0x{}",
+        STATEMENT_TITLE,
+        address.trim_start_matches("0x").to_lowercase(),
+        hex::encode(key_id(address, file_id, password))
+    );
+
+    if !file_id.is_empty() {
+        message.push_str(&format!("\nFile: {}", file_id));
+    }
+
+    message
+}
+
+/// Identifies the password in the statement without revealing it.
+fn key_id(address: &str, file_id: &str, password: &str) -> [u8; KEY_ID_SIZE] {
+    let mut id: [u8; KEY_ID_SIZE] = [0u8; KEY_ID_SIZE];
+    id.copy_from_slice(&password_digest(KEY_ID_DOMAIN, address, file_id, password)[..KEY_ID_SIZE]);
+    id
+}
+
+/// Builds the `WabasenUnlock` typed data.
+fn unlock_typed_data(address: &str, file_id: &str, password: &str) -> TypedData {
+    let password_hash: [u8; 32] = password_hash(address, file_id, password);
+
+    TypedData {
+        domain_separator: domain_separator(),
+        struct_hash: unlock_struct_hash(file_id, &password_hash),
+        json: json!({
+            "types": {
+                "EIP712Domain": [
+                    { "name": "name", "type": "string" },
+                    { "name": "version", "type": "string" },
+                ],
+                "WabasenUnlock": [
+                    { "name": "app", "type": "string" },
+                    { "name": "version", "type": "string" },
+                    { "name": "fileId", "type": "string" },
+                    { "name": "passwordHash", "type": "bytes32" },
+                ],
+            },
+            "primaryType": "WabasenUnlock",
+            "domain": {
+                "name": APP_NAME,
+                "version": APP_VERSION,
+            },
+            "message": {
+                "app": APP_NAME,
+                "version": APP_VERSION,
+                "fileId": file_id,
+                "passwordHash": format!("0x{}", hex::encode(password_hash)),
+            },
+        }),
+    }
+}
+
+fn domain_separator() -> [u8; 32] {
+    let mut bytes: Vec<u8> = Vec::with_capacity(3 * 32);
+    bytes.extend_from_slice(&keccak256(DOMAIN_TYPE.as_bytes()));
+    bytes.extend_from_slice(&keccak256(APP_NAME.as_bytes()));
+    bytes.extend_from_slice(&keccak256(APP_VERSION.as_bytes()));
+
+    keccak256(&bytes)
+}
+
+fn unlock_struct_hash(file_id: &str, password_hash: &[u8; 32]) -> [u8; 32] {
+    let mut bytes: Vec<u8> = Vec::with_capacity(5 * 32);
+    bytes.extend_from_slice(&keccak256(UNLOCK_TYPE.as_bytes()));
+    bytes.extend_from_slice(&keccak256(APP_NAME.as_bytes()));
+    bytes.extend_from_slice(&keccak256(APP_VERSION.as_bytes()));
+    bytes.extend_from_slice(&keccak256(file_id.as_bytes()));
+    bytes.extend_from_slice(password_hash);
+
+    keccak256(&bytes)
+}
+
+/// The `passwordHash` field, so the password itself is not shown by the wallet.
+fn password_hash(address: &str, file_id: &str, password: &str) -> [u8; 32] {
+    password_digest(PASSWORD_HASH_DOMAIN, address, file_id, password)
+}
+
+/// Derives a digest of the password with scrypt, salted with the wallet
+/// address, in lowercase, and the file id. Signed messages are public, so the
+/// digest must not be cheap to test passwords against, nor reusable across
+/// wallets and files.
+fn password_digest(domain: &[u8], address: &str, file_id: &str, password: &str) -> [u8; 32] {
+    let salt: Vec<u8> = digest_salt(domain, address, file_id);
+    let inputs: [u8; 32] = cache_key(&salt, password);
+
+    if let Some(digest) = cached_digest(&inputs) {
+        return digest;
+    }
+
+    let digest: [u8; 32] = derive_digest(&salt, password);
+
+    if let Ok(mut digests) = DIGESTS.lock() {
+        if digests.len() == DIGEST_CACHE_SIZE {
+            digests.remove(0).1.zeroize();
+        }
+        digests.push((inputs, digest));
+    }
+
+    digest
+}
+
+/// Wipes the digests cached by this process, for when its wallet is forgotten.
+pub(crate) fn forget_digests() {
+    if let Ok(mut digests) = DIGESTS.lock() {
+        digests.iter_mut().for_each(|(_, digest)| digest.zeroize());
+        digests.clear();
+    }
+}
+
+fn digest_salt(domain: &[u8], address: &str, file_id: &str) -> Vec<u8> {
+    let mut salt: Vec<u8> = domain.to_vec();
+    salt.extend_from_slice(address.trim_start_matches("0x").to_lowercase().as_bytes());
+    salt.push(0);
+    salt.extend_from_slice(file_id.as_bytes());
+    salt
+}
+
+fn cache_key(salt: &[u8], password: &str) -> [u8; 32] {
+    let key: &[u8; 32] = DIGEST_CACHE_KEY.get_or_init(|| {
+        let mut key: [u8; 32] = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    });
+
+    *secret_keccak256(&[key, salt, password.as_bytes()])
+}
+
+fn cached_digest(inputs: &[u8; 32]) -> Option<[u8; 32]> {
+    match DIGESTS.lock() {
+        Ok(digests) => digests
+            .iter()
+            .find(|(key, _)| ct_eq(key, inputs))
+            .map(|(_, digest)| *digest),
+        Err(_) => None,
+    }
+}
+
+fn derive_digest(salt: &[u8], password: &str) -> [u8; 32] {
+    let params: Params = match Params::new(DIGEST_LOG_N, DIGEST_R, DIGEST_P, 32) {
+        Ok(f) => f,
+        Err(_) => unreachable!("the digest parameters are valid"),
+    };
+
+    let mut digest: [u8; 32] = [0u8; 32];
+
+    match scrypt(password.as_bytes(), salt, &params, &mut digest) {
+        Ok(f) => f,
+        Err(_) => unreachable!("the digest length is valid"),
+    };
+
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "0x2c7536E3605D9C16a7a3D7b1898e529396a65c23";
+
+    fn hash(scheme: &Scheme) -> String {
+        hex::encode(scheme.message_hash(ADDRESS, "password"))
+    }
+
+    #[test]
+    fn signed_digests_are_pinned() {
+        assert_eq!(
+            hash(&Scheme::default()),
+            "211ca4bbad516ffc424d342d0a3eeac241be86b97566d4ba90c88e1dc113cd98"
+        );
+        assert_eq!(
+            hash(&Scheme::PersonalSign),
+            "0a06196949364e16c6b932d1aa3704f96bf0e19c621526ea58e75f1199ef859f"
+        );
+        assert_eq!(
+            hash(&Scheme::parse("eip712", "").unwrap()),
+            "e2badc67e1a05c0a36ace25153ba74f22818e0e7cae37034fb454a154a1b67c9"
+        );
+        assert_eq!(
+            hash(&Scheme::parse("eip712", "report.pdf").unwrap()),
+            "62ffa930a63cfd238a38bddb659d9b4f8390fbb93f3a44b7880efbdea782ad0a"
+        );
+    }
+
+    #[test]
+    fn statements_and_key_ids_are_pinned() {
+        assert_eq!(
+            statement(ADDRESS, "", "password"),
+            "Wabasen unlock v1\n\
+             Address: 0x2c7536e3605d9c16a7a3d7b1898e529396a65c23\n\
+             Key-ID: 0x10de799b2b591e7a310b6349a03d6985"
+        );
+        assert_eq!(
+            statement(ADDRESS, "report.pdf", "password"),
+            "Wabasen unlock v1\n\
+             Address: 0x2c7536e3605d9c16a7a3d7b1898e529396a65c23\n\
+             Key-ID: 0xb84e69a5cca135703572b5f7427cc7f7\n\
+             File: report.pdf"
+        );
+
+        // Derived without the cache, so scrypt itself is checked.
+        let salt: Vec<u8> = digest_salt(KEY_ID_DOMAIN, ADDRESS, "");
+        assert_eq!(
+            hex::encode(&derive_digest(&salt, "password")[..KEY_ID_SIZE]),
+            "10de799b2b591e7a310b6349a03d6985"
+        );
+        let salt: Vec<u8> = digest_salt(PASSWORD_HASH_DOMAIN, ADDRESS, "");
+        assert_eq!(
+            hex::encode(derive_digest(&salt, "password")),
+            "c9068211fd8c01b01141d5b07cc9f24c1330d48dec0cc43e3208d50c386650eb"
+        );
+    }
+
+    #[test]
+    fn forgotten_digests_are_derived_again() {
+        let key: [u8; KEY_ID_SIZE] = key_id(ADDRESS, "", "forgotten");
+        let inputs: [u8; 32] = cache_key(&digest_salt(KEY_ID_DOMAIN, ADDRESS, ""), "forgotten");
+
+        forget_digests();
+
+        assert_eq!(cached_digest(&inputs), None);
+        assert_eq!(key_id(ADDRESS, "", "forgotten"), key);
+    }
+}
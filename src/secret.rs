@@ -0,0 +1,184 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tiny_keccak::{Hasher, Keccak};
+use zeroize::Zeroize;
+
+static LOCK_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Secret bytes such as a key or a nonce derived from a password. They have
+/// their own allocation, locked in memory after `lock_secrets_in_memory`, and
+/// are wiped when dropped.
+pub(crate) struct SecretBytes<const N: usize> {
+    page: Box<Page<N>>,
+}
+
+/// Allocation of a secret, aligned to the largest memory page of the supported
+/// systems so that no other data shares its pages, which are unlocked along
+/// with it.
+#[repr(C, align(65536))]
+struct Page<const N: usize> {
+    bytes: [u8; N],
+    locked: bool,
+}
+
+impl<const N: usize> SecretBytes<N> {
+    /// Returns zeroed bytes to fill in place, so that the secret is never
+    /// copied out of this allocation.
+    pub(crate) fn zeroed() -> SecretBytes<N> {
+        // Allocated zeroed on the heap, without a copy built on the stack.
+        // SAFETY: zeroed bytes and `false` are valid values of every field.
+        let mut page: Box<Page<N>> = unsafe { Box::<Page<N>>::new_zeroed().assume_init() };
+
+        if LOCK_SECRETS.load(Ordering::Relaxed) {
+            page.locked = lock_memory(page.bytes.as_ptr(), N);
+        }
+
+        SecretBytes { page }
+    }
+
+    pub(crate) fn copy_from(bytes: &[u8; N]) -> SecretBytes<N> {
+        let mut secret: SecretBytes<N> = SecretBytes::zeroed();
+        secret.copy_from_slice(bytes);
+        secret
+    }
+}
+
+impl<const N: usize> Deref for SecretBytes<N> {
+    type Target = [u8; N];
+
+    fn deref(&self) -> &[u8; N] {
+        &self.page.bytes
+    }
+}
+
+impl<const N: usize> DerefMut for SecretBytes<N> {
+    fn deref_mut(&mut self) -> &mut [u8; N] {
+        &mut self.page.bytes
+    }
+}
+
+impl<const N: usize> Drop for Page<N> {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+
+        if self.locked {
+            unlock_memory(self.bytes.as_ptr(), N);
+        }
+    }
+}
+
+/// Hashes `parts` one after the other with Keccak-256 straight into secret
+/// bytes, for keys and nonces derived from signatures, passwords or shared
+/// secrets. Outputs shorter than 32 bytes are truncated.
+pub(crate) fn secret_keccak256<const N: usize>(parts: &[&[u8]]) -> SecretBytes<N> {
+    let mut output: SecretBytes<N> = SecretBytes::zeroed();
+    let mut hasher: Keccak = Keccak::v256();
+
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.finalize(&mut *output);
+    output
+}
+
+/// Locks every secret allocated from now on in memory, so that keys are never
+/// swapped to disk. Locking beyond the RLIMIT_MEMLOCK limit silently fails.
+/// Each secret is unlocked once wiped.
+pub fn lock_secrets_in_memory() {
+    LOCK_SECRETS.store(true, Ordering::Relaxed);
+}
+
+/// Keeps secrets out of core dumps for the rest of the run. On Linux this also
+/// stops other processes of the user from attaching to this one.
+pub fn disable_core_dumps() -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err("Failed to disable core dumps".to_string());
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        let limit: libc::rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+            return Err("Failed to disable core dumps".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps the pages holding `len` bytes at `ptr` out of swap and reports
+/// whether it could.
+#[cfg(unix)]
+pub(crate) fn lock_memory(ptr: *const u8, len: usize) -> bool {
+    len == 0 || unsafe { libc::mlock(ptr as *const libc::c_void, len) } == 0
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock_memory(_ptr: *const u8, _len: usize) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn unlock_memory(ptr: *const u8, len: usize) {
+    unsafe { libc::munlock(ptr as *const libc::c_void, len) };
+}
+
+#[cfg(not(unix))]
+fn unlock_memory(_ptr: *const u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+
+    #[test]
+    fn secrets_are_built_zeroed_in_their_own_pages() {
+        let secret: SecretBytes<32> = SecretBytes::zeroed();
+
+        assert_eq!(*secret, [0u8; 32]);
+        assert_eq!(secret.as_ptr() as usize % 65536, 0);
+    }
+
+    #[test]
+    fn dropping_a_secret_wipes_it() {
+        let mut page: ManuallyDrop<Page<32>> = ManuallyDrop::new(Page {
+            bytes: [0x5a; 32],
+            locked: false,
+        });
+        page.locked = lock_memory(page.bytes.as_ptr(), 32);
+
+        let bytes: *const [u8; 32] = &page.bytes;
+
+        // The page is only dropped, so its bytes can still be read.
+        unsafe { ManuallyDrop::drop(&mut page) };
+
+        assert_eq!(unsafe { bytes.read_volatile() }, [0u8; 32]);
+    }
+}
@@ -0,0 +1,175 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+//! Shamir secret sharing over GF(256), applied byte by byte.
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use zeroize::Zeroizing;
+
+/// Splits `secret` into `count` shares, any `threshold` of which rebuild it.
+/// Shares are returned as `(x, y)` with `x` running from 1 to `count`, and
+/// everything derived from the secret is wiped once dropped.
+pub(crate) fn split(secret: &[u8], threshold: u8, count: u8) -> Vec<(u8, Zeroizing<Vec<u8>>)> {
+    let mut shares: Vec<(u8, Zeroizing<Vec<u8>>)> = (1..=count)
+        .map(|x| (x, Zeroizing::new(Vec::with_capacity(secret.len()))))
+        .collect();
+
+    let mut coefficients: Zeroizing<Vec<u8>> = Zeroizing::new(vec![0u8; threshold as usize]);
+
+    for byte in secret {
+        coefficients[0] = *byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+
+        for (x, y) in shares.iter_mut() {
+            y.push(evaluate(&coefficients, *x));
+        }
+    }
+
+    shares
+}
+
+/// Rebuilds the secret from at least `threshold` distinct shares.
+pub(crate) fn combine(shares: &[(u8, Zeroizing<Vec<u8>>)]) -> Zeroizing<Vec<u8>> {
+    let length: usize = shares.first().map(|(_, y)| y.len()).unwrap_or_default();
+
+    let secret: Vec<u8> = (0..length)
+        .map(|index| {
+            shares.iter().fold(0u8, |secret, (x_i, y_i)| {
+                let basis: u8 = shares
+                    .iter()
+                    .filter(|(x_j, _)| x_j != x_i)
+                    .fold(1u8, |basis, (x_j, _)| {
+                        multiply(basis, divide(*x_j, x_j ^ x_i))
+                    });
+
+                secret ^ multiply(y_i[index], basis)
+            })
+        })
+        .collect();
+
+    Zeroizing::new(secret)
+}
+
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0u8, |result, coefficient| multiply(result, x) ^ coefficient)
+}
+
+/// Multiplies in GF(256) without branching on either operand, which may be
+/// secret: each step masks with all ones or all zeros instead.
+fn multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut product: u8 = 0;
+
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+
+        let carry: u8 = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+
+        b >>= 1;
+    }
+
+    product
+}
+
+fn divide(a: u8, b: u8) -> u8 {
+    // b^254 is the inverse of b in GF(256).
+    let mut inverse: u8 = 1;
+
+    for _ in 0..254 {
+        inverse = multiply(inverse, b);
+    }
+
+    multiply(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8; 32] = b"a file key of exactly 32 bytes..";
+
+    /// Returns the shares whose bit is set in `mask`.
+    fn subset(shares: &[(u8, Zeroizing<Vec<u8>>)], mask: u32) -> Vec<(u8, Zeroizing<Vec<u8>>)> {
+        shares
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| mask & (1 << index) != 0)
+            .map(|(_, share)| share.clone())
+            .collect()
+    }
+
+    #[test]
+    fn enough_shares_rebuild_the_secret() {
+        for count in 1..=5u8 {
+            for threshold in 1..=count {
+                let shares: Vec<(u8, Zeroizing<Vec<u8>>)> = split(SECRET, threshold, count);
+
+                for mask in 1..(1u32 << count) {
+                    if mask.count_ones() >= threshold as u32 {
+                        assert_eq!(
+                            &*combine(&subset(&shares, mask)),
+                            SECRET,
+                            "{} of {}, shares {:b}",
+                            threshold,
+                            count,
+                            mask
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_shares_do_not_rebuild_the_secret() {
+        for count in 2..=5u8 {
+            for threshold in 2..=count {
+                let shares: Vec<(u8, Zeroizing<Vec<u8>>)> = split(SECRET, threshold, count);
+
+                for mask in 1..(1u32 << count) {
+                    if mask.count_ones() == threshold as u32 - 1 {
+                        assert_ne!(&*combine(&subset(&shares, mask)), SECRET);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn known_vectors() {
+        // FIPS-197 section 4.2: {57} • {83} = {c1}, and {53} is the inverse of {ca}.
+        assert_eq!(multiply(0x57, 0x83), 0xc1);
+        assert_eq!(multiply(0x53, 0xca), 0x01);
+        assert_eq!(divide(1, 0xca), 0x53);
+
+        // f(x) = {42} + {57} x, so f(1) = {15} and f(2) = {42} ^ {ae} = {ec}.
+        assert_eq!(evaluate(&[0x42, 0x57], 1), 0x15);
+        assert_eq!(evaluate(&[0x42, 0x57], 2), 0xec);
+        assert_eq!(
+            *combine(&[
+                (1, Zeroizing::new(vec![0x15])),
+                (2, Zeroizing::new(vec![0xec]))
+            ]),
+            vec![0x42]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE},
+    Engine,
+};
+use std::fmt;
+use subtle::{Choice, ConditionallySelectable};
+use zeroize::{Zeroize, Zeroizing};
+
+const COMPONENT_SIZE: usize = 32;
+const COMPACT_SIZE: usize = 2 * COMPONENT_SIZE;
+const SIGNATURE_SIZE: usize = COMPACT_SIZE + 1;
+/// Longest `r ‖ s ‖ v` accepted, leaving room for an EIP-155 `v` of up to 8 bytes.
+const MAX_SIGNATURE_SIZE: usize = COMPACT_SIZE + 8;
+
+/// Order of the secp256k1 group, and its half, the largest low `s`.
+const CURVE_ORDER: [u8; COMPONENT_SIZE] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+const HALF_CURVE_ORDER: [u8; COMPONENT_SIZE] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// Why a signature string could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignatureError {
+    /// The string is neither hex nor base64.
+    Encoding,
+    /// The decoded signature has an unexpected number of bytes.
+    Length(usize),
+    /// The `v` value does not map to a recovery id.
+    RecoveryId(u64),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Encoding => write!(f, "the signature is neither hex nor base64"),
+            SignatureError::Length(length) => write!(
+                f,
+                "a signature has 64 or 65 bytes, {} bytes were given",
+                length
+            ),
+            SignatureError::RecoveryId(v) => write!(f, "invalid recovery value v = {}", v),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Recoverable secp256k1 signature, whatever encoding the wallet returned it in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    compact: [u8; COMPACT_SIZE],
+    recovery_id: u8,
+}
+
+impl Signature {
+    /// Parses a signature given as hex, with or without `0x`, or as base64.
+    ///
+    /// Accepted layouts are `r ‖ s ‖ v` with `v` being 0/1, 27/28 or an
+    /// EIP-155 value `chainId * 2 + 35/36`, and the 64-byte EIP-2098 compact
+    /// form where the recovery bit is the top bit of `s`. A high `s` is turned
+    /// into its low twin, so both forms of a signature derive the same keys.
+    pub fn parse(signature: &str) -> Result<Signature, SignatureError> {
+        let signature: &str = signature.trim();
+        let hex: &str = signature
+            .strip_prefix("0x")
+            .or_else(|| signature.strip_prefix("0X"))
+            .unwrap_or(signature);
+
+        let bytes: Zeroizing<Vec<u8>> = match hex::decode(hex) {
+            Ok(f) => Zeroizing::new(f),
+            Err(_) => match STANDARD.decode(signature).or(URL_SAFE.decode(signature)) {
+                Ok(f) => Zeroizing::new(f),
+                Err(_) => return Err(SignatureError::Encoding),
+            },
+        };
+
+        Signature::from_bytes(&bytes)
+    }
+
+    /// Reads the decoded bytes of one of the layouts accepted by `parse`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Signature, SignatureError> {
+        let mut compact: [u8; COMPACT_SIZE] = [0u8; COMPACT_SIZE];
+
+        let signature: Signature = match bytes.len() {
+            COMPACT_SIZE => {
+                compact.copy_from_slice(bytes);
+
+                let recovery_id: u8 = compact[COMPONENT_SIZE] >> 7;
+                compact[COMPONENT_SIZE] &= 0x7f;
+
+                Signature {
+                    compact,
+                    recovery_id,
+                }
+            }
+            SIGNATURE_SIZE..=MAX_SIGNATURE_SIZE => {
+                compact.copy_from_slice(&bytes[..COMPACT_SIZE]);
+
+                let v: u64 = bytes[COMPACT_SIZE..]
+                    .iter()
+                    .fold(0u64, |v, byte| (v << 8) | *byte as u64);
+
+                let recovery_id: u8 = match v {
+                    0 | 1 => v as u8,
+                    27 | 28 => (v - 27) as u8,
+                    35.. => ((v - 35) % 2) as u8,
+                    _ => return Err(SignatureError::RecoveryId(v)),
+                };
+
+                Signature {
+                    compact,
+                    recovery_id,
+                }
+            }
+            length => return Err(SignatureError::Length(length)),
+        };
+
+        compact.zeroize();
+
+        Ok(signature.normalized())
+    }
+
+    /// Replaces a high `s`, between half the curve order and the order, with
+    /// `order - s` and flips the recovery id, which recovers the same address.
+    /// `s` derives keys, so it is handled without branching on its value.
+    fn normalized(mut self) -> Signature {
+        let mut s: Zeroizing<[u8; COMPONENT_SIZE]> = Zeroizing::new([0u8; COMPONENT_SIZE]);
+        s.copy_from_slice(&self.compact[COMPONENT_SIZE..]);
+
+        let (_, above_half): (Zeroizing<[u8; COMPONENT_SIZE]>, u8) =
+            subtract(&HALF_CURVE_ORDER, &s);
+        let (_, below_order): (Zeroizing<[u8; COMPONENT_SIZE]>, u8) = subtract(&s, &CURVE_ORDER);
+        let (low, _): (Zeroizing<[u8; COMPONENT_SIZE]>, u8) = subtract(&CURVE_ORDER, &s);
+
+        let high: u8 = above_half & below_order;
+
+        for (byte, low) in self.compact[COMPONENT_SIZE..].iter_mut().zip(low.iter()) {
+            *byte = u8::conditional_select(byte, low, Choice::from(high));
+        }
+
+        self.recovery_id ^= high;
+        self
+    }
+
+    /// Returns `r ‖ s`, without the recovery bit.
+    pub fn compact(&self) -> &[u8; COMPACT_SIZE] {
+        &self.compact
+    }
+
+    /// Returns the recovery id, 0 or 1.
+    pub fn recovery_id(&self) -> u8 {
+        self.recovery_id
+    }
+
+    /// Returns the canonical 65-byte `r ‖ s ‖ v` form with `v` = 27/28, the
+    /// same bytes whatever encoding the signature was parsed from.
+    pub fn to_bytes(&self) -> [u8; SIGNATURE_SIZE] {
+        let mut bytes: [u8; SIGNATURE_SIZE] = [0u8; SIGNATURE_SIZE];
+        bytes[..COMPACT_SIZE].copy_from_slice(&self.compact);
+        bytes[COMPACT_SIZE] = self.recovery_id + 27;
+        bytes
+    }
+
+    /// Returns `to_bytes` as `0x`-prefixed hex, the way wallets print signatures.
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.to_bytes()))
+    }
+}
+
+/// Returns `a - b` over big-endian numbers, and 1 when it borrows, that is
+/// when `b` is greater than `a`.
+fn subtract(
+    a: &[u8; COMPONENT_SIZE],
+    b: &[u8; COMPONENT_SIZE],
+) -> (Zeroizing<[u8; COMPONENT_SIZE]>, u8) {
+    let mut difference: Zeroizing<[u8; COMPONENT_SIZE]> = Zeroizing::new([0u8; COMPONENT_SIZE]);
+    let mut borrow: u16 = 0;
+
+    for index in (0..COMPONENT_SIZE).rev() {
+        let value: u16 = (a[index] as u16)
+            .wrapping_sub(b[index] as u16)
+            .wrapping_sub(borrow);
+        difference[index] = value as u8;
+        borrow = (value >> 8) & 1;
+    }
+
+    (difference, borrow as u8)
+}
+
+/// A signature of the password is what derives the file key, so it is wiped
+/// once dropped.
+impl Drop for Signature {
+    fn drop(&mut self) {
+        self.compact.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credential::Credential, scheme::Scheme, signer::sign_password,
+        verify_password_from_signature, wallet::LocalWallet,
+    };
+    use proptest::prelude::*;
+
+    const FIRST_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    /// Returns `r ‖ (order - s) ‖ v`, the high-s twin of a low-s signature.
+    fn high_s_twin(signature: &Signature) -> Vec<u8> {
+        let mut s: [u8; COMPONENT_SIZE] = [0u8; COMPONENT_SIZE];
+        s.copy_from_slice(&signature.compact()[COMPONENT_SIZE..]);
+
+        let mut bytes: Vec<u8> = signature.compact()[..COMPONENT_SIZE].to_vec();
+        bytes.extend_from_slice(&*subtract(&CURVE_ORDER, &s).0);
+        bytes.push((signature.recovery_id() ^ 1) + 27);
+        bytes
+    }
+
+    fn with_s(s: &[u8; COMPONENT_SIZE], v: u8) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0x11; COMPONENT_SIZE];
+        bytes.extend_from_slice(s);
+        bytes.push(v);
+        bytes
+    }
+
+    #[test]
+    fn high_s_is_normalized() {
+        let mut above_half: [u8; COMPONENT_SIZE] = HALF_CURVE_ORDER;
+        above_half[COMPONENT_SIZE - 1] += 1;
+
+        let signature: Signature = Signature::from_bytes(&with_s(&above_half, 27)).unwrap();
+        assert_eq!(signature.compact()[COMPONENT_SIZE..], HALF_CURVE_ORDER);
+        assert_eq!(signature.recovery_id(), 1);
+
+        let signature: Signature = Signature::from_bytes(&with_s(&HALF_CURVE_ORDER, 27)).unwrap();
+        assert_eq!(signature.compact()[COMPONENT_SIZE..], HALF_CURVE_ORDER);
+        assert_eq!(signature.recovery_id(), 0);
+
+        // Not a valid s at all, left for verification to reject.
+        let signature: Signature = Signature::from_bytes(&with_s(&CURVE_ORDER, 28)).unwrap();
+        assert_eq!(signature.compact()[COMPONENT_SIZE..], CURVE_ORDER);
+        assert_eq!(signature.recovery_id(), 1);
+    }
+
+    #[test]
+    fn high_s_signatures_verify_and_derive_like_low_s_ones() {
+        let wallet: LocalWallet =
+            LocalWallet::from_private_key(&hex::decode(FIRST_KEY).unwrap()).unwrap();
+        let credential: Credential =
+            sign_password(&wallet, "password", &Scheme::default()).unwrap();
+
+        let signature: Signature = Signature::parse(&credential.signature).unwrap();
+        let twin: Vec<u8> = high_s_twin(&signature);
+
+        assert_eq!(Signature::from_bytes(&twin), Ok(signature.clone()));
+        verify_password_from_signature(
+            &credential.address,
+            "password",
+            &hex::encode(&twin),
+            &Scheme::default(),
+        )
+        .unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(text in "\\PC*") {
+            let _ = Signature::parse(&text);
+        }
+
+        #[test]
+        fn from_bytes_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..80)) {
+            let _ = Signature::from_bytes(&bytes);
+        }
+
+        #[test]
+        fn canonical_bytes_round_trip(
+            compact in proptest::collection::vec(any::<u8>(), COMPACT_SIZE),
+            v in 27u8..=28,
+        ) {
+            let mut bytes: Vec<u8> = compact;
+            bytes[COMPONENT_SIZE] &= 0x3f;
+            bytes.push(v);
+
+            let signature: Signature = Signature::from_bytes(&bytes).unwrap();
+
+            prop_assert_eq!(signature.to_bytes().to_vec(), bytes);
+            prop_assert_eq!(Signature::from_bytes(&signature.to_bytes()), Ok(signature));
+        }
+
+        #[test]
+        fn encodings_parse_to_the_same_signature(
+            compact in proptest::collection::vec(any::<u8>(), COMPACT_SIZE),
+            v in prop_oneof![0u64..=1, 27u64..=28, 35u64..=1_000_000],
+        ) {
+            let mut bytes: Vec<u8> = compact;
+            bytes[COMPONENT_SIZE] &= 0x3f;
+            bytes.extend_from_slice(&v.to_be_bytes()[(v.leading_zeros() as usize / 8).min(7)..]);
+
+            let signature: Signature = Signature::from_bytes(&bytes).unwrap();
+
+            prop_assert_eq!(signature.recovery_id() as u64, match v {
+                0 | 1 => v,
+                27 | 28 => v - 27,
+                _ => (v - 35) % 2,
+            });
+            prop_assert_eq!(Signature::parse(&hex::encode(&bytes)), Ok(signature.clone()));
+            prop_assert_eq!(Signature::parse(&signature.to_hex()), Ok(signature.clone()));
+            prop_assert_eq!(Signature::parse(&STANDARD.encode(&bytes)), Ok(signature));
+        }
+
+        #[test]
+        fn compact_form_keeps_the_recovery_bit(bytes in proptest::collection::vec(any::<u8>(), COMPACT_SIZE)) {
+            let mut bytes: Vec<u8> = bytes;
+            bytes[COMPONENT_SIZE] &= 0xbf;
+
+            let signature: Signature = Signature::from_bytes(&bytes).unwrap();
+
+            prop_assert_eq!(signature.recovery_id(), bytes[COMPONENT_SIZE] >> 7);
+            prop_assert_eq!(signature.compact()[COMPONENT_SIZE], bytes[COMPONENT_SIZE] & 0x7f);
+            prop_assert_eq!(Signature::from_bytes(&signature.to_bytes()), Ok(signature));
+        }
+
+        #[test]
+        fn high_s_twins_parse_to_the_same_signature(
+            compact in proptest::collection::vec(any::<u8>(), COMPACT_SIZE),
+            v in 27u8..=28,
+        ) {
+            let mut bytes: Vec<u8> = compact;
+            bytes[COMPONENT_SIZE] &= 0x7f;
+            bytes[COMPACT_SIZE - 1] |= 1;
+            bytes.push(v);
+
+            let signature: Signature = Signature::from_bytes(&bytes).unwrap();
+
+            prop_assert_eq!(Signature::from_bytes(&high_s_twin(&signature)), Ok(signature));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::Address, credential::Credential, parse_address, scheme::Scheme, scheme::TypedData,
+    verify_password_from_signature,
+};
+
+/// Source of the signatures of a wallet: a signature given up front, a local
+/// private key or keystore, an external command, a node or a Ledger. Library
+/// users implement it to sign with their own HSM or KMS.
+///
+/// Signatures are returned as text in any encoding `Signature::parse` accepts.
+/// Wabasen checks them against `address` before using them.
+pub trait Signer {
+    /// Address of the wallet that signs.
+    fn address(&self) -> Address;
+
+    /// Signs `message` with EIP-191 `personal_sign`.
+    fn sign_message(&self, message: &[u8]) -> Result<String, String>;
+
+    /// Signs EIP-712 typed data, as `eth_signTypedData_v4` does.
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String>;
+}
+
+/// Signature made beforehand, such as one pasted with `--signature`. It is
+/// returned as given whatever is asked, and only passes verification for the
+/// message it was made for.
+pub struct PresignedSigner {
+    address: Address,
+    signature: String,
+}
+
+impl PresignedSigner {
+    pub fn new(address: &str, signature: &str) -> Result<PresignedSigner, String> {
+        Ok(PresignedSigner {
+            address: parse_address(address)?,
+            signature: signature.to_string(),
+        })
+    }
+}
+
+impl Signer for PresignedSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn sign_message(&self, _message: &[u8]) -> Result<String, String> {
+        Ok(self.signature.clone())
+    }
+
+    fn sign_typed_data(&self, _typed_data: &TypedData) -> Result<String, String> {
+        Ok(self.signature.clone())
+    }
+}
+
+/// Has `signer` sign `password` under `scheme` and returns the verified
+/// credential of the wallet.
+pub(crate) fn sign_password(
+    signer: &dyn Signer,
+    password: &str,
+    scheme: &Scheme,
+) -> Result<Credential, String> {
+    let address: String = signer.address().to_lowercase();
+
+    let signature: String = match (
+        scheme.message(&address, password),
+        scheme.typed_data(&address, password),
+    ) {
+        (Some(message), _) => signer.sign_message(message.as_bytes())?,
+        (None, Some(typed_data)) => signer.sign_typed_data(&typed_data)?,
+        (None, None) => unreachable!("every scheme signs a message or typed data"),
+    };
+
+    verify_password_from_signature(&address, password, &signature, scheme)?;
+
+    Ok(Credential::new(
+        &signer.address().to_checksum(),
+        password,
+        &signature,
+    ))
+}
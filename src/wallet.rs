@@ -0,0 +1,83 @@
+/*
+Open source software for file encryption with wallet-based 2FA.
+Copyright (C) 2024 Sinodevi
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+Please send bugreports with examples or suggestions to: wabasen@sinodevi.com
+*/
+use crate::{
+    address::Address, hash_message, keccak256, scheme::TypedData, signature::Signature,
+    signer::Signer,
+};
+use secp256k1::{ecdsa::RecoverableSignature, Message, PublicKey, Secp256k1, SecretKey};
+
+/// Wallet whose private key is held in memory, to sign without a human at a
+/// hardware wallet.
+pub struct LocalWallet {
+    secret_key: SecretKey,
+}
+
+impl LocalWallet {
+    /// Creates a wallet from a raw 32-byte secp256k1 private key.
+    pub fn from_private_key(private_key: &[u8]) -> Result<LocalWallet, String> {
+        match SecretKey::from_slice(private_key) {
+            Ok(secret_key) => Ok(LocalWallet { secret_key }),
+            Err(_) => Err("Invalid private key".to_string()),
+        }
+    }
+
+    /// Signs a 32-byte digest.
+    pub(crate) fn sign_hash(&self, hash: &[u8; 32]) -> Signature {
+        let signature: RecoverableSignature = Secp256k1::signing_only()
+            .sign_ecdsa_recoverable(&Message::from_digest(*hash), &self.secret_key);
+
+        let (recovery_id, compact) = signature.serialize_compact();
+
+        let mut bytes: [u8; 65] = [0u8; 65];
+        bytes[..64].copy_from_slice(&compact);
+        bytes[64] = recovery_id.to_i32() as u8;
+
+        match Signature::from_bytes(&bytes) {
+            Ok(f) => f,
+            Err(_) => unreachable!("a recovery id is 0 or 1"),
+        }
+    }
+}
+
+impl Drop for LocalWallet {
+    fn drop(&mut self) {
+        self.secret_key.non_secure_erase();
+    }
+}
+
+impl Signer for LocalWallet {
+    fn address(&self) -> Address {
+        let public_key: PublicKey = self.secret_key.public_key(&Secp256k1::signing_only());
+        let hash: [u8; 32] = keccak256(&public_key.serialize_uncompressed()[1..]);
+
+        let mut address: [u8; 20] = [0u8; 20];
+        address.copy_from_slice(&hash[12..]);
+
+        Address::from_bytes(address)
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<String, String> {
+        Ok(self.sign_hash(&hash_message(message)).to_hex())
+    }
+
+    fn sign_typed_data(&self, typed_data: &TypedData) -> Result<String, String> {
+        Ok(self.sign_hash(&typed_data.hash()).to_hex())
+    }
+}